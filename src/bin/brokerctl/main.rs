@@ -0,0 +1,458 @@
+use std::net::SocketAddr;
+use std::str::FromStr;
+
+use broker::main_capnp::root_service;
+use broker::topic_capnp::{topic_service, TopicSort};
+use broker::util::stream_to_rpc_network;
+use capnp_rpc::{rpc_twoparty_capnp, RpcSystem};
+use clap::{Parser, Subcommand, ValueEnum};
+use records::{DeletedRecord, KickRecord, MarkedReadRecord, ReadOnlyRecord, Record, TopicRecord};
+use tokio::net::TcpStream;
+use tokio::task::LocalSet;
+use uuid::Uuid;
+
+mod records;
+mod requests;
+
+type BoxError = Box<dyn std::error::Error>;
+
+/// Largest page the server hands out.
+const MAX_PAGE_SIZE: u32 = 500;
+/// Environment variable with the admin token, used when `--admin-token` is not given
+const ADMIN_TOKEN_VARIABLE: &str = "BROKER_ADMIN_TOKEN";
+
+/// Non-interactive administration of a broker server.
+/// Topic commands work for any user, the rest need the admin token the server was started with.
+#[derive(Parser, Debug, Clone)]
+pub struct CliArgs {
+    #[arg(short, long, default_value_t = SocketAddr::from_str("127.0.0.1:8080").unwrap())]
+    pub address: SocketAddr,
+
+    /// Username to log in with
+    #[arg(short, long, default_value_t = String::from("admin"))]
+    pub username: String,
+
+    /// Secret the server was started with, needed by the admin commands. Defaults to `BROKER_ADMIN_TOKEN`
+    #[arg(long, value_name = "TOKEN")]
+    pub admin_token: Option<String>,
+
+    /// Print JSON instead of text: an array for listings, an object otherwise
+    #[arg(short, long)]
+    pub json: bool,
+
+    #[command(subcommand)]
+    pub command: Command,
+}
+
+#[derive(Subcommand, Debug, Clone)]
+pub enum Command {
+    /// Manage topics
+    #[command(subcommand)]
+    Topics(TopicCommand),
+
+    /// Dump the newest messages of a topic, oldest first
+    Messages {
+        /// Topic name or UUID
+        topic: String,
+
+        #[arg(short, long, default_value_t = 20)]
+        limit: usize,
+    },
+
+    /// Replace the content of a message. Only works for messages posted under the `--username`
+    Edit {
+        message: Uuid,
+        content: String,
+    },
+
+    /// List previous contents of an edited message, oldest first
+    Revisions {
+        message: Uuid,
+    },
+
+    /// Dump a message with all the replies to it, in the order they were posted
+    Thread {
+        message: Uuid,
+    },
+
+    /// List mentions of the `--username`, newest first
+    Notifications {
+        /// Skip the notifications that were already read
+        #[arg(long)]
+        unread: bool,
+
+        /// 0 lists all of them
+        #[arg(short, long, default_value_t = 20)]
+        limit: u32,
+    },
+
+    /// Mark notifications of the messages as read. Without messages all of them are marked
+    MarkRead {
+        messages: Vec<Uuid>,
+    },
+
+    /// List users the server knows, with their presence and when they were last seen
+    Presence,
+
+    /// List open connections with their usernames
+    Sessions,
+
+    /// List subscribers of every topic
+    Subscriptions,
+
+    /// Disconnect a session, e.g. `127.0.0.1:53412`. Kicked clients do not reconnect
+    Kick {
+        peer: String,
+    },
+
+    /// Save the server state right now
+    Snapshot,
+
+    /// Show store sizes and `ConcurrentList` stats
+    Stats,
+
+    /// Reject posting messages and changing topics while on
+    ReadOnly {
+        state: Switch,
+    },
+}
+
+#[derive(Subcommand, Debug, Clone)]
+pub enum TopicCommand {
+    List {
+        /// Only topics whose name starts with this
+        #[arg(short, long, default_value_t = String::new())]
+        prefix: String,
+
+        /// Only topics whose name or description contain this, ignoring case
+        #[arg(short, long, default_value_t = String::new())]
+        search: String,
+
+        /// Only topics with this tag, may be repeated
+        #[arg(short, long = "tag")]
+        tags: Vec<String>,
+
+        #[arg(long, value_enum, default_value_t = SortBy::Name)]
+        sort: SortBy,
+
+        #[arg(short, long)]
+        descending: bool,
+
+        /// Stop after this many topics and print the cursor to continue with to stderr. 0 lists all of them
+        #[arg(short, long, default_value_t = 0)]
+        limit: u32,
+
+        /// Continue a listing that was stopped by `--limit`, with the same filters and order
+        #[arg(short, long, default_value_t = String::new())]
+        cursor: String,
+    },
+
+    Create {
+        name: String,
+
+        /// Spread messages over this many partitions. Keyed messages keep their order within a partition
+        #[arg(short, long, default_value_t = 0)]
+        partitions: u32,
+    },
+
+    Rename {
+        /// Topic name or UUID
+        topic: String,
+        new_name: String,
+    },
+
+    Delete {
+        /// Topic name or UUID
+        topic: String,
+    },
+
+    /// Set retention in minutes, `-` to keep messages forever
+    Retention {
+        /// Topic name or UUID
+        topic: String,
+        minutes: String,
+    },
+
+    /// Set the description, an empty one removes it
+    Describe {
+        /// Topic name or UUID
+        topic: String,
+        description: String,
+    },
+
+    /// Replace the tags, none removes them all
+    Tag {
+        /// Topic name or UUID
+        topic: String,
+        tags: Vec<String>,
+    },
+
+    /// Show how busy the topic is
+    Stats {
+        /// Topic name or UUID
+        topic: String,
+    },
+
+    /// List users subscribed to the topic, with their presence
+    Subscribers {
+        /// Topic name or UUID
+        topic: String,
+    },
+
+    Pin {
+        /// Topic name or UUID
+        topic: String,
+        message: Uuid,
+    },
+
+    Unpin {
+        /// Topic name or UUID
+        topic: String,
+        message: Uuid,
+    },
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy)]
+pub enum SortBy {
+    Name,
+    Created,
+    /// Newest message first with `--descending`
+    Activity,
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy)]
+pub enum Switch {
+    On,
+    Off,
+}
+
+#[tokio::main(flavor = "current_thread")]
+async fn main() {
+    let args = CliArgs::parse();
+
+    // Errors go to stderr with a non-zero exit code, so scripts can tell them apart from the output
+    if let Err(e) = LocalSet::new().run_until(run(args)).await {
+        eprintln!("brokerctl: {e}");
+        std::process::exit(1);
+    }
+}
+
+async fn run(args: CliArgs) -> Result<(), BoxError> {
+    let stream = TcpStream::connect(args.address).await?;
+    let _ = stream.set_nodelay(true);
+
+    let network = stream_to_rpc_network(stream);
+    let mut rpc_system = RpcSystem::new(Box::new(network), None);
+    let root: root_service::Client = rpc_system.bootstrap(rpc_twoparty_capnp::Side::Client);
+    let rpc_handle = tokio::task::spawn_local(rpc_system);
+
+    requests::login(&root, &args.username).await?;
+    let admin_token = args.admin_token.or_else(|| std::env::var(ADMIN_TOKEN_VARIABLE).ok());
+    let result = run_command(&root, args.command, admin_token.as_deref(), args.json).await;
+
+    rpc_handle.abort();
+    result
+}
+
+async fn run_command(root: &root_service::Client, command: Command, admin_token: Option<&str>, json: bool) -> Result<(), BoxError> {
+    match command {
+        Command::Topics(command) => {
+            let topic_service = requests::get_topic_client(root).await?;
+            run_topic_command(&topic_service, command, json).await?;
+        }
+
+        Command::Messages { topic, limit } => {
+            let topic_service = requests::get_topic_client(root).await?;
+            let message_service = requests::get_message_client(root).await?;
+
+            let topic = find_topic(&topic_service, &topic).await?;
+            let mut messages = requests::get_messages(&message_service, topic.uuid).await?;
+            messages.drain(..messages.len().saturating_sub(limit));
+            print_all(&messages, json)?;
+        }
+        Command::Edit { message, content } => {
+            let message_service = requests::get_message_client(root).await?;
+            print_one(&requests::edit_message(&message_service, message, &content).await?, json)?;
+        }
+        Command::Revisions { message } => {
+            let message_service = requests::get_message_client(root).await?;
+            print_all(&requests::get_revisions(&message_service, message).await?, json)?;
+        }
+        Command::Thread { message } => {
+            let message_service = requests::get_message_client(root).await?;
+            print_all(&requests::get_thread(&message_service, message).await?, json)?;
+        }
+
+        Command::Notifications { unread, limit } => {
+            let message_service = requests::get_message_client(root).await?;
+            print_all(&requests::get_notifications(&message_service, unread, limit).await?, json)?;
+        }
+        Command::MarkRead { messages } => {
+            let message_service = requests::get_message_client(root).await?;
+            let marked = requests::mark_read(&message_service, &messages).await?;
+            print_one(&MarkedReadRecord { marked }, json)?;
+        }
+
+        Command::Presence => {
+            print_all(&requests::get_presence(root).await?, json)?;
+        }
+
+        Command::Sessions => {
+            let admin = requests::get_admin_client(root, admin_token).await?;
+            print_all(&requests::list_sessions(&admin).await?, json)?;
+        }
+        Command::Subscriptions => {
+            let admin = requests::get_admin_client(root, admin_token).await?;
+            print_all(&requests::list_subscriptions(&admin).await?, json)?;
+        }
+        Command::Kick { peer } => {
+            let admin = requests::get_admin_client(root, admin_token).await?;
+            let kicked = requests::kick(&admin, &peer).await?;
+            print_one(&KickRecord { peer, kicked }, json)?;
+        }
+        Command::Snapshot => {
+            let admin = requests::get_admin_client(root, admin_token).await?;
+            print_one(&requests::snapshot(&admin).await?, json)?;
+        }
+        Command::Stats => {
+            let admin = requests::get_admin_client(root, admin_token).await?;
+            print_one(&requests::get_stats(&admin).await?, json)?;
+        }
+        Command::ReadOnly { state } => {
+            let admin = requests::get_admin_client(root, admin_token).await?;
+            let read_only = matches!(state, Switch::On);
+            requests::set_read_only(&admin, read_only).await?;
+            print_one(&ReadOnlyRecord { read_only }, json)?;
+        }
+    }
+
+    Ok(())
+}
+
+async fn run_topic_command(topic_service: &topic_service::Client, command: TopicCommand, json: bool) -> Result<(), BoxError> {
+    match command {
+        TopicCommand::List { prefix, search, tags, sort, descending, limit, cursor } => {
+            let sort = match sort {
+                SortBy::Name => TopicSort::Name,
+                SortBy::Created => TopicSort::CreatedAt,
+                SortBy::Activity => TopicSort::LastActivity,
+            };
+            let query = requests::TopicQuery { name_prefix: &prefix, search: &search, tags: &tags, sort, descending };
+
+            let mut topics = vec![];
+            let mut cursor = Some(cursor);
+            while let Some(current) = cursor.take() {
+                let remaining = match limit {
+                    0 => MAX_PAGE_SIZE,
+                    limit => (limit - topics.len() as u32).min(MAX_PAGE_SIZE),
+                };
+                if remaining == 0 {
+                    eprintln!("Next cursor: {current}");
+                    break;
+                }
+
+                let (page, next_cursor) = requests::list_topics(topic_service, &query, remaining, &current).await?;
+                topics.extend(page);
+                cursor = next_cursor;
+            }
+            print_all(&topics, json)?;
+        }
+        TopicCommand::Create { name, partitions } => {
+            print_one(&requests::create_topic(topic_service, &name, partitions).await?, json)?;
+        }
+        TopicCommand::Rename { topic, new_name } => {
+            let topic = find_topic(topic_service, &topic).await?;
+            let updated = requests::update_topic(topic_service, topic.uuid, &new_name, topic.retention_minutes).await?;
+            print_one(&updated, json)?;
+        }
+        TopicCommand::Delete { topic } => {
+            let topic = find_topic(topic_service, &topic).await?;
+            requests::delete_topic(topic_service, topic.uuid).await?;
+            print_one(&DeletedRecord { uuid: topic.uuid, name: topic.name }, json)?;
+        }
+        TopicCommand::Retention { topic, minutes } => {
+            let retention_minutes = parse_retention(&minutes)?;
+            let topic = find_topic(topic_service, &topic).await?;
+            let updated = requests::update_topic(topic_service, topic.uuid, &topic.name, retention_minutes).await?;
+            print_one(&updated, json)?;
+        }
+        TopicCommand::Describe { topic, description } => {
+            let topic = find_topic(topic_service, &topic).await?;
+            let updated = requests::update_topic_metadata(topic_service, topic.uuid, &description, &topic.tags).await?;
+            print_one(&updated, json)?;
+        }
+        TopicCommand::Tag { topic, tags } => {
+            let topic = find_topic(topic_service, &topic).await?;
+            let updated = requests::update_topic_metadata(topic_service, topic.uuid, &topic.description, &tags).await?;
+            print_one(&updated, json)?;
+        }
+        TopicCommand::Stats { topic } => {
+            let topic = find_topic(topic_service, &topic).await?;
+            print_one(&requests::get_topic_stats(topic_service, &topic).await?, json)?;
+        }
+        TopicCommand::Subscribers { topic } => {
+            let topic = find_topic(topic_service, &topic).await?;
+            print_all(&requests::get_subscribers(topic_service, topic.uuid).await?, json)?;
+        }
+        TopicCommand::Pin { topic, message } => {
+            let topic = find_topic(topic_service, &topic).await?;
+            print_one(&requests::pin_message(topic_service, topic.uuid, message).await?, json)?;
+        }
+        TopicCommand::Unpin { topic, message } => {
+            let topic = find_topic(topic_service, &topic).await?;
+            print_one(&requests::unpin_message(topic_service, topic.uuid, message).await?, json)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Looks the topic up by UUID first, then by name.
+async fn find_topic(topic_service: &topic_service::Client, topic: &str) -> Result<TopicRecord, BoxError> {
+    if let Ok(uuid) = Uuid::parse_str(topic) {
+        if let Ok(found) = requests::get_topic(topic_service, uuid).await {
+            return Ok(found);
+        }
+    }
+
+    // The exact name sorts first among the names it is a prefix of
+    let query = requests::TopicQuery { name_prefix: topic, search: "", tags: &[], sort: TopicSort::Name, descending: false };
+    let (found, _) = requests::list_topics(topic_service, &query, 1, "").await?;
+    found.into_iter()
+        .find(|t| t.name == topic)
+        .ok_or_else(|| format!("Topic '{topic}' does not exist").into())
+}
+
+fn parse_retention(minutes: &str) -> Result<Option<f64>, BoxError> {
+    if minutes == "-" {
+        return Ok(None);
+    }
+
+    let minutes = minutes.parse::<f64>()?;
+    if minutes <= 0.0 {
+        return Err("Retention must be a positive number of minutes".into());
+    }
+    Ok(Some(minutes))
+}
+
+// ---- Printing utilities ----
+
+fn print_all<T: Record>(records: &[T], json: bool) -> Result<(), BoxError> {
+    if json {
+        println!("{}", serde_json::to_string(records)?);
+    } else {
+        for record in records {
+            println!("{record}");
+        }
+    }
+    Ok(())
+}
+
+fn print_one<T: Record>(record: &T, json: bool) -> Result<(), BoxError> {
+    if json {
+        println!("{}", serde_json::to_string(record)?);
+    } else {
+        println!("{record}");
+    }
+    Ok(())
+}
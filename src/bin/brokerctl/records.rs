@@ -0,0 +1,289 @@
+use std::collections::BTreeMap;
+use std::fmt::Display;
+
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use uuid::Uuid;
+
+/// A single line of `brokerctl` output. Printed as text for humans, or as JSON with `--json`.
+pub trait Record: Serialize + Display {}
+
+impl<T: Serialize + Display> Record for T {}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct TopicRecord {
+    pub uuid: Uuid,
+    pub name: String,
+    pub creator: String,
+    pub created_at: DateTime<Utc>,
+    pub retention_minutes: Option<f64>,
+    pub partitions: u32,
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub description: String,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub pinned_messages: Vec<Uuid>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub participants: Vec<String>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct MessageRecord {
+    pub uuid: Uuid,
+    pub topic_uuid: Uuid,
+    pub author: String,
+    pub content: String,
+    pub timestamp: DateTime<Utc>,
+    /// Base64 of the binary content, omitted for text messages
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub payload: Option<String>,
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub content_type: String,
+    #[serde(skip)]
+    pub payload_len: usize,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub headers: BTreeMap<String, String>,
+    pub partition: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub edited_at: Option<DateTime<Utc>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reply_to: Option<Uuid>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct RevisionRecord {
+    pub content: String,
+    pub timestamp: DateTime<Utc>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct TopicStatsRecord {
+    pub uuid: Uuid,
+    pub name: String,
+    pub messages: u64,
+    pub bytes: u64,
+    pub oldest_message: Option<DateTime<Utc>>,
+    pub newest_message: Option<DateTime<Utc>>,
+    pub last_activity: DateTime<Utc>,
+    pub subscribers: u32,
+    pub posts_last_minute: u32,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct SessionRecord {
+    pub peer: String,
+    pub username: Option<String>,
+    pub connected_at: DateTime<Utc>,
+    pub subscriptions: u32,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct SubscriptionsRecord {
+    pub topic_uuid: Uuid,
+    pub topic_name: String,
+    pub subscribers: Vec<SessionRecord>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct NotificationRecord {
+    pub message_uuid: Uuid,
+    pub topic_uuid: Uuid,
+    pub topic_name: String,
+    pub author: String,
+    pub excerpt: String,
+    pub timestamp: DateTime<Utc>,
+    pub read: bool,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct MarkedReadRecord {
+    pub marked: u32,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct PresenceRecord {
+    pub username: String,
+    /// `online`, `idle` or `offline`
+    pub status: &'static str,
+    /// None for users that were not seen since the server started
+    pub last_seen: Option<DateTime<Utc>>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct StatsRecord {
+    pub topics: u64,
+    pub messages: u64,
+    pub message_nodes: u64,
+    pub sessions: u64,
+    pub logged_in: u64,
+    pub read_only: bool,
+
+    pub list_reads: u64,
+    pub list_read_locks: u64,
+    pub list_appends: u64,
+    pub list_append_locks: u64,
+    pub list_append_misses: u64,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct SnapshotRecord {
+    pub bytes: u64,
+    pub duration_ms: f64,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct KickRecord {
+    pub peer: String,
+    pub kicked: bool,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct ReadOnlyRecord {
+    pub read_only: bool,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct DeletedRecord {
+    pub uuid: Uuid,
+    pub name: String,
+}
+
+fn format_timestamp(timestamp: &DateTime<Utc>) -> impl Display {
+    timestamp.with_timezone(&chrono::Local).format("%Y.%m.%d %H:%M:%S")
+}
+
+impl Display for TopicRecord {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let retention = match self.retention_minutes {
+            Some(minutes) => format!("{minutes} min"),
+            None => "-".to_string(),
+        };
+        write!(f, "{}\t{}\t{}\t{}\t{retention}", self.uuid, self.name, self.creator, format_timestamp(&self.created_at))?;
+        if self.partitions > 0 {
+            write!(f, "\t{} partitions", self.partitions)?;
+        }
+        if !self.tags.is_empty() {
+            write!(f, "\t#{}", self.tags.join(" #"))?;
+        }
+        if !self.pinned_messages.is_empty() {
+            write!(f, "\t{} pinned", self.pinned_messages.len())?;
+        }
+        if !self.description.is_empty() {
+            write!(f, "\n\t{}", self.description)?;
+        }
+        Ok(())
+    }
+}
+
+impl Display for MessageRecord {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "[{}] {} |> ", format_timestamp(&self.timestamp), self.author)?;
+        if self.payload.is_some() {
+            write!(f, "[{}, {} bytes] ", self.content_type, self.payload_len)?;
+        }
+        write!(f, "{}", self.content)?;
+        if self.edited_at.is_some() {
+            write!(f, " (edited)")?;
+        }
+        Ok(())
+    }
+}
+
+impl Display for RevisionRecord {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "[{}] {}", format_timestamp(&self.timestamp), self.content)
+    }
+}
+
+impl Display for TopicStatsRecord {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "Topic: {} ({})", self.name, self.uuid)?;
+        writeln!(f, "Messages: {} ({} bytes)", self.messages, self.bytes)?;
+        if let (Some(oldest), Some(newest)) = (&self.oldest_message, &self.newest_message) {
+            writeln!(f, "Posted: {} to {}", format_timestamp(oldest), format_timestamp(newest))?;
+        }
+        writeln!(f, "Last activity: {}", format_timestamp(&self.last_activity))?;
+        writeln!(f, "Subscribers: {}", self.subscribers)?;
+        write!(f, "Posts in the last minute: {}", self.posts_last_minute)
+    }
+}
+
+impl Display for SessionRecord {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let username = self.username.as_deref().unwrap_or("-");
+        write!(f, "{}\t{username}\t{}\t{} subscriptions", self.peer, format_timestamp(&self.connected_at), self.subscriptions)
+    }
+}
+
+impl Display for NotificationRecord {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let status = if self.read { "read" } else { "unread" };
+        write!(f, "{}\t{}\t{}\t{}\t{status}\t{}", self.message_uuid, format_timestamp(&self.timestamp), self.topic_name, self.author, self.excerpt)
+    }
+}
+
+impl Display for MarkedReadRecord {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Marked {} notifications as read", self.marked)
+    }
+}
+
+impl Display for PresenceRecord {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}\t{}", self.username, self.status)?;
+        if let Some(last_seen) = &self.last_seen {
+            write!(f, "\t{}", format_timestamp(last_seen))?;
+        }
+        Ok(())
+    }
+}
+
+impl Display for SubscriptionsRecord {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} ({}): {} subscribers", self.topic_name, self.topic_uuid, self.subscribers.len())?;
+        for subscriber in &self.subscribers {
+            write!(f, "\n\t{}\t{}", subscriber.peer, subscriber.username.as_deref().unwrap_or("-"))?;
+        }
+        Ok(())
+    }
+}
+
+impl Display for StatsRecord {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "Topics: {}", self.topics)?;
+        writeln!(f, "Messages: {} ({} nodes)", self.messages, self.message_nodes)?;
+        writeln!(f, "Sessions: {} ({} logged in)", self.sessions, self.logged_in)?;
+        writeln!(f, "Read-only: {}", self.read_only)?;
+        writeln!(f, "ConcurrentList reads: {} ({} locked)", self.list_reads, self.list_read_locks)?;
+        write!(f, "ConcurrentList appends: {} ({} locked, {} missed)", self.list_appends, self.list_append_locks, self.list_append_misses)
+    }
+}
+
+impl Display for SnapshotRecord {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Saved {} bytes in {:.1}ms", self.bytes, self.duration_ms)
+    }
+}
+
+impl Display for KickRecord {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.kicked {
+            write!(f, "Kicked {}", self.peer)
+        } else {
+            write!(f, "No session of {}", self.peer)
+        }
+    }
+}
+
+impl Display for ReadOnlyRecord {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Read-only mode is {}", if self.read_only { "on" } else { "off" })
+    }
+}
+
+impl Display for DeletedRecord {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Deleted topic '{}' ({})", self.name, self.uuid)
+    }
+}
@@ -0,0 +1,535 @@
+use base64::{engine::general_purpose::STANDARD, Engine};
+use broker::auth_capnp::{self, presence_watcher, user_presence};
+use broker::main_capnp::{admin_service, root_service};
+use broker::message_capnp::{message, message_service};
+use broker::topic_capnp::{self, topic, topic_service, TopicSort};
+use broker::util_capnp;
+use capnp::capability::Promise;
+use capnp::Error;
+use chrono::{DateTime, Utc};
+use uuid::Uuid;
+
+use crate::records::{MessageRecord, NotificationRecord, PresenceRecord, RevisionRecord, SessionRecord, SnapshotRecord, StatsRecord, SubscriptionsRecord, TopicRecord, TopicStatsRecord};
+
+
+pub async fn login(root: &root_service::Client, username: &str) -> Result<(), Error> {
+    let auth = root.auth_request().send().promise.await?.get()?.get_service()?;
+
+    let mut request = auth.login_request();
+    request.get().set_username(username);
+    request.send().promise.await?;
+
+    Ok(())
+}
+
+/// Presence of every user the server knows. Changes after the call are not followed.
+pub async fn get_presence(root: &root_service::Client) -> Result<Vec<PresenceRecord>, Error> {
+    let auth = root.auth_request().send().promise.await?.get()?.get_service()?;
+
+    let mut request = auth.watch_presence_request();
+    request.get().set_watcher(capnp_rpc::new_client(IgnoredPresenceChanges));
+    let response = request.send().promise.await?;
+
+    let mut users = response.get()?.get_users()?.iter()
+        .map(read_capnp_presence)
+        .collect::<Result<Vec<_>, _>>()?;
+    users.sort_by(|a, b| a.username.cmp(&b.username));
+    Ok(users)
+}
+
+/// The server only hands out the presence snapshot along with a watcher.
+struct IgnoredPresenceChanges;
+
+impl presence_watcher::Server for IgnoredPresenceChanges {
+    fn changed(&mut self, _: presence_watcher::ChangedParams) -> Promise<(), Error> {
+        Promise::ok(())
+    }
+}
+
+pub async fn get_topic_client(root: &root_service::Client) -> Result<topic_service::Client, Error> {
+    root.topic_request().send().promise.await?.get()?.get_service()
+}
+
+pub async fn get_message_client(root: &root_service::Client) -> Result<message_service::Client, Error> {
+    root.message_request().send().promise.await?.get()?.get_service()
+}
+
+/// Fails unless the token matches the admin token of the server.
+pub async fn get_admin_client(root: &root_service::Client, token: Option<&str>) -> Result<admin_service::Client, Error> {
+    let token = token.ok_or_else(|| Error::failed("Admin commands need --admin-token or BROKER_ADMIN_TOKEN".to_owned()))?;
+
+    let mut request = root.admin_request();
+    request.get().set_token(token);
+    request.send().promise.await?.get()?.get_service()
+}
+
+// ---- Topics ----
+
+/// Filters and order of a topic listing.
+pub struct TopicQuery<'a> {
+    pub name_prefix: &'a str,
+    pub search: &'a str,
+    pub tags: &'a [String],
+    pub sort: TopicSort,
+    pub descending: bool,
+}
+
+/// A single page of topics, along with the cursor of the next one. `None` on the last page.
+pub async fn list_topics(topic_service: &topic_service::Client, query: &TopicQuery<'_>, page_size: u32, cursor: &str) -> Result<(Vec<TopicRecord>, Option<String>), Error> {
+    let mut request = topic_service.list_topics_request();
+    let mut builder = request.get().init_query();
+    builder.set_name_prefix(query.name_prefix);
+    builder.set_search(query.search);
+    builder.set_sort(query.sort);
+    builder.set_descending(query.descending);
+    builder.set_page_size(page_size);
+    builder.set_cursor(cursor);
+    let mut tags_builder = builder.init_tags(query.tags.len() as u32);
+    for (i, tag) in query.tags.iter().enumerate() {
+        tags_builder.set(i as u32, tag);
+    }
+
+    let response = request.send().promise.await?;
+    match response.get()?.get_page()?.which()? {
+        util_capnp::result::Which::Ok(page) => {
+            let page = page?;
+            let topics = page.get_topics()?.iter()
+                .map(read_capnp_topic)
+                .collect::<Result<_, _>>()?;
+            let next_cursor = page.get_next_cursor()?.to_string()?;
+            Ok((topics, (!next_cursor.is_empty()).then_some(next_cursor)))
+        }
+        util_capnp::result::Which::Err(err) => Err(topic_error(err?)?),
+    }
+}
+
+pub async fn get_topic(topic_service: &topic_service::Client, uuid: Uuid) -> Result<TopicRecord, Error> {
+    let mut request = topic_service.get_topic_request();
+    fill_capnp_uuid(request.get().init_topic_id(), uuid);
+
+    let response = request.send().promise.await?;
+    match response.get()?.get_topic()?.which()? {
+        util_capnp::result::Which::Ok(topic) => read_capnp_topic(topic?),
+        util_capnp::result::Which::Err(err) => Err(topic_error(err?)?),
+    }
+}
+
+pub async fn get_topic_stats(topic_service: &topic_service::Client, topic: &TopicRecord) -> Result<TopicStatsRecord, Error> {
+    let mut request = topic_service.get_topic_stats_request();
+    fill_capnp_uuid(request.get().init_topic_id(), topic.uuid);
+
+    let response = request.send().promise.await?;
+    let stats = match response.get()?.get_stats()?.which()? {
+        util_capnp::result::Which::Ok(stats) => stats?,
+        util_capnp::result::Which::Err(err) => return Err(topic_error(err?)?),
+    };
+    let oldest_message = stats.get_oldest_message()?;
+    let newest_message = stats.get_newest_message()?;
+
+    Ok(TopicStatsRecord {
+        uuid: topic.uuid,
+        name: topic.name.clone(),
+        messages: stats.get_messages(),
+        bytes: stats.get_bytes(),
+        oldest_message: if oldest_message.has_t() { Some(read_capnp_timestamp(oldest_message.get_t()?)) } else { None },
+        newest_message: if newest_message.has_t() { Some(read_capnp_timestamp(newest_message.get_t()?)) } else { None },
+        last_activity: read_capnp_timestamp(stats.get_last_activity()?),
+        subscribers: stats.get_subscribers(),
+        posts_last_minute: stats.get_posts_last_minute(),
+    })
+}
+
+pub async fn create_topic(topic_service: &topic_service::Client, name: &str, partitions: u32) -> Result<TopicRecord, Error> {
+    let mut request = topic_service.create_topic_request();
+    request.get().set_name(name);
+    request.get().set_partitions(partitions);
+
+    let response = request.send().promise.await?;
+    match response.get()?.get_topic()?.which()? {
+        util_capnp::result::Which::Ok(topic) => read_capnp_topic(topic?),
+        util_capnp::result::Which::Err(err) => Err(topic_error(err?)?),
+    }
+}
+
+/// Replaces the name and the retention of the topic.
+pub async fn update_topic(topic_service: &topic_service::Client, uuid: Uuid, name: &str, retention_minutes: Option<f64>) -> Result<TopicRecord, Error> {
+    let mut request = topic_service.update_topic_request();
+    let mut builder = request.get();
+
+    fill_capnp_uuid(builder.reborrow().init_topic_id(), uuid);
+    builder.set_name(name);
+    let mut retention = builder.init_retention();
+    match retention_minutes {
+        Some(minutes) => retention.set_minutes(minutes),
+        None => retention.set_none(()),
+    }
+
+    let response = request.send().promise.await?;
+    match response.get()?.get_topic()?.which()? {
+        util_capnp::result::Which::Ok(topic) => read_capnp_topic(topic?),
+        util_capnp::result::Which::Err(err) => Err(topic_error(err?)?),
+    }
+}
+
+/// Replaces the description and the tags of the topic.
+pub async fn update_topic_metadata(topic_service: &topic_service::Client, uuid: Uuid, description: &str, tags: &[String]) -> Result<TopicRecord, Error> {
+    let mut request = topic_service.update_topic_metadata_request();
+    let mut builder = request.get();
+
+    fill_capnp_uuid(builder.reborrow().init_topic_id(), uuid);
+    builder.set_description(description);
+    let mut tags_builder = builder.init_tags(tags.len() as u32);
+    for (i, tag) in tags.iter().enumerate() {
+        tags_builder.set(i as u32, tag);
+    }
+
+    let response = request.send().promise.await?;
+    match response.get()?.get_topic()?.which()? {
+        util_capnp::result::Which::Ok(topic) => read_capnp_topic(topic?),
+        util_capnp::result::Which::Err(err) => Err(topic_error(err?)?),
+    }
+}
+
+pub async fn pin_message(topic_service: &topic_service::Client, uuid: Uuid, message_uuid: Uuid) -> Result<TopicRecord, Error> {
+    let mut request = topic_service.pin_message_request();
+    fill_capnp_uuid(request.get().init_topic_id(), uuid);
+    fill_capnp_uuid(request.get().init_message_id(), message_uuid);
+
+    let response = request.send().promise.await?;
+    match response.get()?.get_topic()?.which()? {
+        util_capnp::result::Which::Ok(topic) => read_capnp_topic(topic?),
+        util_capnp::result::Which::Err(err) => Err(topic_error(err?)?),
+    }
+}
+
+pub async fn unpin_message(topic_service: &topic_service::Client, uuid: Uuid, message_uuid: Uuid) -> Result<TopicRecord, Error> {
+    let mut request = topic_service.unpin_message_request();
+    fill_capnp_uuid(request.get().init_topic_id(), uuid);
+    fill_capnp_uuid(request.get().init_message_id(), message_uuid);
+
+    let response = request.send().promise.await?;
+    match response.get()?.get_topic()?.which()? {
+        util_capnp::result::Which::Ok(topic) => read_capnp_topic(topic?),
+        util_capnp::result::Which::Err(err) => Err(topic_error(err?)?),
+    }
+}
+
+pub async fn delete_topic(topic_service: &topic_service::Client, uuid: Uuid) -> Result<(), Error> {
+    let mut request = topic_service.delete_topic_request();
+    fill_capnp_uuid(request.get().init_topic_id(), uuid);
+
+    let response = request.send().promise.await?;
+    match response.get()?.get_result()?.which()? {
+        util_capnp::result::Which::Ok(_) => Ok(()),
+        util_capnp::result::Which::Err(err) => Err(topic_error(err?)?),
+    }
+}
+
+/// Users subscribed to the topic, sorted by name.
+pub async fn get_subscribers(topic_service: &topic_service::Client, uuid: Uuid) -> Result<Vec<PresenceRecord>, Error> {
+    let mut request = topic_service.get_subscribers_request();
+    fill_capnp_uuid(request.get().init_topic_id(), uuid);
+
+    let response = request.send().promise.await?;
+    match response.get()?.get_subscribers()?.which()? {
+        util_capnp::result::Which::Ok(subscribers) => subscribers?.iter().map(read_capnp_presence).collect(),
+        util_capnp::result::Which::Err(err) => Err(topic_error(err?)?),
+    }
+}
+
+fn topic_error(reader: topic_service::error::Reader<'_>) -> Result<Error, Error> {
+    let message = match reader.which()? {
+        topic_service::error::Which::NotFound(()) => "Topic does not exist",
+        topic_service::error::Which::AlreadyExists(()) => "Topic already exists",
+        topic_service::error::Which::ReadOnly(()) => "Server is in read-only mode",
+        topic_service::error::Which::TooManyPartitions(max) => return Ok(Error::failed(format!("Topics may have at most {max} partitions"))),
+        topic_service::error::Which::InvalidMetadata(reason) => return Ok(Error::failed(format!("Invalid metadata: {}", reason?.to_str()?))),
+        topic_service::error::Which::MessageNotFound(()) => "Message does not exist in the topic",
+        topic_service::error::Which::InvalidCursor(reason) => return Ok(Error::failed(format!("Invalid cursor: {}", reason?.to_str()?))),
+    };
+    Ok(Error::failed(message.to_owned()))
+}
+
+// ---- Messages ----
+
+/// All messages of the topic, oldest first.
+pub async fn get_messages(message_service: &message_service::Client, topic_uuid: Uuid) -> Result<Vec<MessageRecord>, Error> {
+    let mut request = message_service.get_messages_sync_request();
+    fill_capnp_uuid(request.get().init_topic_id(), topic_uuid);
+
+    let response = request.send().promise.await?;
+    let mut messages = match response.get()?.get_messages()?.which()? {
+        util_capnp::result::Which::Ok(messages) => messages?.iter()
+            .map(read_capnp_message)
+            .collect::<Result<Vec<_>, _>>()?,
+        util_capnp::result::Which::Err(err) => {
+            let message = match err?.which()? {
+                message_service::error::Which::RateLimited(retry_after) => format!("Rate limited, retry in {retry_after:.1}s"),
+                _ => "Topic does not exist".to_owned(),
+            };
+            return Err(Error::failed(message));
+        }
+    };
+
+    messages.sort_by_key(|message| message.timestamp);
+    Ok(messages)
+}
+
+pub async fn edit_message(message_service: &message_service::Client, message_uuid: Uuid, content: &str) -> Result<MessageRecord, Error> {
+    let mut request = message_service.edit_message_request();
+    fill_capnp_uuid(request.get().init_message_id(), message_uuid);
+    request.get().set_content(content);
+
+    let response = request.send().promise.await?;
+    match response.get()?.get_message()?.which()? {
+        util_capnp::result::Which::Ok(message) => read_capnp_message(message?),
+        util_capnp::result::Which::Err(err) => Err(message_error(err?)?),
+    }
+}
+
+/// Previous contents of the message, oldest first.
+pub async fn get_revisions(message_service: &message_service::Client, message_uuid: Uuid) -> Result<Vec<RevisionRecord>, Error> {
+    let mut request = message_service.get_revisions_request();
+    fill_capnp_uuid(request.get().init_message_id(), message_uuid);
+
+    let response = request.send().promise.await?;
+    match response.get()?.get_revisions()?.which()? {
+        util_capnp::result::Which::Ok(revisions) => revisions?.iter()
+            .map(|revision| Ok(RevisionRecord {
+                content: revision.get_content()?.to_string()?,
+                timestamp: read_capnp_timestamp(revision.get_timestamp()?),
+            }))
+            .collect(),
+        util_capnp::result::Which::Err(err) => Err(message_error(err?)?),
+    }
+}
+
+/// The root message followed by all the replies to it, in the order they were posted.
+pub async fn get_thread(message_service: &message_service::Client, root_uuid: Uuid) -> Result<Vec<MessageRecord>, Error> {
+    let mut request = message_service.get_thread_request();
+    fill_capnp_uuid(request.get().init_root_message_id(), root_uuid);
+
+    let response = request.send().promise.await?;
+    match response.get()?.get_messages()?.which()? {
+        util_capnp::result::Which::Ok(messages) => messages?.iter()
+            .map(read_capnp_message)
+            .collect(),
+        util_capnp::result::Which::Err(err) => Err(message_error(err?)?),
+    }
+}
+
+/// Mentions of the `--username`, newest first. A `limit` of 0 gets all of them.
+pub async fn get_notifications(message_service: &message_service::Client, unread_only: bool, limit: u32) -> Result<Vec<NotificationRecord>, Error> {
+    let mut request = message_service.get_notifications_request();
+    request.get().set_unread_only(unread_only);
+    request.get().set_limit(limit);
+
+    let response = request.send().promise.await?;
+    response.get()?.get_notifications()?.iter()
+        .map(|notification| Ok(NotificationRecord {
+            message_uuid: read_capnp_uuid(notification.get_message_id()?),
+            topic_uuid: read_capnp_uuid(notification.get_topic_id()?),
+            topic_name: notification.get_topic_name()?.to_string()?,
+            author: notification.get_author_name()?.to_string()?,
+            excerpt: notification.get_excerpt()?.to_string()?,
+            timestamp: read_capnp_timestamp(notification.get_timestamp()?),
+            read: notification.get_read(),
+        }))
+        .collect()
+}
+
+/// Marks notifications of the messages as read, or all of them for an empty slice. Returns how many were unread.
+pub async fn mark_read(message_service: &message_service::Client, messages: &[Uuid]) -> Result<u32, Error> {
+    let mut request = message_service.mark_read_request();
+    let mut capnp_uuids = request.get().init_message_ids(messages.len() as u32);
+    for (index, uuid) in messages.iter().enumerate() {
+        fill_capnp_uuid(capnp_uuids.reborrow().get(index as u32), *uuid);
+    }
+
+    let response = request.send().promise.await?;
+    Ok(response.get()?.get_marked())
+}
+
+fn message_error(err: message_service::error::Reader<'_>) -> Result<Error, Error> {
+    let message = match err.which()? {
+        message_service::error::Which::EntityDoesNotExist(()) => "Message does not exist",
+        message_service::error::Which::InvalidContent(reason) => return Ok(Error::failed(format!("Invalid content: {}", reason?.to_str()?))),
+        message_service::error::Which::ReadOnly(()) => "Server is in read-only mode",
+        message_service::error::Which::RateLimited(retry_after) => return Ok(Error::failed(format!("Rate limited, retry in {retry_after:.1}s"))),
+        message_service::error::Which::InvalidFilter(reason) => return Ok(Error::failed(format!("Invalid filter: {}", reason?.to_str()?))),
+        message_service::error::Which::NotAuthor(()) => "Only the author may edit the message",
+        message_service::error::Which::ReplyTargetNotFound(()) => "Replied message does not exist in the topic",
+        message_service::error::Which::InvalidRecipients(reason) => return Ok(Error::failed(format!("Invalid recipients: {}", reason?.to_str()?))),
+    };
+    Ok(Error::failed(message.to_owned()))
+}
+
+// ---- Admin ----
+
+pub async fn list_sessions(admin: &admin_service::Client) -> Result<Vec<SessionRecord>, Error> {
+    admin.list_sessions_request()
+        .send().promise.await?
+        .get()?
+        .get_sessions()?
+        .iter()
+        .map(read_capnp_session)
+        .collect()
+}
+
+pub async fn list_subscriptions(admin: &admin_service::Client) -> Result<Vec<SubscriptionsRecord>, Error> {
+    let response = admin.list_subscriptions_request().send().promise.await?;
+
+    response.get()?.get_topics()?.iter()
+        .map(|topic| Ok(SubscriptionsRecord {
+            topic_uuid: read_capnp_uuid(topic.get_topic_id()?),
+            topic_name: topic.get_topic_name()?.to_string()?,
+            subscribers: topic.get_subscribers()?.iter()
+                .map(read_capnp_session)
+                .collect::<Result<_, _>>()?,
+        }))
+        .collect()
+}
+
+/// Returns `false` if the peer has no session on the server.
+pub async fn kick(admin: &admin_service::Client, peer: &str) -> Result<bool, Error> {
+    let mut request = admin.kick_request();
+    request.get().set_peer(peer);
+
+    Ok(request.send().promise.await?.get()?.get_kicked())
+}
+
+pub async fn snapshot(admin: &admin_service::Client) -> Result<SnapshotRecord, Error> {
+    let response = admin.snapshot_request().send().promise.await?;
+    let response = response.get()?;
+
+    Ok(SnapshotRecord {
+        bytes: response.get_bytes(),
+        duration_ms: response.get_duration_ms(),
+    })
+}
+
+pub async fn get_stats(admin: &admin_service::Client) -> Result<StatsRecord, Error> {
+    let response = admin.get_stats_request().send().promise.await?;
+    let stats = response.get()?.get_stats()?;
+
+    Ok(StatsRecord {
+        topics: stats.get_topics(),
+        messages: stats.get_messages(),
+        message_nodes: stats.get_message_nodes(),
+        sessions: stats.get_sessions(),
+        logged_in: stats.get_logged_in(),
+        read_only: stats.get_read_only(),
+        list_reads: stats.get_list_reads(),
+        list_read_locks: stats.get_list_read_locks(),
+        list_appends: stats.get_list_appends(),
+        list_append_locks: stats.get_list_append_locks(),
+        list_append_misses: stats.get_list_append_misses(),
+    })
+}
+
+pub async fn set_read_only(admin: &admin_service::Client, enabled: bool) -> Result<(), Error> {
+    let mut request = admin.set_read_only_request();
+    request.get().set_enabled(enabled);
+    request.send().promise.await?;
+
+    Ok(())
+}
+
+// ---- Readers ----
+
+fn read_capnp_uuid(reader: util_capnp::uuid::Reader<'_>) -> Uuid {
+    Uuid::from_u64_pair(reader.get_upper(), reader.get_lower())
+}
+
+fn fill_capnp_uuid(mut builder: util_capnp::uuid::Builder<'_>, uuid: Uuid) {
+    let (upper, lower) = uuid.as_u64_pair();
+    builder.set_upper(upper);
+    builder.set_lower(lower);
+}
+
+fn read_capnp_timestamp(reader: util_capnp::timestamp::Reader<'_>) -> DateTime<Utc> {
+    DateTime::from_timestamp(reader.get_seconds(), reader.get_nanos()).unwrap_or_default()
+}
+
+fn read_capnp_topic(reader: topic::Reader<'_>) -> Result<TopicRecord, Error> {
+    let retention_minutes = match reader.get_retention()?.which()? {
+        topic_capnp::retention::Which::None(()) => None,
+        topic_capnp::retention::Which::Minutes(minutes) => Some(minutes),
+    };
+
+    Ok(TopicRecord {
+        uuid: read_capnp_uuid(reader.get_uuid()?),
+        name: reader.get_name()?.to_string()?,
+        creator: reader.get_owner_username()?.to_string()?,
+        created_at: read_capnp_timestamp(reader.get_created_at()?),
+        retention_minutes,
+        partitions: reader.get_partitions(),
+        description: reader.get_description()?.to_string()?,
+        tags: reader.get_tags()?.iter()
+            .map(|tag| Ok(tag?.to_string()?))
+            .collect::<Result<_, Error>>()?,
+        pinned_messages: reader.get_pinned_messages()?.iter()
+            .map(read_capnp_uuid)
+            .collect(),
+        participants: reader.get_participants()?.iter()
+            .map(|participant| Ok(participant?.to_string()?))
+            .collect::<Result<_, Error>>()?,
+    })
+}
+
+fn read_capnp_message(reader: message::Reader<'_>) -> Result<MessageRecord, Error> {
+    let payload = reader.get_payload()?;
+
+    Ok(MessageRecord {
+        uuid: read_capnp_uuid(reader.get_uuid()?),
+        topic_uuid: read_capnp_uuid(reader.get_topic_uuid()?),
+        author: reader.get_author_name()?.to_string()?,
+        content: reader.get_content()?.to_string()?,
+        timestamp: read_capnp_timestamp(reader.get_timestamp()?),
+        payload: (!payload.is_empty()).then(|| STANDARD.encode(payload)),
+        content_type: reader.get_content_type()?.to_string()?,
+        payload_len: payload.len(),
+        partition: reader.get_partition(),
+        edited_at: {
+            let edited_at = reader.get_edited_at()?;
+            if edited_at.has_t() { Some(read_capnp_timestamp(edited_at.get_t()?)) } else { None }
+        },
+        reply_to: {
+            let reply_to = reader.get_reply_to()?;
+            if reply_to.has_t() { Some(read_capnp_uuid(reply_to.get_t()?)) } else { None }
+        },
+        headers: reader.get_headers()?.iter()
+            .map(|header| Ok((header.get_key()?.to_string()?, header.get_value()?.to_string()?)))
+            .collect::<Result<_, Error>>()?,
+    })
+}
+
+fn read_capnp_presence(reader: user_presence::Reader<'_>) -> Result<PresenceRecord, Error> {
+    let last_seen = read_capnp_timestamp(reader.get_last_seen()?);
+
+    Ok(PresenceRecord {
+        username: reader.get_username()?.to_string()?,
+        status: match reader.get_status()? {
+            auth_capnp::PresenceStatus::Online => "online",
+            auth_capnp::PresenceStatus::Idle => "idle",
+            auth_capnp::PresenceStatus::Offline => "offline",
+        },
+        last_seen: (last_seen != DateTime::<Utc>::MIN_UTC).then_some(last_seen),
+    })
+}
+
+fn read_capnp_session(reader: admin_service::session::Reader<'_>) -> Result<SessionRecord, Error> {
+    let username = reader.get_username()?;
+    let username = if username.has_t() {
+        Some(username.get_t()?.to_string()?)
+    } else {
+        None
+    };
+
+    Ok(SessionRecord {
+        peer: reader.get_peer()?.to_string()?,
+        username,
+        connected_at: read_capnp_timestamp(reader.get_connected_at()?),
+        subscriptions: reader.get_subscriptions(),
+    })
+}
@@ -1,3 +1,4 @@
+use std::collections::BTreeMap;
 use std::fmt::Display;
 
 use chrono::{DateTime, Duration, Utc};
@@ -12,6 +13,34 @@ pub struct Topic {
     pub creator: String,
     pub timestamp: DateTime<Utc>,
     pub retention: Retention,
+    pub partitions: u32,
+    pub description: String,
+    pub tags: Vec<String>,
+    pub pinned_messages: Vec<Uuid>,
+    /// Sorted usernames of a direct conversation. Empty for public topics
+    pub participants: Vec<String>,
+}
+
+impl Topic {
+    /// How a direct conversation is shown to one of its participants, e.g. `@alice,bob`.
+    pub fn conversation_label(&self, username: &str) -> String {
+        let others = self.participants.iter()
+            .filter(|participant| *participant != username)
+            .map(String::as_str)
+            .collect::<Vec<_>>();
+        format!("@{}", others.join(","))
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct TopicStats {
+    pub messages: u64,
+    pub bytes: u64,
+    pub oldest_message: Option<DateTime<Utc>>,
+    pub newest_message: Option<DateTime<Utc>>,
+    pub last_activity: DateTime<Utc>,
+    pub subscribers: u32,
+    pub posts_last_minute: u32,
 }
 
 #[derive(Clone, Debug, Default)]
@@ -22,6 +51,101 @@ pub struct Message {
     pub content: String,
     pub timestamp: DateTime<Utc>,
     pub key: Option<String>,
+    /// Binary content. Empty for text messages
+    pub payload: Vec<u8>,
+    pub content_type: String,
+    pub headers: BTreeMap<String, String>,
+    pub partition: u32,
+    pub edited_at: Option<DateTime<Utc>>,
+    pub reply_to: Option<Uuid>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PresenceStatus {
+    Online,
+    Idle,
+    Offline,
+}
+
+#[derive(Clone, Debug)]
+pub struct UserPresence {
+    pub username: String,
+    pub status: PresenceStatus,
+    /// Last post or typing notice, or when the user went offline
+    pub last_seen: DateTime<Utc>,
+}
+
+impl Message {
+    /// Whether the content has `@username` in it, the same way the server finds mentions.
+    pub fn mentions(&self, username: &str) -> bool {
+        let is_username_char = |c: char| c.is_alphanumeric() || matches!(c, '_' | '-' | '.');
+
+        self.content.match_indices('@').any(|(index, _)| {
+            let is_in_word = self.content[..index].chars().next_back().is_some_and(|before| before.is_alphanumeric() || before == '@');
+            let rest = &self.content[index + 1..];
+            let end = rest.find(|c: char| !is_username_char(c)).unwrap_or(rest.len());
+            !is_in_word && rest[..end].trim_end_matches(['.', '-']) == username
+        })
+    }
+}
+
+/// Mention of the user in a message.
+#[derive(Clone, Debug)]
+pub struct Notification {
+    pub message_uuid: Uuid,
+    pub topic_uuid: Uuid,
+    /// Empty if the topic was deleted
+    pub topic_name: String,
+    pub author_name: String,
+    pub excerpt: String,
+    pub timestamp: DateTime<Utc>,
+    pub read: bool,
+}
+
+/// Change of a topic made by anyone, with the state of the topic after it.
+#[derive(Clone, Debug)]
+pub enum TopicEvent {
+    Created(Topic),
+    Renamed { topic: Topic, old_name: String },
+    RetentionChanged(Topic),
+    Deleted(Topic),
+    MetadataChanged(Topic),
+}
+
+#[derive(Clone, Debug, Default)]
+pub enum KeyFilter {
+    #[default]
+    Any,
+    Equals(String),
+    Prefix(String),
+}
+
+#[derive(Clone, Debug, Default)]
+pub enum ContentFilter {
+    #[default]
+    Any,
+    Contains(String),
+    Regex(String),
+}
+
+/// Sent along with subscriptions, so the server only delivers matching messages.
+#[derive(Clone, Debug, Default)]
+pub struct MessageFilter {
+    pub author_name: Option<String>,
+    pub key: KeyFilter,
+    pub headers: Vec<(String, String)>,
+    pub content: ContentFilter,
+    pub partitions: Vec<u32>,
+}
+
+impl MessageFilter {
+    pub fn is_empty(&self) -> bool {
+        self.author_name.is_none()
+            && matches!(self.key, KeyFilter::Any)
+            && self.headers.is_empty()
+            && matches!(self.content, ContentFilter::Any)
+            && self.partitions.is_empty()
+    }
 }
 
 
@@ -30,6 +154,47 @@ impl Display for Topic {
         writeln!(f, "Topic '{}' ({})", self.name, self.uuid)?;
         writeln!(f, "\tCreator: {}", self.creator)?;
         write!(f, "\tTimestamp: {}", self.timestamp.with_timezone(&chrono::Local).format("%Y.%m.%d %H:%M:%S"))?;
+        if self.partitions > 0 {
+            write!(f, "\n\tPartitions: {}", self.partitions)?;
+        }
+        if !self.description.is_empty() {
+            write!(f, "\n\tDescription: {}", self.description)?;
+        }
+        if !self.tags.is_empty() {
+            write!(f, "\n\tTags: {}", self.tags.join(", "))?;
+        }
+        for pinned in &self.pinned_messages {
+            write!(f, "\n\tPinned: {pinned}")?;
+        }
         Ok(())
     }
-}
\ No newline at end of file
+}
+impl Display for UserPresence {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.status {
+            PresenceStatus::Online => write!(f, "{} (online)", self.username),
+            PresenceStatus::Idle | PresenceStatus::Offline => {
+                let status = if self.status == PresenceStatus::Idle { "idle" } else { "offline" };
+                if self.last_seen == DateTime::<Utc>::MIN_UTC {
+                    write!(f, "{} ({status})", self.username)
+                } else {
+                    let last_seen = self.last_seen.with_timezone(&chrono::Local).format("%Y.%m.%d %H:%M:%S");
+                    write!(f, "{} ({status}, last seen {last_seen})", self.username)
+                }
+            }
+        }
+    }
+}
+impl Display for TopicStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let format_time = |timestamp: DateTime<Utc>| timestamp.with_timezone(&chrono::Local).format("%Y.%m.%d %H:%M:%S").to_string();
+
+        writeln!(f, "\tMessages: {} ({} bytes)", self.messages, self.bytes)?;
+        if let (Some(oldest), Some(newest)) = (self.oldest_message, self.newest_message) {
+            writeln!(f, "\tPosted: {} to {}", format_time(oldest), format_time(newest))?;
+        }
+        writeln!(f, "\tLast activity: {}", format_time(self.last_activity))?;
+        writeln!(f, "\tSubscribers: {}", self.subscribers)?;
+        write!(f, "\tPosts in the last minute: {}", self.posts_last_minute)
+    }
+}
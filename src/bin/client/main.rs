@@ -1,26 +1,50 @@
-use std::collections::HashMap;
+use std::cell::{Cell, RefCell};
+use std::collections::{BTreeMap, HashMap};
 use std::io::{stdout, Write};
 use std::net::SocketAddr;
+use std::rc::Rc;
 use std::str::FromStr;
+use std::sync::OnceLock;
 
 use chrono::Duration;
 use futures::future::join_all;
-use network::connect_to_server;
-use tokio::{io::BufReader, task::LocalSet};
+use network::{connect_to_server, Backoff, RpcSystemHandle};
+use tokio::io::{BufReader, Stdin};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+use tokio::task::LocalSet;
 use cli::read_line;
 use clap::{arg, Parser};
 
+use broker::main_capnp::root_service;
 use broker::topic_capnp::topic_service;
 use broker::message_capnp::message_service;
-use datatypes::{Message, Topic};
+use datatypes::{ContentFilter, KeyFilter, Message, MessageFilter, PresenceStatus, Topic, TopicEvent, UserPresence};
+use requests::NewMessage;
+use seen_messages::SeenMessages;
+use session_listener_impl::SessionListener;
+use message_receiver_impl::{MessageReceiver, PatternReceiver};
+use notification_watcher_impl::NotificationWatcher;
+use presence_watcher_impl::PresenceWatcher;
+use topic_watcher_impl::TopicWatcher;
+use uuid::Uuid;
 
 mod datatypes;
 mod message_receiver_impl;
+mod notification_watcher_impl;
+mod presence_watcher_impl;
+mod seen_messages;
+mod session_listener_impl;
+mod topic_watcher_impl;
 mod readers;
 mod cli;
 mod requests;
 mod network;
 
+/// Newest messages of a direct conversation shown by `/inbox` and when it is started
+const INBOX_MESSAGES: usize = 20;
+
+/// Messages mentioning this user are highlighted
+static OWN_USERNAME: OnceLock<String> = OnceLock::new();
 
 #[derive(Parser, Debug, Clone)]
 pub struct CliArgs {
@@ -31,32 +55,169 @@ pub struct CliArgs {
 
     #[arg(short, long)]
     pub topics: Vec<String>, 
+
+    /// Seconds between pings to the server. The connection is considered lost when a ping takes longer
+    #[arg(long, default_value_t = 5.0)]
+    pub heartbeat: f64,
+
+    /// Only show messages of this author
+    #[arg(long)]
+    pub filter_author: Option<String>,
+
+    /// Only show messages with this key
+    #[arg(long, conflicts_with = "filter_key_prefix")]
+    pub filter_key: Option<String>,
+
+    /// Only show messages with a key starting with this prefix
+    #[arg(long)]
+    pub filter_key_prefix: Option<String>,
+
+    /// Only show messages with this header, as `KEY=VALUE`. Can be repeated
+    #[arg(long, value_parser = parse_header)]
+    pub filter_header: Vec<(String, String)>,
+
+    /// Only show messages containing this text
+    #[arg(long, conflicts_with = "filter_regex")]
+    pub filter_content: Option<String>,
+
+    /// Only show messages matching this regex
+    #[arg(long)]
+    pub filter_regex: Option<String>,
+
+    /// Only show messages of these partitions of partitioned topics, e.g. `0,2`
+    #[arg(long, value_delimiter = ',')]
+    pub partitions: Vec<u32>,
+}
+
+impl CliArgs {
+    fn message_filter(&self) -> MessageFilter {
+        let key = match (&self.filter_key, &self.filter_key_prefix) {
+            (Some(key), _) => KeyFilter::Equals(key.clone()),
+            (None, Some(prefix)) => KeyFilter::Prefix(prefix.clone()),
+            (None, None) => KeyFilter::Any,
+        };
+        let content = match (&self.filter_content, &self.filter_regex) {
+            (Some(text), _) => ContentFilter::Contains(text.clone()),
+            (None, Some(pattern)) => ContentFilter::Regex(pattern.clone()),
+            (None, None) => ContentFilter::Any,
+        };
+
+        MessageFilter {
+            author_name: self.filter_author.clone(),
+            key,
+            headers: self.filter_header.clone(),
+            content,
+            partitions: self.partitions.clone(),
+        }
+    }
+}
+
+/// Names with wildcards, like `builds.linux.*`, are subscribed to as patterns.
+fn is_pattern(topic_name: &str) -> bool {
+    topic_name.contains(['*', '#'])
+}
+
+fn parse_header(s: &str) -> Result<(String, String), String> {
+    let (key, value) = s.split_once('=').ok_or_else(|| format!("Expected `KEY=VALUE`, got '{s}'"))?;
+    Ok((key.to_string(), value.to_string()))
 }
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let args = CliArgs::parse();
-    let mut wanted_topics = args.topics;
+    let filter = args.message_filter();
+    let (patterns, mut wanted_topics): (Vec<_>, Vec<_>) = args.topics.into_iter().partition(|topic| is_pattern(topic));
     if wanted_topics.is_empty() {
         eprintln!("No topics specified with `--topics`. Choosing 'general' automatically.");
         wanted_topics.push("general".to_string());
     }
 
-    LocalSet::new().run_until(run_client(args.address, args.username, &mut wanted_topics)).await?;
+    let heartbeat = std::time::Duration::from_secs_f64(args.heartbeat);
+    let _ = OWN_USERNAME.set(args.username.clone());
+    let state = ClientState::new(args.username.clone(), patterns, filter);
+    LocalSet::new().run_until(run_client(args.address, args.username, &mut wanted_topics, heartbeat, state)).await?;
     Ok(())
 }
 
-async fn do_work(message_service: &message_service::Client, topic_service: &topic_service::Client, topics: &mut [Topic]) -> Result<(), Box<dyn std::error::Error>> {
-    let mut buf = String::new();
-    let mut reader = BufReader::new(tokio::io::stdin());
+/// Everything that has to outlive a single connection to the server.
+struct ClientState {
+    username: String,
+    topics: Vec<Topic>,
+    /// Direct conversations, subscribed to along with the topics
+    conversations: Vec<Topic>,
+    /// Current names of the topics, for the live subscriptions to print
+    topic_names: Rc<RefCell<HashMap<Uuid, String>>>,
+    /// Presence of every user the server knows, kept up to date by the server
+    presence: Rc<RefCell<HashMap<String, UserPresence>>>,
+    current_topic_id: usize,
+    key: Option<String>,
+    headers: BTreeMap<String, String>,
+    patterns: Vec<String>,
+    filter: MessageFilter,
+    seen: Rc<RefCell<SeenMessages>>,
+    /// Newest message posted to every topic from this client, for `/edit`
+    last_posted: HashMap<Uuid, Uuid>,
+    kicked: Rc<Cell<bool>>,
+    topic_events: UnboundedReceiver<TopicEvent>,
+    topic_events_sender: UnboundedSender<TopicEvent>,
+
+    reader: BufReader<Stdin>,
+    buf: String,
+}
 
-    let mut current_topic_id = 0;
-    let mut key: Option<String> = None;
+impl ClientState {
+    fn new(username: String, patterns: Vec<String>, filter: MessageFilter) -> Self {
+        let (topic_events_sender, topic_events) = unbounded_channel();
+
+        Self {
+            username,
+            topics: vec![],
+            conversations: vec![],
+            topic_names: Rc::new(RefCell::new(HashMap::new())),
+            presence: Rc::new(RefCell::new(HashMap::new())),
+            current_topic_id: 0,
+            key: None,
+            headers: BTreeMap::new(),
+            patterns,
+            filter,
+            seen: Rc::new(RefCell::new(SeenMessages::default())),
+            last_posted: HashMap::new(),
+            kicked: Rc::new(Cell::new(false)),
+            topic_events,
+            topic_events_sender,
+            reader: BufReader::new(tokio::io::stdin()),
+            buf: String::new(),
+        }
+    }
+}
 
+/// A live connection to the server.
+struct Connection {
+    rpc_system: RpcSystemHandle,
+    root_service: root_service::Client,
+    topic_service: topic_service::Client,
+    message_service: message_service::Client,
+}
+
+enum SessionEnd {
+    Quit,
+    Lost(String),
+}
+
+async fn do_work(message_service: &message_service::Client, topic_service: &topic_service::Client, state: &mut ClientState) -> Result<(), Box<dyn std::error::Error>> {
     loop {
-        print!("\rv | {} |\n", topics[current_topic_id].name);
+        print!("\rv | {} |\n", state.topics[state.current_topic_id].name);
         stdout().flush()?;
-        let line = read_line(&mut reader, &mut buf).await?;
+        let line = tokio::select! {
+            line = read_line(&mut state.reader, &mut state.buf) => line?,
+            Some(event) = state.topic_events.recv() => {
+                match event {
+                    TopicEvent::Created(topic) if !topic.participants.is_empty() => join_conversation(message_service, state, topic).await?,
+                    event => apply_topic_event(state, event),
+                }
+                continue;
+            }
+        };
         
         let trimmed = line.trim();
 
@@ -76,29 +237,79 @@ async fn do_work(message_service: &message_service::Client, topic_service: &topi
                     let find_topic_id = cmd_args.next()
                         .and_then(
                             |topic_name| 
-                                topics.iter()
+                                state.topics.iter()
                                     .enumerate()
                                     .filter(|(_, t)| t.name == topic_name)
                                     .map(|(i, _)| i)
                                     .next()
                         );
                     if let Some(topic_id) = find_topic_id {
-                        current_topic_id = topic_id;
+                        state.current_topic_id = topic_id;
                     } else {
-                        println!("Available topics: {:?}", topics.iter().map(|t| &t.name).collect::<Box<[_]>>())
+                        println!("Available topics: {:?}", state.topics.iter().map(|t| &t.name).collect::<Box<[_]>>())
                     }
                 }
                 "/key" => {
-                    key = cmd_args.next().map(|x| x.to_string());
-                    println!("New key set: {key:?}");
+                    state.key = cmd_args.next().map(|x| x.to_string());
+                    println!("New key set: {:?}", state.key);
+                }
+                "/header" => {
+                    command_header(&mut state.headers, cmd_args);
                 }
                 "/retention" => {
-                    command_retention(topic_service, &mut topics[current_topic_id], cmd_args).await?;
+                    command_retention(topic_service, &mut state.topics[state.current_topic_id], cmd_args).await?;
+                }
+                "/file" => {
+                    command_file(message_service, state, cmd_args).await?;
+                }
+                "/reply" => {
+                    command_reply(message_service, state, trimmed.strip_prefix("/reply").unwrap_or_default().trim()).await?;
+                }
+                "/thread" => {
+                    command_thread(message_service, state, cmd_args.next()).await?;
+                }
+                "/dm" => {
+                    command_dm(message_service, topic_service, state, trimmed.strip_prefix("/dm").unwrap_or_default().trim()).await?;
+                }
+                "/inbox" => {
+                    command_inbox(message_service, topic_service, state, cmd_args.next()).await?;
+                }
+                "/edit" => {
+                    command_edit(message_service, state, trimmed.strip_prefix("/edit").unwrap_or_default().trim()).await?;
+                }
+                "/info" => {
+                    println!("{}", state.topics[state.current_topic_id]);
+                }
+                "/who" => {
+                    command_who(topic_service, state).await?;
+                }
+                "/online" => {
+                    command_online(state);
+                }
+                "/mentions" => {
+                    command_mentions(message_service, cmd_args.next() == Some("all")).await?;
+                }
+                "/typing" => {
+                    if let Err(e) = requests::send_typing(message_service, state.topics[state.current_topic_id].uuid).await {
+                        if e.kind == capnp::ErrorKind::Disconnected {
+                            return Err(e.into());
+                        }
+                        eprintln!("Failed to send typing notice: {}", e.extra);
+                    }
+                }
+                "/stats" => {
+                    let topic = &state.topics[state.current_topic_id];
+                    match requests::get_topic_stats(topic_service, topic.uuid).await {
+                        Err(e) if e.kind == capnp::ErrorKind::Disconnected => return Err(e.into()),
+                        Err(e) => eprintln!("Failed to get stats: {}", e.extra),
+                        Ok(stats) => println!("Topic '{}':\n{stats}", topic.name),
+                    }
                 }
 
                 _regular_message => {
-                    let key_ref = key.as_ref().map(|x| x.as_str());
-                    requests::post_message(message_service, trimmed, topics[current_topic_id].uuid, key_ref).await?;
+                    let posted = requests::post_message(message_service, state.topics[state.current_topic_id].uuid, &text_message(state, trimmed)).await;
+                    remember_posted(&mut state.last_posted, &posted);
+                    report_rejection(posted)?;
                 }
             }
         }
@@ -107,6 +318,359 @@ async fn do_work(message_service: &message_service::Client, topic_service: &topi
     Ok(())
 }
 
+/// Lists users subscribed to the current topic, with their presence.
+async fn command_who(topic_service: &topic_service::Client, state: &ClientState) -> Result<(), capnp::Error> {
+    let topic = &state.topics[state.current_topic_id];
+    let subscribers = match requests::get_subscribers(topic_service, topic.uuid).await {
+        Err(e) if e.kind == capnp::ErrorKind::Disconnected => return Err(e),
+        Err(e) => {
+            eprintln!("Failed to get subscribers: {}", e.extra);
+            return Ok(());
+        }
+        Ok(subscribers) => subscribers,
+    };
+
+    println!("Subscribed to '{}':", topic.name);
+    for subscriber in &subscribers {
+        println!("\t{subscriber}");
+    }
+    Ok(())
+}
+
+/// Lists users that are online or idle, then the offline ones.
+fn command_online(state: &ClientState) {
+    let presence = state.presence.borrow();
+    let mut users = presence.values().collect::<Vec<_>>();
+    users.sort_by(|a, b| (a.status == PresenceStatus::Offline, &a.username).cmp(&(b.status == PresenceStatus::Offline, &b.username)));
+
+    for user in users {
+        println!("\t{user}");
+    }
+}
+
+/// `/mentions` shows unread mentions, oldest first, and marks them as read. `/mentions all` includes the newest read ones.
+async fn command_mentions(message_service: &message_service::Client, all: bool) -> Result<(), capnp::Error> {
+    let notifications = if all {
+        requests::get_notifications(message_service, false, INBOX_MESSAGES as u32).await?
+    } else {
+        requests::get_notifications(message_service, true, 0).await?
+    };
+    if notifications.is_empty() {
+        println!("{}", if all { "No mentions" } else { "No new mentions" });
+        return Ok(());
+    }
+
+    for notification in notifications.iter().rev() {
+        let topic_name = if notification.topic_name.is_empty() { "deleted topic" } else { &notification.topic_name };
+        let timestamp = notification.timestamp.with_timezone(&chrono::Local).format("%Y.%m.%d %H:%M:%S");
+        let new = if notification.read { "" } else { " (new)" };
+        println!("[{topic_name} | {timestamp}] {} {} |> {}{new}", short_id(notification.message_uuid), notification.author_name, notification.excerpt);
+    }
+
+    let uuids = notifications.iter().map(|notification| notification.message_uuid).collect::<Vec<_>>();
+    requests::mark_read(message_service, &uuids).await?;
+    Ok(())
+}
+
+/// Keeps the list of topics in sync with changes made by others.
+fn apply_topic_event(state: &mut ClientState, event: TopicEvent) {
+    let subscribed = |topic: &Topic| state.topics.iter().position(|t| t.uuid == topic.uuid);
+
+    match event {
+        TopicEvent::Created(topic) => {
+            println!("\rTopic '{}' was created by {}", topic.name, topic.creator);
+        }
+        TopicEvent::Renamed { topic, old_name } => {
+            println!("\rTopic '{old_name}' was renamed to '{}'", topic.name);
+            if let Some(index) = subscribed(&topic) {
+                state.topic_names.borrow_mut().insert(topic.uuid, topic.name.clone());
+                state.topics[index] = topic;
+            }
+        }
+        TopicEvent::RetentionChanged(topic) => {
+            if let Some(index) = subscribed(&topic) {
+                let retention = match topic.retention {
+                    Some(retention) => format!("{} minutes", retention.num_seconds() as f64 / 60.0),
+                    None => "unlimited".to_string(),
+                };
+                println!("\rRetention of topic '{}' is now {retention}", topic.name);
+                state.topics[index] = topic;
+            }
+        }
+        TopicEvent::MetadataChanged(topic) => {
+            if let Some(index) = subscribed(&topic) {
+                println!("\rDescription, tags or pins of topic '{}' changed, see `/info`", topic.name);
+                state.topics[index] = topic;
+            }
+        }
+        TopicEvent::Deleted(topic) => {
+            println!("\rTopic '{}' was deleted", topic.name);
+            let Some(index) = subscribed(&topic) else {
+                return;
+            };
+            // The last topic stays, so there is something to show in the prompt
+            if state.topics.len() == 1 {
+                println!("Messages to '{}' will not be sent. Restart with other `--topics`.", topic.name);
+                return;
+            }
+
+            state.topics.remove(index);
+            if state.current_topic_id >= index && state.current_topic_id > 0 {
+                state.current_topic_id -= 1;
+            }
+        }
+    }
+}
+
+/// `/dm <user>[,<user>...] <content...>` sends a direct message, starting the conversation if needed.
+async fn command_dm(message_service: &message_service::Client, topic_service: &topic_service::Client, state: &mut ClientState, args: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let Some((recipients, content)) = args.split_once(' ') else {
+        println!("Send a direct message via `/dm user text`, or `/dm user1,user2 text` to a group.");
+        return Ok(());
+    };
+    let recipients = recipients.split(',').collect::<Vec<_>>();
+
+    let message = match requests::send_direct(message_service, &recipients, content.trim()).await {
+        Err(e) if e.kind == capnp::ErrorKind::Disconnected => return Err(e.into()),
+        Err(e) => {
+            eprintln!("\rDirect message was not sent: {}", e.extra);
+            return Ok(());
+        }
+        Ok(message) => message,
+    };
+
+    // The first message starts the conversation, which is joined right away to see the answers
+    if !state.conversations.iter().any(|conversation| conversation.uuid == message.topic_uuid) {
+        let conversations = requests::list_conversations(topic_service).await?;
+        if let Some(conversation) = conversations.into_iter().find(|conversation| conversation.uuid == message.topic_uuid) {
+            join_conversation(message_service, state, conversation).await?;
+        }
+    }
+    Ok(())
+}
+
+/// `/inbox` lists the direct conversations, `/inbox <user>[,<user>...]` shows the newest messages of one of them.
+async fn command_inbox(message_service: &message_service::Client, topic_service: &topic_service::Client, state: &ClientState, label: Option<&str>) -> Result<(), capnp::Error> {
+    let conversations = requests::list_conversations(topic_service).await?;
+
+    let Some(label) = label else {
+        if conversations.is_empty() {
+            println!("No direct messages yet, start a conversation via `/dm user text`.");
+        }
+        for conversation in &conversations {
+            println!("{} (started by {})", conversation.conversation_label(&state.username), conversation.creator);
+        }
+        return Ok(());
+    };
+
+    let mut others = label.trim_start_matches('@').split(',').collect::<Vec<_>>();
+    others.sort();
+    let label = format!("@{}", others.join(","));
+    let Some(conversation) = conversations.iter().find(|conversation| conversation.conversation_label(&state.username) == label) else {
+        println!("No conversation with {label}, see `/inbox`.");
+        return Ok(());
+    };
+
+    let messages = requests::get_messages(message_service, conversation.uuid).await?;
+    for message in &messages[messages.len().saturating_sub(INBOX_MESSAGES)..] {
+        print_message(message, &label);
+    }
+    Ok(())
+}
+
+/// Subscribes to a direct conversation. Messages posted before the subscription are printed too.
+async fn join_conversation(message_service: &message_service::Client, state: &mut ClientState, conversation: Topic) -> Result<(), capnp::Error> {
+    if state.conversations.iter().any(|known| known.uuid == conversation.uuid) {
+        return Ok(());
+    }
+    let label = conversation.conversation_label(&state.username);
+    if conversation.creator != state.username {
+        println!("\r{} started a conversation with you, answer via `/dm {} text`", conversation.creator, label.trim_start_matches('@'));
+    }
+
+    state.topic_names.borrow_mut().insert(conversation.uuid, label.clone());
+    let history = get_history_for_topics(message_service, std::slice::from_ref(&conversation), &state.topic_names, &state.seen, INBOX_MESSAGES as u32, &MessageFilter::default()).await?;
+    state.conversations.push(conversation);
+    for message in &history {
+        print_message(message, &label);
+    }
+    Ok(())
+}
+
+/// Text message with the key and headers currently set by `/key` and `/header`.
+fn text_message<'a>(state: &'a ClientState, content: &'a str) -> NewMessage<'a> {
+    NewMessage {
+        content,
+        payload: &[],
+        content_type: "",
+        key: state.key.as_deref(),
+        headers: &state.headers,
+        reply_to: None,
+    }
+}
+
+/// Full UUID of a message that was already shown, from the short ID printed along with it.
+fn resolve_message_prefix(state: &ClientState, prefix: &str) -> Option<Uuid> {
+    match state.seen.borrow().find_by_prefix(prefix).as_slice() {
+        [uuid] => Some(*uuid),
+        [] => {
+            println!("No message starts with '{prefix}'.");
+            None
+        }
+        _ => {
+            println!("Several messages start with '{prefix}', type more of the ID.");
+            None
+        }
+    }
+}
+
+/// `/reply <message id prefix> <content...>` answers the message in the current topic.
+async fn command_reply(message_service: &message_service::Client, state: &mut ClientState, args: &str) -> Result<(), capnp::Error> {
+    let Some((prefix, content)) = args.split_once(' ') else {
+        println!("Reply via `/reply <message id> text`, the ID is printed before the author and may be shortened.");
+        return Ok(());
+    };
+    let Some(reply_to) = resolve_message_prefix(state, prefix) else {
+        return Ok(());
+    };
+
+    let message = NewMessage { reply_to: Some(reply_to), ..text_message(state, content.trim()) };
+    let posted = requests::post_message(message_service, state.topics[state.current_topic_id].uuid, &message).await;
+    remember_posted(&mut state.last_posted, &posted);
+    report_rejection(posted)
+}
+
+/// `/thread <message id prefix>` prints the message with all the replies to it, indented by depth.
+async fn command_thread(message_service: &message_service::Client, state: &ClientState, prefix: Option<&str>) -> Result<(), capnp::Error> {
+    let Some(prefix) = prefix else {
+        println!("Show a thread via `/thread <message id>`.");
+        return Ok(());
+    };
+    let Some(root_uuid) = resolve_message_prefix(state, prefix) else {
+        return Ok(());
+    };
+
+    let thread = match requests::get_thread(message_service, root_uuid).await {
+        Err(e) if e.kind == capnp::ErrorKind::Disconnected => return Err(e),
+        Err(e) => {
+            eprintln!("\rFailed to get the thread: {}", e.extra);
+            return Ok(());
+        }
+        Ok(thread) => thread,
+    };
+
+    // Replies come after the message they answer, so depths of parents are always known
+    let topic_names = state.topic_names.borrow();
+    let mut depths = HashMap::new();
+    for message in &thread {
+        let depth = message.reply_to
+            .and_then(|reply_to| depths.get(&reply_to))
+            .map_or(0, |depth| depth + 1);
+        depths.insert(message.uuid, depth);
+
+        let topic_name = topic_names.get(&message.topic_uuid).map_or("?", String::as_str);
+        println!("\r{}{}", "    ".repeat(depth), format_message(message, topic_name));
+    }
+    Ok(())
+}
+
+fn remember_posted(last_posted: &mut HashMap<Uuid, Uuid>, posted: &Result<Message, capnp::Error>) {
+    if let Ok(message) = posted {
+        last_posted.insert(message.topic_uuid, message.uuid);
+    }
+}
+
+/// `/edit <content...>` replaces the content of the last message posted to the current topic.
+async fn command_edit(message_service: &message_service::Client, state: &ClientState, content: &str) -> Result<(), capnp::Error> {
+    let topic = &state.topics[state.current_topic_id];
+    let Some(&message_uuid) = state.last_posted.get(&topic.uuid) else {
+        println!("Nothing to edit, no message was posted to '{}' yet.", topic.name);
+        return Ok(());
+    };
+    if content.is_empty() {
+        println!("Replace the last message via `/edit new content`.");
+        return Ok(());
+    }
+
+    match requests::edit_message(message_service, message_uuid, content).await {
+        Err(e) if e.kind == capnp::ErrorKind::Disconnected => Err(e),
+        Err(e) => {
+            eprintln!("\rMessage was not edited: {}", e.extra);
+            Ok(())
+        }
+        Ok(_) => Ok(()),
+    }
+}
+
+/// Rejected messages are reported, only a lost connection stops the work.
+fn report_rejection(posted: Result<Message, capnp::Error>) -> Result<(), capnp::Error> {
+    match posted {
+        Err(e) if e.kind == capnp::ErrorKind::Disconnected => Err(e),
+        Err(e) => {
+            eprintln!("\rMessage was not sent: {}", e.extra);
+            Ok(())
+        }
+        Ok(_) => Ok(()),
+    }
+}
+
+/// `/file <path> [content type] [caption...]` sends the file as a binary message.
+async fn command_file(message_service: &message_service::Client, state: &mut ClientState, mut cmd_args: impl Iterator<Item = &str>) -> Result<(), capnp::Error> {
+    let Some(path) = cmd_args.next() else {
+        println!("Send a file via `/file path/to/file [content/type] [caption]`.");
+        return Ok(());
+    };
+
+    let payload = match tokio::fs::read(path).await {
+        Ok(payload) if payload.is_empty() => {
+            eprintln!("File '{path}' is empty");
+            return Ok(());
+        }
+        Ok(payload) => payload,
+        Err(e) => {
+            eprintln!("Failed to read '{path}': {e}");
+            return Ok(());
+        }
+    };
+    let content_type = cmd_args.next().unwrap_or_else(|| guess_content_type(path));
+    let caption = cmd_args.collect::<Vec<_>>().join(" ");
+
+    let message = NewMessage { content: &caption, payload: &payload, content_type, ..text_message(state, "") };
+    let posted = requests::post_message(message_service, state.topics[state.current_topic_id].uuid, &message).await;
+    remember_posted(&mut state.last_posted, &posted);
+    report_rejection(posted)
+}
+
+/// `/header <key> <value...>` attaches a header to the following messages, `/header <key>` removes it.
+fn command_header<'a>(headers: &mut BTreeMap<String, String>, mut cmd_args: impl Iterator<Item = &'a str>) {
+    let Some(key) = cmd_args.next() else {
+        println!("Current headers: {headers:?}");
+        println!("Set a header via `/header key value`, remove it via `/header key`.");
+        return;
+    };
+
+    let value = cmd_args.collect::<Vec<_>>().join(" ");
+    if value.is_empty() {
+        headers.remove(key);
+    } else {
+        headers.insert(key.to_string(), value);
+    }
+    println!("Current headers: {headers:?}");
+}
+
+fn guess_content_type(path: &str) -> &'static str {
+    let extension = path.rsplit_once('.').map(|(_, extension)| extension.to_lowercase());
+    match extension.as_deref() {
+        Some("png") => "image/png",
+        Some("jpg" | "jpeg") => "image/jpeg",
+        Some("gif") => "image/gif",
+        Some("json") => "application/json",
+        Some("pdf") => "application/pdf",
+        Some("txt") => "text/plain",
+        _ => "application/octet-stream",
+    }
+}
+
 async fn command_retention(topic_service: &topic_service::Client, current_topic: &mut Topic, mut cmd_args: impl Iterator<Item = &str>) -> Result<(), capnp::Error> {
     if let Some(new_retention) = cmd_args.next() {
         // Parse retention
@@ -140,45 +704,152 @@ async fn command_retention(topic_service: &topic_service::Client, current_topic:
     Ok(())
 }
 
-async fn run_client(addr: SocketAddr, username: String, wanted_topic_names: &[String]) -> Result<(), Box<dyn std::error::Error>> {
-    // Connect and get services
+async fn run_client(addr: SocketAddr, username: String, wanted_topic_names: &[String], heartbeat: std::time::Duration, mut state: ClientState) -> Result<(), Box<dyn std::error::Error>> {
+    let mut backoff = Backoff::new(std::time::Duration::from_millis(500), std::time::Duration::from_secs(30));
+
+    // Failing to connect at start is fatal. Once connected, the client keeps reconnecting
     println!("Connecting to server on {addr}");
-    let (rpc_system, root_service) = connect_to_server(addr).await?;
+    let mut connection = start_session(addr, &username, wanted_topic_names, &mut state).await?;
 
-    let topic_service = requests::get_topic_client(&root_service).await?;
-    let message_service = requests::get_message_client(&root_service).await?;
+    loop {
+        let end = serve_session(&mut connection, &mut state, heartbeat).await?;
+        connection.rpc_system.abort();
 
-    // Authorize
-    requests::autorize(&root_service, &username).await?;
+        let SessionEnd::Lost(reason) = end else {
+            break;
+        };
+        eprintln!("\rLost connection to the server: {reason}");
+        if state.kicked.get() {
+            return Err("Kicked by an admin, not reconnecting".into());
+        }
 
-    // Get or create topics
-    let mut topics = ensure_topics_exist(&topic_service, wanted_topic_names).await?;
-    show_topics(&topics);
+        connection = loop {
+            let delay = backoff.next_delay();
+            eprintln!("Reconnecting in {delay:?}...");
+            tokio::time::sleep(delay).await;
 
-    // Get old messages & subscribe to new messages
-    let total_history = get_history_for_topics(&message_service, &topics, 100).await?;
-    print_messages(total_history.iter(), &topics);
+            match start_session(addr, &username, wanted_topic_names, &mut state).await {
+                Ok(connection) => break connection,
+                Err(e) => eprintln!("Failed to reconnect: {e}"),
+            }
+        };
+        backoff.reset();
+        println!("Reconnected to the server");
+    }
 
-    // Do work
-    do_work(&message_service, &topic_service, &mut topics).await?;
     println!("All work done");
-
-    rpc_system.abort();
     Ok(())
 }
 
+/// Connects, logs in and subscribes to the topics. Prints the messages that were missed since the previous session.
+async fn start_session(addr: SocketAddr, username: &str, wanted_topic_names: &[String], state: &mut ClientState) -> Result<Connection, Box<dyn std::error::Error>> {
+    let (rpc_system, root_service) = connect_to_server(addr).await?;
+
+    let session = async {
+        let topic_service = requests::get_topic_client(&root_service).await?;
+        let message_service = requests::get_message_client(&root_service).await?;
+
+        // Authorize
+        requests::autorize(&root_service, username).await?;
+        requests::set_session_listener(&root_service, SessionListener { kicked: state.kicked.clone() }).await?;
+        let users = requests::watch_presence(&root_service, PresenceWatcher { users: state.presence.clone(), username: username.to_string() }).await?;
+        *state.presence.borrow_mut() = users.into_iter()
+            .map(|user| (user.username.clone(), user))
+            .collect();
+
+        // Get or create topics. Server may have lost them while we were away
+        let topics = ensure_topics_exist(&topic_service, wanted_topic_names).await?;
+        let is_reconnect = !state.topics.is_empty();
+        if !is_reconnect {
+            show_topics(&topics);
+        }
+        state.topics = topics;
+        *state.topic_names.borrow_mut() = state.topics.iter()
+            .map(|topic| (topic.uuid, topic.name.clone()))
+            .collect();
+
+        // Only after our own topics were created, so they are not reported back
+        requests::watch_topics(&topic_service, TopicWatcher { events: state.topic_events_sender.clone() }).await?;
+
+        // Subscribe to new messages & get the ones we have not seen yet
+        let history = get_history_for_topics(&message_service, &state.topics, &state.topic_names, &state.seen, 100, &state.filter).await?;
+        print_messages(history.iter(), &state.topics);
+
+        // Direct messages are not filtered. Their history is left for `/inbox`, only the ones missed while reconnecting are printed
+        let conversations = requests::list_conversations(&topic_service).await?;
+        for conversation in &conversations {
+            state.topic_names.borrow_mut().insert(conversation.uuid, conversation.conversation_label(username));
+        }
+        let missed = get_history_for_topics(&message_service, &conversations, &state.topic_names, &state.seen, INBOX_MESSAGES as u32, &MessageFilter::default()).await?;
+        if is_reconnect {
+            let topic_names = state.topic_names.borrow();
+            for message in &missed {
+                print_message(message, topic_names.get(&message.topic_uuid).map_or("?", String::as_str));
+            }
+        }
+        state.conversations = conversations;
+
+        // Mentions that arrive while away are announced once, on start
+        requests::watch_notifications(&message_service, NotificationWatcher { topic_names: state.topic_names.clone() }).await?;
+        if !is_reconnect {
+            let unread = requests::get_notifications(&message_service, true, 0).await?;
+            if !unread.is_empty() {
+                println!("You have {} unread mentions, see /mentions", unread.len());
+            }
+        }
+
+        // Patterns only get messages posted from now on
+        for pattern in &state.patterns {
+            let live_seen = state.seen.clone();
+            let receiver = PatternReceiver::new(
+                move |message, topic_name| if live_seen.borrow_mut().insert(&message) {
+                    print_message(&message, &topic_name)
+                },
+                |message, topic_name| print_message(&message, &topic_name),
+            );
+            requests::subscribe_pattern(&message_service, receiver, pattern, &state.filter).await?;
+        }
+
+        Ok::<_, capnp::Error>((topic_service, message_service))
+    };
+
+    match session.await {
+        Ok((topic_service, message_service)) => Ok(Connection { rpc_system, root_service, topic_service, message_service }),
+        Err(e) => {
+            rpc_system.abort();
+            Err(e.into())
+        }
+    }
+}
+
+/// Runs user commands until the user quits or the connection is lost.
+async fn serve_session(connection: &mut Connection, state: &mut ClientState, heartbeat: std::time::Duration) -> Result<SessionEnd, Box<dyn std::error::Error>> {
+    tokio::select! {
+        result = do_work(&connection.message_service, &connection.topic_service, state) => match result {
+            Ok(()) => Ok(SessionEnd::Quit),
+            Err(e) => match e.downcast::<capnp::Error>() {
+                Ok(e) if e.kind == capnp::ErrorKind::Disconnected => Ok(SessionEnd::Lost(e.to_string())),
+                Ok(e) => Err(e),
+                Err(e) => Err(e),
+            },
+        },
+        error = network::watch_connection(&connection.root_service, heartbeat) => Ok(SessionEnd::Lost(error.to_string())),
+        result = &mut connection.rpc_system => {
+            let reason = match result {
+                Ok(Ok(())) => "connection closed".to_string(),
+                Ok(Err(e)) => e.to_string(),
+                Err(e) => e.to_string(),
+            };
+            Ok(SessionEnd::Lost(reason))
+        }
+    }
+}
+
 async fn ensure_topics_exist(topic_service: &topic_service::Client, topics: &[String]) -> Result<Vec<Topic>, capnp::Error> {
-    let all_topics = requests::get_all_topics(&topic_service).await?;
     let mut results = vec![];
 
-    let topic_by_name = |name: &str| 
-        all_topics.iter()
-            .filter(|topic| topic.name == name)
-            .next()
-            .cloned();
-
     for wanted_topic_name in topics {
-        if let Some(topic) = topic_by_name(wanted_topic_name) {
+        if let Some(topic) = requests::find_topic(topic_service, wanted_topic_name).await? {
             results.push(topic);
         } else {
             let new_topic = requests::create_topic(topic_service, &wanted_topic_name).await?;
@@ -189,16 +860,41 @@ async fn ensure_topics_exist(topic_service: &topic_service::Client, topics: &[St
     Ok(results)
 }
 
-async fn get_history_for_topics(message_service: &message_service::Client, topics: &[Topic], max_messages: u32) -> Result<Vec<Message>, capnp::Error> {
+/// Subscribes to all topics and returns their history in chronological order.
+/// Messages that are already in `seen` are skipped, both in the history and in the live updates.
+async fn get_history_for_topics(message_service: &message_service::Client, topics: &[Topic], topic_names: &Rc<RefCell<HashMap<Uuid, String>>>, seen: &Rc<RefCell<SeenMessages>>, max_messages: u32, filter: &MessageFilter) -> Result<Vec<Message>, capnp::Error> {
     // Subscribe to all topics in parallel
     let handles = topics.iter()
         .map(|topic| {
-            let topic_name = topic.name.clone();
+            let topic_names = topic_names.clone();
+            let edited_topic_names = topic_names.clone();
+            let typing_topic_names = topic_names.clone();
+            let topic_uuid = topic.uuid;
+            let live_seen = seen.clone();
+            let last_seen = seen.borrow().last_seen(&topic.uuid);
+
+            let live_receiver = MessageReceiver::new(
+                move |message| if live_seen.borrow_mut().insert(&message) {
+                    let topic_names = topic_names.borrow();
+                    print_message(&message, topic_names.get(&message.topic_uuid).map_or("?", String::as_str))
+                },
+                move |message| {
+                    let topic_names = edited_topic_names.borrow();
+                    print_message(&message, topic_names.get(&message.topic_uuid).map_or("?", String::as_str))
+                },
+                move |username| {
+                    let topic_names = typing_topic_names.borrow();
+                    println!("\r{username} is typing in {}...", topic_names.get(&topic_uuid).map_or("?", String::as_str))
+                },
+            );
+
             requests::subscribe_and_get_messages(
-                &message_service, 
-                topic, 
-                move |message| print_message(&message, &topic_name), 
-                max_messages
+                &message_service,
+                topic,
+                live_receiver,
+                last_seen,
+                max_messages,
+                filter,
             )
         })
         .collect::<Vec<_>>();
@@ -208,17 +904,47 @@ async fn get_history_for_topics(message_service: &message_service::Client, topic
     let mut total_history = topics_histories.into_iter().flatten().collect::<Vec<_>>();
 
     total_history.sort_by(|a, b| a.timestamp.cmp(&b.timestamp));
+
+    // Live updates may have already shown some of them
+    let mut seen = seen.borrow_mut();
+    total_history.retain(|message| seen.insert(message));
     Ok(total_history)
 }
 
 // ---- Printing utilities ----
 
 pub fn print_message(message: &Message, topic_name: &str) {
+    println!("\r{}", format_message(message, topic_name))
+}
+
+/// A line of chat. Messages are marked with the first 8 characters of their UUID, which `/reply` and `/thread` accept.
+fn format_message(message: &Message, topic_name: &str) -> String {
     let timestamp = &message.timestamp;
     let author = &message.author_name;
-    let content = &message.content;
+    let content = if message.payload.is_empty() {
+        message.content.clone()
+    } else {
+        // Binary payloads are not printed, only described
+        let summary = format!("[{}, {} bytes]", message.content_type, message.payload.len());
+        match message.content.as_str() {
+            "" => summary,
+            caption => format!("{summary} {caption}"),
+        }
+    };
+
+    let edited = if message.edited_at.is_some() { " (edited)" } else { "" };
+    let reply_to = message.reply_to.map(|uuid| format!(" (re {})", short_id(uuid))).unwrap_or_default();
+    let line = format!("[{topic_name} | {}] {} {author}{reply_to} |> {content}{edited}", timestamp.with_timezone(&chrono::Local).format("%Y.%m.%d %H:%M:%S"), short_id(message.uuid));
+
+    // Bold, so mentions stand out
+    match OWN_USERNAME.get() {
+        Some(username) if message.mentions(username) => format!("\x1b[1m{line}\x1b[0m"),
+        _ => line,
+    }
+}
 
-    println!("\r[{topic_name} | {}] {author} |> {content}", timestamp.with_timezone(&chrono::Local).format("%Y.%m.%d %H:%M:%S"))
+fn short_id(uuid: Uuid) -> String {
+    uuid.to_string()[..8].to_string()
 }
 
 fn print_messages<'a>(messages: impl Iterator<Item = &'a Message>, all_topics: &[Topic]) {
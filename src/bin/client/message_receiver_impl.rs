@@ -1,6 +1,7 @@
 use std::io::{stdout, Write};
 
-use broker::message_capnp::message_receiver::{self, ReceiveParams};
+use broker::message_capnp::message_receiver::{self, EditedParams, ReceiveParams, TypingParams};
+use broker::message_capnp::pattern_receiver;
 use capnp::capability::Promise;
 use capnp_rpc::pry;
 
@@ -9,12 +10,18 @@ use crate::{datatypes::Message, readers::read_capnp_message};
 
 pub struct MessageReceiver {
     action: Box<dyn FnMut(Message)>,
+    edited_action: Box<dyn FnMut(Message)>,
+    typing_action: Box<dyn FnMut(String)>,
 }
 
 impl MessageReceiver {
-    pub fn new(action: impl 'static + FnMut(Message)) -> Self {
+    /// `edited_action` gets messages that were received before, with their new content.
+    /// `typing_action` gets names of other users typing in the topic.
+    pub fn new(action: impl 'static + FnMut(Message), edited_action: impl 'static + FnMut(Message), typing_action: impl 'static + FnMut(String)) -> Self {
         Self {
             action: Box::new(action),
+            edited_action: Box::new(edited_action),
+            typing_action: Box::new(typing_action),
         }
     }
 }
@@ -30,4 +37,59 @@ impl message_receiver::Server for MessageReceiver {
         stdout().flush().unwrap();
         Promise::ok(())
     }
-}
\ No newline at end of file
+
+    fn edited(&mut self, params: EditedParams) -> Promise<(), capnp::Error> {
+        let message = pry!(read_capnp_message(pry!(pry!(params.get()).get_message())));
+
+        (self.edited_action)(message);
+        stdout().flush().unwrap();
+        Promise::ok(())
+    }
+
+    fn typing(&mut self, params: TypingParams) -> Promise<(), capnp::Error> {
+        let username = pry!(pry!(pry!(params.get()).get_username()).to_string());
+
+        (self.typing_action)(username);
+        stdout().flush().unwrap();
+        Promise::ok(())
+    }
+}
+
+/// Receives messages of pattern subscriptions, along with the name of their topic.
+pub struct PatternReceiver {
+    action: Box<dyn FnMut(Message, String)>,
+    edited_action: Box<dyn FnMut(Message, String)>,
+}
+
+impl PatternReceiver {
+    pub fn new(action: impl 'static + FnMut(Message, String), edited_action: impl 'static + FnMut(Message, String)) -> Self {
+        Self {
+            action: Box::new(action),
+            edited_action: Box::new(edited_action),
+        }
+    }
+}
+
+impl pattern_receiver::Server for PatternReceiver {
+    fn receive(&mut self, params: pattern_receiver::ReceiveParams) -> Promise<(), capnp::Error> {
+        let reader = pry!(params.get());
+
+        let message = pry!(read_capnp_message(pry!(reader.get_message())));
+        let topic_name = pry!(pry!(reader.get_topic_name()).to_string());
+
+        (self.action)(message, topic_name);
+        stdout().flush().unwrap();
+        Promise::ok(())
+    }
+
+    fn edited(&mut self, params: pattern_receiver::EditedParams) -> Promise<(), capnp::Error> {
+        let reader = pry!(params.get());
+
+        let message = pry!(read_capnp_message(pry!(reader.get_message())));
+        let topic_name = pry!(pry!(reader.get_topic_name()).to_string());
+
+        (self.edited_action)(message, topic_name);
+        stdout().flush().unwrap();
+        Promise::ok(())
+    }
+}
@@ -1,9 +1,12 @@
 use std::net::SocketAddr;
+use std::time::Duration;
 
 use broker::{main_capnp::root_service, util::stream_to_rpc_network};
 use capnp_rpc::{rpc_twoparty_capnp, RpcSystem};
 use tokio::net::TcpStream;
 
+use crate::requests;
+
 pub type RpcSystemHandle = tokio::task::JoinHandle<Result<(), capnp::Error>>;
 
 pub async fn connect_to_server(addr: SocketAddr) -> Result<(RpcSystemHandle, root_service::Client), std::io::Error> {
@@ -18,4 +21,42 @@ pub async fn connect_to_server(addr: SocketAddr) -> Result<(RpcSystemHandle, roo
     let rpc_handle = tokio::task::spawn_local(rpc_system);
 
     Ok((rpc_handle, root))
+}
+
+/// Pings the server every `interval`. Resolves with an error once a ping fails or is not answered within `interval`.
+/// 
+/// Pinging also keeps the connection from hitting the idle timeout of the server.
+pub async fn watch_connection(root: &root_service::Client, interval: Duration) -> capnp::Error {
+    loop {
+        tokio::time::sleep(interval).await;
+
+        match tokio::time::timeout(interval, requests::ping(root)).await {
+            Ok(Ok(())) => continue,
+            Ok(Err(e)) => return e,
+            Err(_) => return capnp::Error::disconnected(format!("Server did not answer a ping within {interval:?}")),
+        }
+    }
+}
+
+/// Exponentially growing delays between reconnection attempts.
+pub struct Backoff {
+    initial: Duration,
+    next: Duration,
+    max: Duration,
+}
+
+impl Backoff {
+    pub fn new(initial: Duration, max: Duration) -> Self {
+        Self { initial, next: initial, max }
+    }
+
+    pub fn next_delay(&mut self) -> Duration {
+        let delay = self.next;
+        self.next = (self.next * 2).min(self.max);
+        delay
+    }
+
+    pub fn reset(&mut self) {
+        self.next = self.initial;
+    }
 }
\ No newline at end of file
@@ -0,0 +1,29 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::io::{stdout, Write};
+use std::rc::Rc;
+
+use broker::message_capnp::notification_watcher::{self, NotifiedParams};
+use capnp::capability::Promise;
+use capnp_rpc::pry;
+use uuid::Uuid;
+
+use crate::readers::read_capnp_notification;
+
+
+/// Announces mentions in topics the client is not subscribed to. Messages of subscribed topics are highlighted instead.
+pub struct NotificationWatcher {
+    pub topic_names: Rc<RefCell<HashMap<Uuid, String>>>,
+}
+
+impl notification_watcher::Server for NotificationWatcher {
+    fn notified(&mut self, params: NotifiedParams) -> Promise<(), capnp::Error> {
+        let notification = pry!(read_capnp_notification(pry!(pry!(params.get()).get_notification())));
+
+        if !self.topic_names.borrow().contains_key(&notification.topic_uuid) {
+            println!("\r{} mentioned you in {}: {}", notification.author_name, notification.topic_name, notification.excerpt);
+            stdout().flush().unwrap();
+        }
+        Promise::ok(())
+    }
+}
@@ -0,0 +1,34 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::io::{stdout, Write};
+use std::rc::Rc;
+
+use broker::auth_capnp::presence_watcher::{self, ChangedParams};
+use capnp::capability::Promise;
+use capnp_rpc::pry;
+
+use crate::{datatypes::{PresenceStatus, UserPresence}, readers::read_capnp_presence};
+
+
+/// Keeps presence of all users up to date. Users coming online and going offline are announced.
+pub struct PresenceWatcher {
+    pub users: Rc<RefCell<HashMap<String, UserPresence>>>,
+    /// Own presence is not announced
+    pub username: String,
+}
+
+impl presence_watcher::Server for PresenceWatcher {
+    fn changed(&mut self, params: ChangedParams) -> Promise<(), capnp::Error> {
+        let presence = pry!(read_capnp_presence(pry!(pry!(params.get()).get_presence())));
+
+        let previous = self.users.borrow_mut().insert(presence.username.clone(), presence.clone());
+        let was_offline = previous.is_none_or(|previous| previous.status == PresenceStatus::Offline);
+        let is_offline = presence.status == PresenceStatus::Offline;
+        if presence.username != self.username && was_offline != is_offline {
+            let status = if is_offline { "offline" } else { "online" };
+            println!("\r{} is {status}", presence.username);
+            stdout().flush().unwrap();
+        }
+        Promise::ok(())
+    }
+}
@@ -1,8 +1,8 @@
-use broker::{message_capnp::{self}, topic_capnp::{self, topic, topic_service}, util_capnp};
+use broker::{auth_capnp, message_capnp::{self}, topic_capnp::{self, topic, topic_service}, util_capnp};
 use chrono::{DateTime, Duration, Utc};
 use uuid::Uuid;
 
-use crate::datatypes::{Message, Retention, Topic};
+use crate::datatypes::{Message, Notification, PresenceStatus, Retention, Topic, TopicEvent, TopicStats, UserPresence};
 
 pub fn read_capnp_uuid(reader: util_capnp::uuid::Reader<'_>) -> Uuid {
     Uuid::from_u64_pair(
@@ -20,6 +20,20 @@ pub fn read_capnp_message(reader: message_capnp::message::Reader<'_>) -> Result<
         content: reader.reborrow().get_content()?.to_string()?,
         timestamp: read_capnp_timestamp(reader.reborrow().get_timestamp()?),
         key: None,
+        payload: reader.reborrow().get_payload()?.to_vec(),
+        content_type: reader.reborrow().get_content_type()?.to_string()?,
+        headers: reader.reborrow().get_headers()?.iter()
+            .map(|header| Ok((header.get_key()?.to_string()?, header.get_value()?.to_string()?)))
+            .collect::<Result<_, capnp::Error>>()?,
+        partition: reader.reborrow().get_partition(),
+        edited_at: {
+            let edited_at = reader.reborrow().get_edited_at()?;
+            if edited_at.has_t() { Some(read_capnp_timestamp(edited_at.get_t()?)) } else { None }
+        },
+        reply_to: {
+            let reply_to = reader.reborrow().get_reply_to()?;
+            if reply_to.has_t() { Some(read_capnp_uuid(reply_to.get_t()?)) } else { None }
+        },
     })
 }
 
@@ -34,6 +48,69 @@ pub fn read_capnp_topic(reader: topic::Reader<'_>) -> Result<Topic, capnp::Error
         creator: reader.get_owner_username()?.to_string()?,
         timestamp: read_capnp_timestamp(reader.get_created_at()?),
         retention: read_capnp_retention(reader.get_retention()?)?,
+        partitions: reader.get_partitions(),
+        description: reader.get_description()?.to_string()?,
+        tags: reader.get_tags()?.iter()
+            .map(|tag| Ok(tag?.to_string()?))
+            .collect::<Result<_, capnp::Error>>()?,
+        pinned_messages: reader.get_pinned_messages()?.iter()
+            .map(read_capnp_uuid)
+            .collect(),
+        participants: reader.get_participants()?.iter()
+            .map(|participant| Ok(participant?.to_string()?))
+            .collect::<Result<_, capnp::Error>>()?,
+    })
+}
+
+pub fn read_capnp_notification(reader: message_capnp::notification::Reader<'_>) -> Result<Notification, capnp::Error> {
+    Ok(Notification {
+        message_uuid: read_capnp_uuid(reader.get_message_id()?),
+        topic_uuid: read_capnp_uuid(reader.get_topic_id()?),
+        topic_name: reader.get_topic_name()?.to_string()?,
+        author_name: reader.get_author_name()?.to_string()?,
+        excerpt: reader.get_excerpt()?.to_string()?,
+        timestamp: read_capnp_timestamp(reader.get_timestamp()?),
+        read: reader.get_read(),
+    })
+}
+
+pub fn read_capnp_presence(reader: auth_capnp::user_presence::Reader<'_>) -> Result<UserPresence, capnp::Error> {
+    Ok(UserPresence {
+        username: reader.get_username()?.to_string()?,
+        status: match reader.get_status()? {
+            auth_capnp::PresenceStatus::Online => PresenceStatus::Online,
+            auth_capnp::PresenceStatus::Idle => PresenceStatus::Idle,
+            auth_capnp::PresenceStatus::Offline => PresenceStatus::Offline,
+        },
+        last_seen: read_capnp_timestamp(reader.get_last_seen()?),
+    })
+}
+
+pub fn read_capnp_topic_stats(reader: topic_capnp::topic_stats::Reader<'_>) -> Result<TopicStats, capnp::Error> {
+    let read_optional = |reader: util_capnp::option::Reader<'_, util_capnp::timestamp::Owned>| -> Result<_, capnp::Error> {
+        Ok(if reader.has_t() { Some(read_capnp_timestamp(reader.get_t()?)) } else { None })
+    };
+
+    Ok(TopicStats {
+        messages: reader.get_messages(),
+        bytes: reader.get_bytes(),
+        oldest_message: read_optional(reader.get_oldest_message()?)?,
+        newest_message: read_optional(reader.get_newest_message()?)?,
+        last_activity: read_capnp_timestamp(reader.get_last_activity()?),
+        subscribers: reader.get_subscribers(),
+        posts_last_minute: reader.get_posts_last_minute(),
+    })
+}
+
+pub fn read_capnp_topic_event(reader: topic_capnp::topic_event::Reader<'_>) -> Result<TopicEvent, capnp::Error> {
+    let topic = read_capnp_topic(reader.get_topic()?)?;
+
+    Ok(match reader.which()? {
+        topic_capnp::topic_event::Which::Created(()) => TopicEvent::Created(topic),
+        topic_capnp::topic_event::Which::Renamed(old_name) => TopicEvent::Renamed { topic, old_name: old_name?.to_string()? },
+        topic_capnp::topic_event::Which::RetentionChanged(()) => TopicEvent::RetentionChanged(topic),
+        topic_capnp::topic_event::Which::Deleted(()) => TopicEvent::Deleted(topic),
+        topic_capnp::topic_event::Which::MetadataChanged(()) => TopicEvent::MetadataChanged(topic),
     })
 }
 
@@ -48,6 +125,17 @@ pub fn read_capnp_topic_error(topic_reader: topic_service::error::Reader<'_>) ->
     let err_message = match topic_reader.which()? {
         topic_service::error::Which::NotFound(()) => "Topic does not exist",
         topic_service::error::Which::AlreadyExists(()) => "Topic already exists",
+        topic_service::error::Which::ReadOnly(()) => "Server is in read-only mode",
+        topic_service::error::Which::TooManyPartitions(max) => {
+            return Err(capnp::Error::failed(format!("Topics may have at most {max} partitions")));
+        }
+        topic_service::error::Which::InvalidMetadata(reason) => {
+            return Err(capnp::Error::failed(format!("Invalid metadata: {}", reason?.to_str()?)));
+        }
+        topic_service::error::Which::MessageNotFound(()) => "Message does not exist in the topic",
+        topic_service::error::Which::InvalidCursor(reason) => {
+            return Err(capnp::Error::failed(format!("Invalid cursor: {}", reason?.to_str()?)));
+        }
     };
     Err(capnp::Error::failed(err_message.to_owned()))
 }
\ No newline at end of file
@@ -1,8 +1,10 @@
-use broker::{auth_capnp::auth_service, main_capnp::root_service, message_capnp::{message_receiver, message_service, reverse_message_iterator}, topic_capnp::topic_service, util_capnp};
+use broker::{auth_capnp::{auth_service, presence_watcher}, main_capnp::{root_service, session_listener}, message_capnp::{message_filter, message_receiver, message_service, notification_watcher, pattern_receiver, reverse_message_iterator}, topic_capnp::{topic_service, topic_watcher, TopicSort}, util_capnp};
+use std::collections::BTreeMap;
+
 use capnp::Error;
 use uuid::Uuid;
 
-use crate::{datatypes::{Message, Topic}, message_receiver_impl::MessageReceiver, readers::{read_capnp_message, read_capnp_topic, read_capnp_topic_error}};
+use crate::{datatypes::{ContentFilter, KeyFilter, Message, MessageFilter, Notification, Topic, TopicStats, UserPresence}, message_receiver_impl::{MessageReceiver, PatternReceiver}, notification_watcher_impl::NotificationWatcher, presence_watcher_impl::PresenceWatcher, seen_messages::LastSeen, session_listener_impl::SessionListener, topic_watcher_impl::TopicWatcher, readers::{read_capnp_message, read_capnp_notification, read_capnp_presence, read_capnp_topic, read_capnp_topic_error, read_capnp_topic_stats}};
 
 
 
@@ -17,48 +19,367 @@ pub async fn autorize(root: &root_service::Client, username: &str) -> Result<(),
     Ok(())
 }
 
-pub async fn post_message(message_service: &message_service::Client, content: &str, topic_uuid: Uuid, key: Option<&str>) -> Result<Message, capnp::Error> {
-    let mut request = message_service.post_message_request();
+pub async fn ping(root: &root_service::Client) -> Result<(), capnp::Error> {
+    let _ = root.ping_request().send().promise.await?;
+    Ok(())
+}
+
+pub async fn set_session_listener(root: &root_service::Client, listener: SessionListener) -> Result<(), capnp::Error> {
+    let listener_client: session_listener::Client = capnp_rpc::new_client(listener);
+
+    let mut request = root.set_listener_request();
+    request.get().set_listener(listener_client);
+
+    let _ = request.send().promise.await?;
+
+    Ok(())
+}
+
+/// Server pushes changes of all topics to the watcher until the connection is closed.
+pub async fn watch_topics(topic_service: &topic_service::Client, watcher: TopicWatcher) -> Result<(), capnp::Error> {
+    let watcher_client: topic_watcher::Client = capnp_rpc::new_client(watcher);
+
+    let mut request = topic_service.watch_topics_request();
+    request.get().set_watcher(watcher_client);
+    request.send().promise.await?;
+
+    Ok(())
+}
+
+/// Server pushes presence changes of all users to the watcher until the connection is closed.
+/// Returns presence of every known user at the moment the watcher was set.
+pub async fn watch_presence(root: &root_service::Client, watcher: PresenceWatcher) -> Result<Vec<UserPresence>, capnp::Error> {
+    let auth_service = get_auth_client(root).await?;
+    let watcher_client: presence_watcher::Client = capnp_rpc::new_client(watcher);
 
-    let mut builder = request.get();
-    builder.set_content(content);
+    let mut request = auth_service.watch_presence_request();
+    request.get().set_watcher(watcher_client);
+    let response = request.send().promise.await?;
+
+    response.get()?.get_users()?.iter()
+        .map(read_capnp_presence)
+        .collect()
+}
 
-    let mut capnp_uuid = builder.reborrow().init_topic_id();
-    let (upper, lower) = topic_uuid.as_u64_pair();
-    capnp_uuid.set_upper(upper);
-    capnp_uuid.set_lower(lower);
+/// Message to be posted.
+/// Text messages have an empty `payload`, binary messages may have an empty `content`.
+pub struct NewMessage<'a> {
+    pub content: &'a str,
+    pub payload: &'a [u8],
+    pub content_type: &'a str,
+    pub key: Option<&'a str>,
+    pub headers: &'a BTreeMap<String, String>,
+    pub reply_to: Option<Uuid>,
+}
 
-    if let Some(key) = key {
-        builder.init_key().set_t(key)?;
+/// Waits out rate limits of the server, so piped input slows down instead of being dropped.
+pub async fn post_message(message_service: &message_service::Client, topic_uuid: Uuid, message: &NewMessage<'_>) -> Result<Message, capnp::Error> {
+    loop {
+        let mut request = message_service.post_message_request();
+
+        let mut builder = request.get();
+        builder.set_content(message.content);
+        builder.set_payload(message.payload);
+        builder.set_content_type(message.content_type);
+
+        let mut capnp_uuid = builder.reborrow().init_topic_id();
+        let (upper, lower) = topic_uuid.as_u64_pair();
+        capnp_uuid.set_upper(upper);
+        capnp_uuid.set_lower(lower);
+
+        if let Some(key) = message.key {
+            builder.reborrow().init_key().set_t(key)?;
+        }
+        if let Some(reply_to) = message.reply_to {
+            let mut capnp_uuid = builder.reborrow().init_reply_to().init_t();
+            let (upper, lower) = reply_to.as_u64_pair();
+            capnp_uuid.set_upper(upper);
+            capnp_uuid.set_lower(lower);
+        }
+
+        let mut capnp_headers = builder.init_headers(message.headers.len() as u32);
+        for (i, (key, value)) in message.headers.iter().enumerate() {
+            let mut header = capnp_headers.reborrow().get(i as u32);
+            header.set_key(key);
+            header.set_value(value);
+        }
+
+        let response = request.send().promise.await?;
+        let response = response.get()?.get_message()?;
+        
+        return match response.which()? {
+            util_capnp::result::Which::Err(err) => {
+                let err = err?;
+                let err_message = match err.which()? {
+                    message_service::error::Which::EntityDoesNotExist(()) => "Topic does not exist",
+                    message_service::error::Which::InvalidContent(reason) => {
+                        return Err(capnp::Error::failed(format!("Invalid content: {}", reason?.to_str()?)));
+                    }
+                    message_service::error::Which::ReadOnly(()) => "Server is in read-only mode",
+                    message_service::error::Which::RateLimited(retry_after) => {
+                        wait_rate_limit(retry_after).await;
+                        continue;
+                    }
+                    message_service::error::Which::InvalidFilter(_) => "Invalid filter (unreachable)",
+                    message_service::error::Which::NotAuthor(()) => "Not the author (unreachable)",
+                    message_service::error::Which::ReplyTargetNotFound(()) => "Replied message does not exist in this topic",
+                    message_service::error::Which::InvalidRecipients(_) => "Invalid recipients (unreachable)",
+                };
+                Err(capnp::Error::failed(err_message.to_owned()))
+            },
+            util_capnp::result::Which::Ok(ok) => {
+                let ok = ok?;
+                Ok(read_capnp_message(ok)?)
+            },
+        };
     }
+}
+
+/// Looks the topic up without listing all of them. The exact name sorts first among the names it is a prefix of.
+pub async fn find_topic(topic_service: &topic_service::Client, name: &str) -> Result<Option<Topic>, capnp::Error> {
+    let mut request = topic_service.list_topics_request();
+    let mut query = request.get().init_query();
+    query.set_name_prefix(name);
+    query.set_sort(TopicSort::Name);
+    query.set_page_size(1);
 
     let response = request.send().promise.await?;
-    let response = response.get()?.get_message()?;
-    
-    match response.which()? {
+    match response.get()?.get_page()?.which()? {
+        util_capnp::result::Which::Ok(page) => {
+            let topic = page?.get_topics()?.iter().next().map(read_capnp_topic).transpose()?;
+            Ok(topic.filter(|topic| topic.name == name))
+        }
         util_capnp::result::Which::Err(err) => {
-            let err = err?;
-            let err_message = match err.which()? {
-                message_service::error::Which::EntityDoesNotExist(()) => "Topic does not exist",
-                message_service::error::Which::InvalidContent(()) => "Invalid content",
-            };
-            Err(capnp::Error::failed(err_message.to_owned()))
-        },
-        util_capnp::result::Which::Ok(ok) => {
-            let ok = ok?;
-            Ok(read_capnp_message(ok)?)
-        },
+            read_capnp_topic_error(err?)?;
+            unreachable!();
+        }
+    }
+}
+
+/// Replaces the content of an own message, waiting out rate limits.
+pub async fn edit_message(message_service: &message_service::Client, message_uuid: Uuid, content: &str) -> Result<Message, capnp::Error> {
+    loop {
+        let mut request = message_service.edit_message_request();
+        request.get().set_content(content);
+        let mut capnp_uuid = request.get().init_message_id();
+        let (upper, lower) = message_uuid.as_u64_pair();
+        capnp_uuid.set_upper(upper);
+        capnp_uuid.set_lower(lower);
+
+        let response = request.send().promise.await?;
+        return match response.get()?.get_message()?.which()? {
+            util_capnp::result::Which::Ok(message) => read_capnp_message(message?),
+            util_capnp::result::Which::Err(err) => {
+                let err_message = match err?.which()? {
+                    message_service::error::Which::EntityDoesNotExist(()) => "Message does not exist",
+                    message_service::error::Which::InvalidContent(reason) => {
+                        return Err(capnp::Error::failed(format!("Invalid content: {}", reason?.to_str()?)));
+                    }
+                    message_service::error::Which::ReadOnly(()) => "Server is in read-only mode",
+                    message_service::error::Which::RateLimited(retry_after) => {
+                        wait_rate_limit(retry_after).await;
+                        continue;
+                    }
+                    message_service::error::Which::NotAuthor(()) => "Only the author may edit a message",
+                    message_service::error::Which::InvalidFilter(_) => "Invalid filter (unreachable)",
+                    message_service::error::Which::ReplyTargetNotFound(()) => "Reply target not found (unreachable)",
+                    message_service::error::Which::InvalidRecipients(_) => "Invalid recipients (unreachable)",
+                };
+                Err(capnp::Error::failed(err_message.to_owned()))
+            }
+        };
+    }
+}
+
+/// Posts to the direct conversation with the recipients, which the server starts if needed.
+pub async fn send_direct(message_service: &message_service::Client, recipients: &[&str], content: &str) -> Result<Message, capnp::Error> {
+    loop {
+        let mut request = message_service.send_direct_request();
+        request.get().set_content(content);
+        let mut capnp_recipients = request.get().init_recipients(recipients.len() as u32);
+        for (i, recipient) in recipients.iter().enumerate() {
+            capnp_recipients.set(i as u32, *recipient);
+        }
+
+        let response = request.send().promise.await?;
+        return match response.get()?.get_message()?.which()? {
+            util_capnp::result::Which::Ok(message) => read_capnp_message(message?),
+            util_capnp::result::Which::Err(err) => {
+                let err_message = match err?.which()? {
+                    message_service::error::Which::InvalidRecipients(reason) => {
+                        return Err(capnp::Error::failed(format!("Invalid recipients: {}", reason?.to_str()?)));
+                    }
+                    message_service::error::Which::InvalidContent(reason) => {
+                        return Err(capnp::Error::failed(format!("Invalid content: {}", reason?.to_str()?)));
+                    }
+                    message_service::error::Which::ReadOnly(()) => "Server is in read-only mode",
+                    message_service::error::Which::RateLimited(retry_after) => {
+                        wait_rate_limit(retry_after).await;
+                        continue;
+                    }
+                    _ => "Unexpected error",
+                };
+                Err(capnp::Error::failed(err_message.to_owned()))
+            }
+        };
     }
-} 
+}
 
-pub async fn get_all_topics(topic: &topic_service::Client) -> Result<Vec<Topic>, capnp::Error> {
-    topic.get_all_topics_request()
-        .send().promise.await?
-        .get()?
-        .get_topics()?
-        .iter()
+/// Direct conversations of the user, most recently active first.
+pub async fn list_conversations(topic_service: &topic_service::Client) -> Result<Vec<Topic>, capnp::Error> {
+    let response = topic_service.list_conversations_request().send().promise.await?;
+    response.get()?.get_topics()?.iter()
         .map(read_capnp_topic)
-        .collect::<Result<Vec<_>, _>>()
+        .collect()
+}
+
+/// All messages of the topic, in the order they were posted.
+pub async fn get_messages(message_service: &message_service::Client, topic_uuid: Uuid) -> Result<Vec<Message>, capnp::Error> {
+    loop {
+        let mut request = message_service.get_messages_sync_request();
+        let mut capnp_uuid = request.get().init_topic_id();
+        let (upper, lower) = topic_uuid.as_u64_pair();
+        capnp_uuid.set_upper(upper);
+        capnp_uuid.set_lower(lower);
+
+        let response = request.send().promise.await?;
+        return match response.get()?.get_messages()?.which()? {
+            util_capnp::result::Which::Ok(messages) => {
+                let mut messages = messages?.iter()
+                    .map(read_capnp_message)
+                    .collect::<Result<Vec<_>, _>>()?;
+                messages.sort_by_key(|message| message.timestamp);
+                Ok(messages)
+            }
+            util_capnp::result::Which::Err(err) => {
+                let err_message = match err?.which()? {
+                    message_service::error::Which::RateLimited(retry_after) => {
+                        wait_rate_limit(retry_after).await;
+                        continue;
+                    }
+                    _ => "Topic does not exist",
+                };
+                Err(capnp::Error::failed(err_message.to_owned()))
+            }
+        };
+    }
+}
+
+/// The root message followed by all the replies to it, in the order they were posted.
+pub async fn get_thread(message_service: &message_service::Client, root_uuid: Uuid) -> Result<Vec<Message>, capnp::Error> {
+    loop {
+        let mut request = message_service.get_thread_request();
+        let mut capnp_uuid = request.get().init_root_message_id();
+        let (upper, lower) = root_uuid.as_u64_pair();
+        capnp_uuid.set_upper(upper);
+        capnp_uuid.set_lower(lower);
+
+        let response = request.send().promise.await?;
+        return match response.get()?.get_messages()?.which()? {
+            util_capnp::result::Which::Ok(messages) => messages?.iter()
+                .map(read_capnp_message)
+                .collect(),
+            util_capnp::result::Which::Err(err) => {
+                let err_message = match err?.which()? {
+                    message_service::error::Which::RateLimited(retry_after) => {
+                        wait_rate_limit(retry_after).await;
+                        continue;
+                    }
+                    _ => "Message does not exist",
+                };
+                Err(capnp::Error::failed(err_message.to_owned()))
+            }
+        };
+    }
+}
+
+pub async fn get_topic_stats(topic_service: &topic_service::Client, topic_uuid: Uuid) -> Result<TopicStats, capnp::Error> {
+    let mut request = topic_service.get_topic_stats_request();
+    let mut capnp_topic_id = request.get().init_topic_id();
+    capnp_topic_id.set_upper(topic_uuid.as_u64_pair().0);
+    capnp_topic_id.set_lower(topic_uuid.as_u64_pair().1);
+
+    let response = request.send().promise.await?;
+    match response.get()?.get_stats()?.which()? {
+        util_capnp::result::Which::Ok(stats) => read_capnp_topic_stats(stats?),
+        util_capnp::result::Which::Err(err) => {
+            read_capnp_topic_error(err?)?;
+            unreachable!();
+        }
+    }
+}
+
+/// Users subscribed to the topic, sorted by name.
+pub async fn get_subscribers(topic_service: &topic_service::Client, topic_uuid: Uuid) -> Result<Vec<UserPresence>, capnp::Error> {
+    let mut request = topic_service.get_subscribers_request();
+    let mut capnp_topic_id = request.get().init_topic_id();
+    capnp_topic_id.set_upper(topic_uuid.as_u64_pair().0);
+    capnp_topic_id.set_lower(topic_uuid.as_u64_pair().1);
+
+    let response = request.send().promise.await?;
+    match response.get()?.get_subscribers()?.which()? {
+        util_capnp::result::Which::Ok(subscribers) => subscribers?.iter()
+            .map(read_capnp_presence)
+            .collect(),
+        util_capnp::result::Which::Err(err) => {
+            read_capnp_topic_error(err?)?;
+            unreachable!();
+        }
+    }
+}
+
+/// Tells the other subscribers of the topic that the user is typing. Nothing is stored.
+pub async fn send_typing(message_service: &message_service::Client, topic_uuid: Uuid) -> Result<(), capnp::Error> {
+    let mut request = message_service.send_typing_request();
+    let mut capnp_topic_id = request.get().init_topic_id();
+    capnp_topic_id.set_upper(topic_uuid.as_u64_pair().0);
+    capnp_topic_id.set_lower(topic_uuid.as_u64_pair().1);
+
+    let response = request.send().promise.await?;
+    match response.get()?.get_result()?.which()? {
+        util_capnp::result::Which::Ok(_) => Ok(()),
+        util_capnp::result::Which::Err(err) => match err?.which()? {
+            message_service::error::Which::EntityDoesNotExist(()) => Err(Error::failed("Topic does not exist".to_owned())),
+            _ => Err(Error::failed("Unexpected error (unreachable)".to_owned())),
+        },
+    }
+}
+
+/// Server pushes new mentions of the user to the watcher until the connection is closed.
+pub async fn watch_notifications(message_service: &message_service::Client, watcher: NotificationWatcher) -> Result<(), capnp::Error> {
+    let watcher_client: notification_watcher::Client = capnp_rpc::new_client(watcher);
+
+    let mut request = message_service.watch_notifications_request();
+    request.get().set_watcher(watcher_client);
+    request.send().promise.await?;
+
+    Ok(())
+}
+
+/// Mentions of the user, newest first. A `limit` of 0 gets all of them.
+pub async fn get_notifications(message_service: &message_service::Client, unread_only: bool, limit: u32) -> Result<Vec<Notification>, capnp::Error> {
+    let mut request = message_service.get_notifications_request();
+    request.get().set_unread_only(unread_only);
+    request.get().set_limit(limit);
+
+    let response = request.send().promise.await?;
+    response.get()?.get_notifications()?.iter()
+        .map(read_capnp_notification)
+        .collect()
+}
+
+/// Marks notifications of the messages as read, or all of them for an empty slice. Returns how many were unread.
+pub async fn mark_read(message_service: &message_service::Client, message_uuids: &[Uuid]) -> Result<u32, capnp::Error> {
+    let mut request = message_service.mark_read_request();
+    let mut capnp_uuids = request.get().init_message_ids(message_uuids.len() as u32);
+    for (index, uuid) in message_uuids.iter().enumerate() {
+        let mut capnp_uuid = capnp_uuids.reborrow().get(index as u32);
+        capnp_uuid.set_upper(uuid.as_u64_pair().0);
+        capnp_uuid.set_lower(uuid.as_u64_pair().1);
+    }
+
+    let response = request.send().promise.await?;
+    Ok(response.get()?.get_marked())
 }
 
 pub async fn get_auth_client(root: &root_service::Client) -> Result<auth_service::Client, capnp::Error> {
@@ -101,6 +422,11 @@ pub async fn create_topic(topic_service: &topic_service::Client, name: &str) ->
             let name = match err?.which()? {
                 topic_service::error::Which::NotFound(()) => "Topic does not exist.",
                 topic_service::error::Which::AlreadyExists(()) => "Topic already exists (unreachable).",
+                topic_service::error::Which::ReadOnly(()) => "Server is in read-only mode.",
+                topic_service::error::Which::TooManyPartitions(_) => "Too many partitions (unreachable).",
+                topic_service::error::Which::InvalidMetadata(_) => "Invalid metadata (unreachable).",
+                topic_service::error::Which::MessageNotFound(()) => "Message does not exist (unreachable).",
+                topic_service::error::Which::InvalidCursor(_) => "Invalid cursor (unreachable).",
             };
             Err(Error::failed(name.to_string()))
         },
@@ -108,62 +434,173 @@ pub async fn create_topic(topic_service: &topic_service::Client, name: &str) ->
 }
 
 pub async fn get_messages_reverse(rev_message_iterator: &reverse_message_iterator::Client, count: u32) -> Result<Vec<Message>, capnp::Error> {
-    let mut results = Vec::with_capacity(count as usize);
-
-    let mut request = rev_message_iterator.next_request();
-    request.get().set_count(count);
-
-    let response = request.send().promise.await?;
-    for element in response.get()?.get_messages()? {
-        results.push(read_capnp_message(element)?);
+    loop {
+        let mut request = rev_message_iterator.next_request();
+        request.get().set_count(count);
+
+        let response = request.send().promise.await?;
+
+        return match response.get()?.get_messages()?.which()? {
+            util_capnp::result::Which::Ok(messages) => messages?.iter()
+                .map(read_capnp_message)
+                .collect(),
+            util_capnp::result::Which::Err(error) => match error?.which()? {
+                message_service::error::Which::RateLimited(retry_after) => {
+                    wait_rate_limit(retry_after).await;
+                    continue;
+                }
+                _ => Err(Error::failed("Unexpected error (unreachable)".to_owned())),
+            },
+        };
     }
-
-    Ok(results)
 }
 
-pub async fn subscribe_to_messages(message_service: &message_service::Client, receiver: MessageReceiver, topic_uuid: Uuid) -> Result<reverse_message_iterator::Client, capnp::Error> {
+pub async fn subscribe_to_messages(message_service: &message_service::Client, receiver: MessageReceiver, topic_uuid: Uuid, filter: &MessageFilter) -> Result<reverse_message_iterator::Client, capnp::Error> {
     let receiver_client: message_receiver::Client = capnp_rpc::new_client(receiver);
 
-    let mut subscribe_request = message_service.subscribe_request();
+    loop {
+        let mut subscribe_request = message_service.subscribe_request();
+
+        let mut builder = subscribe_request.get();
+        builder.set_receiver(receiver_client.clone());
+        if !filter.is_empty() {
+            fill_capnp_filter(builder.reborrow().init_filter().init_t(), filter);
+        }
+
+        let mut uuid_builder = builder.init_topic_id();
+        let (upper, lower) = topic_uuid.as_u64_pair();
+        uuid_builder.set_upper(upper);
+        uuid_builder.set_lower(lower);
+
+        let response = subscribe_request.send().promise.await?;
+
+        return match response.get()?.get_messages()?.which()? {
+            broker::util_capnp::result::Which::Ok(messages_iterator) => {
+                let messages_iterator = messages_iterator?;
+                Ok(messages_iterator)
+            },
+
+            broker::util_capnp::result::Which::Err(error) => {
+                let error = error?;
+                let err_message = match error.which()? {
+                    message_service::error::Which::EntityDoesNotExist(()) => "Topic does not exist",
+                    message_service::error::Which::InvalidContent(_) => "Invalid content (unreachable)",
+                    message_service::error::Which::ReadOnly(()) => "Server is in read-only mode (unreachable)",
+                    message_service::error::Which::NotAuthor(()) => "Not the author (unreachable)",
+                    message_service::error::Which::ReplyTargetNotFound(()) => "Reply target not found (unreachable)",
+                    message_service::error::Which::InvalidRecipients(_) => "Invalid recipients (unreachable)",
+                    message_service::error::Which::RateLimited(retry_after) => {
+                        wait_rate_limit(retry_after).await;
+                        continue;
+                    }
+                    message_service::error::Which::InvalidFilter(reason) => {
+                        return Err(Error::failed(format!("Invalid filter: {}", reason?.to_str()?)));
+                    }
+                };
+                Err(Error::failed(err_message.to_owned()))
+            },
+        };
+    }
+}
+
+fn fill_capnp_filter(mut builder: message_filter::Builder<'_>, filter: &MessageFilter) {
+    builder.set_author_name(filter.author_name.as_deref().unwrap_or(""));
 
-    let mut builder = subscribe_request.get();
-    builder.set_receiver(receiver_client);
+    let mut key = builder.reborrow().init_key();
+    match &filter.key {
+        KeyFilter::Any => key.set_any(()),
+        KeyFilter::Equals(expected) => key.set_equals(expected),
+        KeyFilter::Prefix(prefix) => key.set_prefix(prefix),
+    }
 
-    let mut uuid_builder = builder.init_topic_id();
-    let (upper, lower) = topic_uuid.as_u64_pair();
-    uuid_builder.set_upper(upper);
-    uuid_builder.set_lower(lower);
+    let mut headers = builder.reborrow().init_headers(filter.headers.len() as u32);
+    for (i, (key, value)) in filter.headers.iter().enumerate() {
+        let mut header = headers.reborrow().get(i as u32);
+        header.set_key(key);
+        header.set_value(value);
+    }
 
-    let response = subscribe_request.send().promise.await?;
+    let mut partitions = builder.reborrow().init_partitions(filter.partitions.len() as u32);
+    for (i, partition) in filter.partitions.iter().enumerate() {
+        partitions.set(i as u32, *partition);
+    }
 
-    match response.get()?.get_messages()?.which()? {
-        broker::util_capnp::result::Which::Ok(messages_iterator) => {
-            let messages_iterator = messages_iterator?;
-            Ok(messages_iterator)
-        },
+    let mut content = builder.init_content();
+    match &filter.content {
+        ContentFilter::Any => content.set_any(()),
+        ContentFilter::Contains(text) => content.set_contains(text),
+        ContentFilter::Regex(pattern) => content.set_regex(pattern),
+    }
+}
 
-        broker::util_capnp::result::Which::Err(error) => {
-            let error = error?;
-            let err_message = match error.which()? {
-                message_service::error::Which::EntityDoesNotExist(()) => "Topic does not exist",
-                message_service::error::Which::InvalidContent(()) => "Invalid content (unreachable)",
-            };
-            Err(Error::failed(err_message.to_owned()))
-        },
+/// Subscribes to new messages of all the topics matching the pattern, including the ones created later.
+pub async fn subscribe_pattern(message_service: &message_service::Client, receiver: PatternReceiver, pattern: &str, filter: &MessageFilter) -> Result<(), capnp::Error> {
+    let receiver_client: pattern_receiver::Client = capnp_rpc::new_client(receiver);
+
+    loop {
+        let mut request = message_service.subscribe_pattern_request();
+
+        let mut builder = request.get();
+        builder.set_pattern(pattern);
+        builder.set_receiver(receiver_client.clone());
+        if !filter.is_empty() {
+            fill_capnp_filter(builder.init_filter().init_t(), filter);
+        }
+
+        let response = request.send().promise.await?;
+
+        return match response.get()?.get_result()?.which()? {
+            util_capnp::result::Which::Ok(_) => Ok(()),
+            util_capnp::result::Which::Err(error) => match error?.which()? {
+                message_service::error::Which::RateLimited(retry_after) => {
+                    wait_rate_limit(retry_after).await;
+                    continue;
+                }
+                message_service::error::Which::InvalidFilter(reason) => {
+                    Err(Error::failed(format!("Invalid pattern or filter: {}", reason?.to_str()?)))
+                }
+                _ => Err(Error::failed("Unexpected error (unreachable)".to_owned())),
+            },
+        };
     }
 }
 
+async fn wait_rate_limit(retry_after_secs: f64) {
+    let delay = std::time::Duration::from_secs_f64(retry_after_secs.clamp(0.0, 60.0));
+    tokio::time::sleep(delay).await;
+}
+
+/// Subscribes to the topic and reads its history back to the `last_seen` message, newest first.
+/// Without `last_seen` only the `old_messages_limit` newest messages are read.
 pub async fn subscribe_and_get_messages(
     message_service: &message_service::Client, 
     topic: &Topic, 
-    new_messages_action: impl 'static + FnMut(Message), 
-    old_messages_limit: u32
+    live_receiver: MessageReceiver,
+    last_seen: Option<LastSeen>,
+    old_messages_limit: u32,
+    filter: &MessageFilter,
 ) -> Result<Vec<Message>, capnp::Error> {
-    let live_receiver = MessageReceiver::new(new_messages_action);
-    let old_messages_iter = subscribe_to_messages(&message_service, live_receiver, topic.uuid).await?;
-
-    let history = get_messages_reverse(&old_messages_iter, old_messages_limit).await?;
-    Ok(history)
+    let old_messages_iter = subscribe_to_messages(&message_service, live_receiver, topic.uuid, filter).await?;
+
+    let Some(last_seen) = last_seen else {
+        return get_messages_reverse(&old_messages_iter, old_messages_limit).await;
+    };
+
+    let mut history = vec![];
+    loop {
+        let batch = get_messages_reverse(&old_messages_iter, old_messages_limit).await?;
+        let is_exhausted = batch.len() < old_messages_limit as usize;
+
+        for message in batch {
+            if last_seen.is_reached_by(&message) {
+                return Ok(history);
+            }
+            history.push(message);
+        }
+        if is_exhausted {
+            return Ok(history);
+        }
+    }
 }
 
 pub async fn update_topic(topic_service: &topic_service::Client, updated: &Topic) -> Result<Topic, capnp::Error> {
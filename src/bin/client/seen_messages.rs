@@ -0,0 +1,55 @@
+use std::collections::{HashMap, HashSet};
+
+use chrono::{DateTime, Utc};
+use uuid::Uuid;
+
+use crate::datatypes::Message;
+
+/// The newest message seen in a topic. History is read back until this message is reached.
+#[derive(Clone, Copy, Debug)]
+pub struct LastSeen {
+    pub uuid: Uuid,
+    pub timestamp: DateTime<Utc>,
+}
+
+impl LastSeen {
+    pub fn is_reached_by(&self, message: &Message) -> bool {
+        message.uuid == self.uuid || message.timestamp < self.timestamp
+    }
+}
+
+/// Messages that were already shown to the user.
+/// Lets a reconnected client fill the gaps in history without printing anything twice.
+#[derive(Default)]
+pub struct SeenMessages {
+    last_seen: HashMap<Uuid, LastSeen>,
+    uuids: HashSet<Uuid>,
+}
+
+impl SeenMessages {
+    /// Remembers the message. Returns `false` if it was already seen.
+    pub fn insert(&mut self, message: &Message) -> bool {
+        if !self.uuids.insert(message.uuid) {
+            return false;
+        }
+
+        let candidate = LastSeen { uuid: message.uuid, timestamp: message.timestamp };
+        self.last_seen.entry(message.topic_uuid)
+            .and_modify(|last| if candidate.timestamp >= last.timestamp { *last = candidate })
+            .or_insert(candidate);
+        true
+    }
+
+    /// Seen messages with a UUID starting with `prefix`, like the short IDs printed along with messages.
+    pub fn find_by_prefix(&self, prefix: &str) -> Vec<Uuid> {
+        let prefix = prefix.to_lowercase();
+        self.uuids.iter()
+            .filter(|uuid| uuid.to_string().starts_with(&prefix))
+            .copied()
+            .collect()
+    }
+
+    pub fn last_seen(&self, topic_uuid: &Uuid) -> Option<LastSeen> {
+        self.last_seen.get(topic_uuid).copied()
+    }
+}
\ No newline at end of file
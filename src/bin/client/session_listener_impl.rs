@@ -0,0 +1,32 @@
+use std::cell::Cell;
+use std::io::{stdout, Write};
+use std::rc::Rc;
+
+use broker::main_capnp::session_listener::{self, KickedParams, KickedResults, ServerShutdownParams, ServerShutdownResults};
+use capnp::capability::Promise;
+use capnp_rpc::pry;
+
+
+pub struct SessionListener {
+    /// Set once an admin kicks this session, so the client does not reconnect.
+    pub kicked: Rc<Cell<bool>>,
+}
+
+impl session_listener::Server for SessionListener {
+    fn server_shutdown(&mut self, params: ServerShutdownParams, _: ServerShutdownResults) -> Promise<(), capnp::Error> {
+        let reason = pry!(pry!(pry!(params.get()).get_reason()).to_str());
+
+        println!("\rServer is going away: {reason}");
+        stdout().flush().unwrap();
+        Promise::ok(())
+    }
+
+    fn kicked(&mut self, params: KickedParams, _: KickedResults) -> Promise<(), capnp::Error> {
+        let reason = pry!(pry!(pry!(params.get()).get_reason()).to_str());
+
+        println!("\rKicked from the server: {reason}");
+        stdout().flush().unwrap();
+        self.kicked.set(true);
+        Promise::ok(())
+    }
+}
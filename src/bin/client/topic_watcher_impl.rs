@@ -0,0 +1,22 @@
+use broker::topic_capnp::topic_watcher::{self, EventParams};
+use capnp::capability::Promise;
+use capnp_rpc::pry;
+use tokio::sync::mpsc::UnboundedSender;
+
+use crate::{datatypes::TopicEvent, readers::read_capnp_topic_event};
+
+
+/// Hands topic events over to the input loop, which owns the list of topics.
+pub struct TopicWatcher {
+    pub events: UnboundedSender<TopicEvent>,
+}
+
+impl topic_watcher::Server for TopicWatcher {
+    fn event(&mut self, params: EventParams) -> Promise<(), capnp::Error> {
+        let event = pry!(read_capnp_topic_event(pry!(pry!(params.get()).get_event())));
+
+        // Input loop is gone only when the client quits
+        let _ = self.events.send(event);
+        Promise::ok(())
+    }
+}
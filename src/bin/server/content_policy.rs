@@ -0,0 +1,336 @@
+use std::collections::{BTreeMap, HashMap};
+use std::str::FromStr;
+
+use clap::ValueEnum;
+use unicode_normalization::UnicodeNormalization;
+
+/// What a policy does with the content it does not allow.
+#[derive(ValueEnum, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Violation {
+    /// Remove the offending parts and accept the rest
+    #[default]
+    Strip,
+    /// Reject the whole message
+    Reject,
+}
+
+/// A single check or transformation of message content.
+/// Returns the content to pass on to the next policy, or the reason to reject the message.
+pub trait ContentPolicy: Send + Sync {
+    fn apply(&self, content: String) -> Result<String, String>;
+}
+
+/// Control characters other than tabs and spaces, e.g. newlines or a bell.
+pub struct ControlCharacters(pub Violation);
+
+impl ContentPolicy for ControlCharacters {
+    fn apply(&self, content: String) -> Result<String, String> {
+        let is_allowed = |c: char| c == '\t' || c == ' ' || !c.is_control();
+
+        match self.0 {
+            Violation::Strip => Ok(content.chars().filter(|&c| is_allowed(c)).collect()),
+            Violation::Reject => match content.chars().find(|&c| !is_allowed(c)) {
+                Some(c) => Err(format!("Contains control character {c:?}")),
+                None => Ok(content),
+            },
+        }
+    }
+}
+
+/// ANSI escape sequences, which recolor or move the cursor of the terminals showing the message.
+/// Has to run before [`ControlCharacters`] strips the escape character and leaves the rest of a sequence behind.
+pub struct AnsiEscapes(pub Violation);
+
+impl ContentPolicy for AnsiEscapes {
+    fn apply(&self, content: String) -> Result<String, String> {
+        if !content.contains(['\x1B', '\u{9B}']) {
+            return Ok(content);
+        }
+        if self.0 == Violation::Reject {
+            return Err("Contains ANSI escape sequences".to_string());
+        }
+
+        let mut result = String::with_capacity(content.len());
+        let mut chars = content.chars().peekable();
+        while let Some(c) = chars.next() {
+            let is_csi = match c {
+                '\u{9B}' => true,
+                '\x1B' if chars.peek() == Some(&'[') => {
+                    chars.next();
+                    true
+                }
+                // Two-character sequences, like `ESC c`
+                '\x1B' => {
+                    chars.next();
+                    false
+                }
+                _ => {
+                    result.push(c);
+                    false
+                }
+            };
+
+            // Parameters and intermediate bytes, up to and including the final byte
+            if is_csi {
+                for c in chars.by_ref() {
+                    if ('\x40'..='\x7E').contains(&c) {
+                        break;
+                    }
+                }
+            }
+        }
+        Ok(result)
+    }
+}
+
+/// Unicode NFC normalization, so equal looking texts are equal bytes.
+pub struct NormalizeUnicode;
+
+impl ContentPolicy for NormalizeUnicode {
+    fn apply(&self, content: String) -> Result<String, String> {
+        Ok(content.nfc().collect())
+    }
+}
+
+/// Words that may not appear in messages. Matched case-insensitively as whole words.
+pub struct BannedWords {
+    words: Vec<String>,
+}
+
+impl BannedWords {
+    pub fn new(words: impl IntoIterator<Item = String>) -> Self {
+        let words = words.into_iter()
+            .map(|word| word.trim().to_lowercase())
+            .filter(|word| !word.is_empty())
+            .collect();
+        Self { words }
+    }
+}
+
+impl ContentPolicy for BannedWords {
+    fn apply(&self, content: String) -> Result<String, String> {
+        let lowercase = content.to_lowercase();
+        let banned = lowercase
+            .split(|c: char| !c.is_alphanumeric())
+            .find(|word| self.words.iter().any(|banned| banned == word));
+
+        match banned {
+            Some(word) => Err(format!("Contains banned word '{word}'")),
+            None => Ok(content),
+        }
+    }
+}
+
+const MAX_HEADERS: usize = 32;
+const MAX_HEADER_KEY_LENGTH: usize = 128;
+const MAX_HEADER_VALUE_LENGTH: usize = 1024;
+
+/// Longest allowed content of a topic, parsed from `NAME:BYTES`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TopicMaxLength {
+    pub topic_name: String,
+    pub max_length: usize,
+}
+
+impl FromStr for TopicMaxLength {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (topic_name, max_length) = s.rsplit_once(':')
+            .ok_or_else(|| format!("Expected `TOPIC:BYTES`, got '{s}'"))?;
+        let max_length = max_length.parse::<usize>()
+            .map_err(|e| format!("Invalid length '{max_length}': {e}"))?;
+
+        Ok(Self { topic_name: topic_name.to_string(), max_length })
+    }
+}
+
+/// Everything posted content goes through: trimming, the policies in order, then the length limits.
+/// Binary payloads skip the policies, only their size and content type are checked.
+pub struct ContentPolicies {
+    policies: Vec<Box<dyn ContentPolicy>>,
+    max_length: Option<usize>,
+    per_topic_max_length: HashMap<String, usize>,
+    max_payload_length: Option<usize>,
+}
+
+impl Default for ContentPolicies {
+    /// Strips control characters, as the server always did, with no length limit.
+    fn default() -> Self {
+        Self {
+            policies: vec![Box::new(ControlCharacters(Violation::Strip))],
+            max_length: None,
+            per_topic_max_length: HashMap::new(),
+            max_payload_length: None,
+        }
+    }
+}
+
+impl ContentPolicies {
+    /// `max_length` is in bytes. Limits of the topics replace the global one, 0 makes a topic unlimited.
+    pub fn new(policies: Vec<Box<dyn ContentPolicy>>, max_length: Option<usize>, per_topic: impl IntoIterator<Item = TopicMaxLength>) -> Self {
+        Self {
+            policies,
+            max_length,
+            per_topic_max_length: per_topic.into_iter()
+                .map(|topic| (topic.topic_name, topic.max_length))
+                .collect(),
+            max_payload_length: None,
+        }
+    }
+
+    /// Limits binary payloads, in bytes.
+    pub fn with_max_payload_length(mut self, max_payload_length: Option<usize>) -> Self {
+        self.max_payload_length = max_payload_length;
+        self
+    }
+
+    /// Returns the content to store, or the reason to reject it.
+    pub fn validate(&self, topic_name: &str, content: &str) -> Result<String, String> {
+        let max_length = match self.per_topic_max_length.get(topic_name) {
+            Some(0) => None,
+            Some(&length) => Some(length),
+            None => self.max_length,
+        };
+
+        // Saves work on huge messages. Policies only ever shrink the content by a bit
+        if let Some(max_length) = max_length {
+            if content.len() > max_length.saturating_mul(4) {
+                return Err(format!("Content is {} bytes long, at most {max_length} are allowed", content.len()));
+            }
+        }
+
+        let mut content = content.trim().to_string();
+        for policy in &self.policies {
+            content = policy.apply(content)?;
+        }
+        let content = content.trim().to_string();
+
+        if content.is_empty() {
+            return Err("Content is empty".to_string());
+        }
+        if let Some(max_length) = max_length {
+            if content.len() > max_length {
+                return Err(format!("Content is {} bytes long, at most {max_length} are allowed", content.len()));
+            }
+        }
+        Ok(content)
+    }
+
+    /// Checks a binary payload and returns its caption and content type to store, or the reason to reject it.
+    /// The caption is optional, but goes through the same checks as text content.
+    pub fn validate_binary(&self, topic_name: &str, caption: &str, payload: &[u8], content_type: &str) -> Result<(String, String), String> {
+        if let Some(max_payload_length) = self.max_payload_length {
+            if payload.len() > max_payload_length {
+                return Err(format!("Payload is {} bytes long, at most {max_payload_length} are allowed", payload.len()));
+            }
+        }
+
+        let content_type = match content_type.trim() {
+            "" => "application/octet-stream",
+            content_type => content_type,
+        };
+        let is_valid_type = content_type.len() <= 255
+            && content_type.split_once('/').is_some_and(|(kind, subtype)| !kind.is_empty() && !subtype.is_empty())
+            && !content_type.chars().any(|c| c.is_control());
+        if !is_valid_type {
+            return Err(format!("Invalid content type '{}'", content_type.escape_debug()));
+        }
+
+        let caption = match caption.trim() {
+            "" => String::new(),
+            caption => self.validate(topic_name, caption)?,
+        };
+        Ok((caption, content_type.to_string()))
+    }
+
+    /// Checks the headers of a message and collects them into a map, or returns the reason to reject them.
+    /// Headers are not changed by the policies, so they are rejected instead of being stripped.
+    pub fn validate_headers(&self, headers: impl IntoIterator<Item = (String, String)>) -> Result<BTreeMap<String, String>, String> {
+        let mut result = BTreeMap::new();
+
+        for (key, value) in headers {
+            if result.len() >= MAX_HEADERS {
+                return Err(format!("At most {MAX_HEADERS} headers are allowed"));
+            }
+            if key.is_empty() || key.len() > MAX_HEADER_KEY_LENGTH {
+                return Err(format!("Header key must be 1 to {MAX_HEADER_KEY_LENGTH} bytes long"));
+            }
+            if value.len() > MAX_HEADER_VALUE_LENGTH {
+                return Err(format!("Header '{}' is {} bytes long, at most {MAX_HEADER_VALUE_LENGTH} are allowed", key.escape_debug(), value.len()));
+            }
+            if key.chars().chain(value.chars()).any(|c| c.is_control()) {
+                return Err(format!("Header '{}' contains control characters", key.escape_debug()));
+            }
+            if result.contains_key(&key) {
+                return Err(format!("Header '{}' is set more than once", key.escape_debug()));
+            }
+            result.insert(key, value);
+        }
+        Ok(result)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policies(max_length: Option<usize>, per_topic: &[&str]) -> ContentPolicies {
+        ContentPolicies::new(
+            vec![
+                Box::new(AnsiEscapes(Violation::Strip)),
+                Box::new(ControlCharacters(Violation::Strip)),
+                Box::new(BannedWords::new(["spam".to_string()])),
+            ],
+            max_length,
+            per_topic.iter().map(|topic| topic.parse().unwrap()),
+        )
+    }
+
+    #[test]
+    fn strips_ansi_escapes() {
+        let content = AnsiEscapes(Violation::Strip).apply("\x1B[1;31mred\x1B[0m \u{9B}2Jplain \x1Bcreset".to_string());
+        assert_eq!(content.unwrap(), "red plain reset");
+    }
+
+    #[test]
+    fn strips_unterminated_ansi_escapes() {
+        assert_eq!(AnsiEscapes(Violation::Strip).apply("text\x1B[31;".to_string()).unwrap(), "text");
+        assert_eq!(AnsiEscapes(Violation::Strip).apply("text\x1B".to_string()).unwrap(), "text");
+        assert_eq!(AnsiEscapes(Violation::Strip).apply("text\u{9B}".to_string()).unwrap(), "text");
+    }
+
+    #[test]
+    fn rejects_ansi_escapes() {
+        assert!(AnsiEscapes(Violation::Reject).apply("\x1B[31mred".to_string()).is_err());
+        assert_eq!(AnsiEscapes(Violation::Reject).apply("plain".to_string()).unwrap(), "plain");
+    }
+
+    #[test]
+    fn validate_applies_policies_in_order() {
+        // Control characters alone would leave `[31m` behind
+        assert_eq!(policies(None, &[]).validate("news", "  \x1B[31mhi\nthere\x07  ").unwrap(), "hithere");
+    }
+
+    #[test]
+    fn validate_rejects_banned_words_as_whole_words() {
+        let policies = policies(None, &[]);
+        assert!(policies.validate("news", "no SPAM please").is_err());
+        assert!(policies.validate("news", "spamming is fine").is_ok());
+    }
+
+    #[test]
+    fn validate_rejects_content_empty_after_policies() {
+        assert!(policies(None, &[]).validate("news", " \x1B[0m\n ").is_err());
+    }
+
+    #[test]
+    fn validate_checks_length_after_policies() {
+        let policies = policies(Some(5), &["logs:0", "short:2"]);
+
+        assert_eq!(policies.validate("news", "\x1B[31mhello\x1B[0m").unwrap(), "hello");
+        assert!(policies.validate("news", "hello!").is_err());
+        assert!(policies.validate("short", "abc").is_err());
+        assert!(policies.validate("logs", &"a".repeat(1000)).is_ok());
+    }
+}
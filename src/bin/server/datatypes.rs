@@ -1,9 +1,14 @@
+use std::collections::BTreeMap;
+
 use chrono::{DateTime, Duration, Utc};
 use serde::{Serialize, Deserialize};
 use uuid::Uuid;
 
 pub type Username = String;
 
+/// Bytes of the longest username a recipient or a mention may have.
+pub const MAX_USERNAME_LENGTH: usize = 64;
+
 pub type Retention = Option<Duration>;
 
 #[derive(Serialize, Deserialize, Clone, Debug, Default)]
@@ -12,6 +17,21 @@ pub struct Topic {
     pub creator: Username,
     pub timestamp: DateTime<Utc>,
     pub retention: Retention,
+    /// 0 for topics that are not partitioned
+    pub partitions: u32,
+    pub description: String,
+    pub tags: Vec<String>,
+    /// Oldest pin first
+    pub pinned_messages: Vec<Uuid>,
+    /// Sorted usernames of a direct conversation. Empty for public topics
+    pub participants: Vec<Username>,
+}
+
+impl Topic {
+    /// Direct conversations are hidden from everyone but the participants.
+    pub fn is_visible_to(&self, username: &str) -> bool {
+        self.participants.is_empty() || self.participants.iter().any(|participant| participant == username)
+    }
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug, Default)]
@@ -22,4 +42,72 @@ pub struct Message {
     pub content: String,
     pub timestamp: DateTime<Utc>,
     pub key: Option<String>,
+
+    /// Binary content, stored as is. Empty for text messages
+    #[serde(with = "payload_encoding")]
+    pub payload: Vec<u8>,
+    pub content_type: String,
+    pub headers: BTreeMap<String, String>,
+    pub partition: u32,
+    /// Previous contents, oldest first
+    pub revisions: Vec<Revision>,
+    pub edited_at: Option<DateTime<Utc>>,
+    /// Message of the same topic this one answers
+    pub reply_to: Option<Uuid>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct Revision {
+    pub content: String,
+    /// When this content was posted, or written by an edit
+    pub timestamp: DateTime<Utc>,
+}
+
+/// Mention of a user in a message.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Notification {
+    /// Mentioned user
+    pub username: Username,
+    pub message_uuid: Uuid,
+    pub topic_uuid: Uuid,
+    pub author_name: Username,
+    pub excerpt: String,
+    pub timestamp: DateTime<Utc>,
+    pub read: bool,
+}
+
+impl Message {
+    /// Bytes the message takes, as counted by rate limits and statistics.
+    pub fn size(&self) -> usize {
+        message_size(&self.content, &self.payload, &self.headers)
+    }
+}
+
+/// Size of a message that is yet to be created.
+pub fn message_size(content: &str, payload: &[u8], headers: &BTreeMap<String, String>) -> usize {
+    let headers = headers.iter().map(|(key, value)| key.len() + value.len()).sum::<usize>();
+    content.len() + payload.len() + headers
+}
+
+/// Base64 in human-readable formats like the NDJSON export, raw bytes in the state file.
+mod payload_encoding {
+    use base64::{engine::general_purpose::STANDARD, Engine};
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(payload: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        if serializer.is_human_readable() {
+            serializer.serialize_str(&STANDARD.encode(payload))
+        } else {
+            serializer.serialize_bytes(payload)
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        if deserializer.is_human_readable() {
+            let encoded = String::deserialize(deserializer)?;
+            STANDARD.decode(encoded).map_err(serde::de::Error::custom)
+        } else {
+            Vec::<u8>::deserialize(deserializer)
+        }
+    }
 }
\ No newline at end of file
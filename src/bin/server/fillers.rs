@@ -1,18 +1,55 @@
-use broker::message_capnp::message;
+use broker::message_capnp::{message, notification};
 use chrono::{DateTime, Timelike, Utc};
 use uuid::Uuid;
 
-use crate::datatypes::{Message, Retention, Topic};
+use crate::datatypes::{Message, Notification, Retention, Topic};
+use crate::stores::{PresenceStatus, UserPresence};
 
 
 pub fn fill_capnp_message(mut builder: message::Builder<'_>, message: &Message) {
     builder.set_author_name(&message.author_name);
     builder.set_content(&message.content);
+    builder.set_payload(&message.payload);
+    builder.set_content_type(&message.content_type);
+    builder.set_partition(message.partition);
+    let mut headers = builder.reborrow().init_headers(message.headers.len() as u32);
+    for (i, (key, value)) in message.headers.iter().enumerate() {
+        let mut header = headers.reborrow().get(i as u32);
+        header.set_key(key);
+        header.set_value(value);
+    }
     fill_capnp_timestamp(builder.reborrow().init_timestamp(), message.timestamp);
+    if let Some(edited_at) = message.edited_at {
+        fill_capnp_timestamp(builder.reborrow().init_edited_at().init_t(), edited_at);
+    }
+    if let Some(reply_to) = message.reply_to {
+        fill_capnp_uuid(builder.reborrow().init_reply_to().init_t(), reply_to);
+    }
     fill_capnp_uuid(builder.reborrow().init_topic_uuid(), message.topic_uuid);
     fill_capnp_uuid(builder.init_uuid(), message.uuid);
 }
 
+/// `topic_name` is empty for notifications of deleted topics.
+pub fn fill_capnp_notification(mut builder: notification::Builder<'_>, notification: &Notification, topic_name: &str) {
+    fill_capnp_uuid(builder.reborrow().init_message_id(), notification.message_uuid);
+    fill_capnp_uuid(builder.reborrow().init_topic_id(), notification.topic_uuid);
+    builder.set_topic_name(topic_name);
+    builder.set_author_name(&notification.author_name);
+    builder.set_excerpt(&notification.excerpt);
+    builder.set_read(notification.read);
+    fill_capnp_timestamp(builder.init_timestamp(), notification.timestamp);
+}
+
+pub fn fill_capnp_presence(mut builder: broker::auth_capnp::user_presence::Builder, presence: &UserPresence) {
+    builder.set_username(&presence.username);
+    builder.set_status(match presence.status {
+        PresenceStatus::Online => broker::auth_capnp::PresenceStatus::Online,
+        PresenceStatus::Idle => broker::auth_capnp::PresenceStatus::Idle,
+        PresenceStatus::Offline => broker::auth_capnp::PresenceStatus::Offline,
+    });
+    fill_capnp_timestamp(builder.init_last_seen(), presence.last_seen);
+}
+
 pub fn fill_capnp_timestamp(mut builder: broker::util_capnp::timestamp::Builder, timestamp: DateTime<Utc>) {
     let seconds = timestamp.timestamp();
     let nanos = timestamp.nanosecond();
@@ -36,7 +73,24 @@ pub fn fill_capnp_retention(mut builder: broker::topic_capnp::retention::Builder
 pub fn fill_capnp_topic(mut builder: broker::topic_capnp::topic::Builder, uuid: Uuid, topic: &Topic) {
     builder.set_name(&topic.name);
     builder.set_owner_username(&topic.creator);
+    builder.set_partitions(topic.partitions);
     fill_capnp_uuid(builder.reborrow().init_uuid(), uuid);
     fill_capnp_timestamp(builder.reborrow().init_created_at(), topic.timestamp);
     fill_capnp_retention(builder.reborrow().init_retention(), topic.retention);
+    builder.set_description(&topic.description);
+
+    let mut tags = builder.reborrow().init_tags(topic.tags.len() as u32);
+    for (i, tag) in topic.tags.iter().enumerate() {
+        tags.set(i as u32, tag);
+    }
+
+    let mut pinned_messages = builder.reborrow().init_pinned_messages(topic.pinned_messages.len() as u32);
+    for (i, uuid) in topic.pinned_messages.iter().enumerate() {
+        fill_capnp_uuid(pinned_messages.reborrow().get(i as u32), *uuid);
+    }
+
+    let mut participants = builder.init_participants(topic.participants.len() as u32);
+    for (i, participant) in topic.participants.iter().enumerate() {
+        participants.set(i as u32, participant);
+    }
 }
\ No newline at end of file
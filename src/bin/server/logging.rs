@@ -0,0 +1,46 @@
+use std::io::IsTerminal;
+
+use clap::ValueEnum;
+use tracing_subscriber::fmt::writer::BoxMakeWriter;
+use tracing_subscriber::EnvFilter;
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogFormat {
+    /// Human-readable lines
+    Text,
+    /// One JSON object per line, with the fields of the current span
+    Json,
+}
+
+/// Installs the global subscriber.
+///
+/// `filter` uses the `RUST_LOG` syntax, e.g. `info` or `info,server::services=debug`.
+/// Without it `RUST_LOG` is used, and `info` if that is not set either.
+/// Logs go to the standard output, or to the standard error with `to_stderr`.
+pub fn init_logging(format: LogFormat, filter: Option<&str>, to_stderr: bool) -> Result<(), Box<dyn std::error::Error>> {
+    let filter = match filter {
+        Some(filter) => EnvFilter::try_new(filter)?,
+        None => EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info")),
+    };
+
+    let (writer, is_terminal) = if to_stderr {
+        (BoxMakeWriter::new(std::io::stderr), std::io::stderr().is_terminal())
+    } else {
+        (BoxMakeWriter::new(std::io::stdout), std::io::stdout().is_terminal())
+    };
+
+    let builder = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(writer)
+        .with_ansi(is_terminal);
+    let result = match format {
+        LogFormat::Text => builder.try_init(),
+        LogFormat::Json => builder.json()
+            .flatten_event(true)
+            .with_current_span(true)
+            .with_span_list(true)
+            .try_init(),
+    };
+
+    result.map_err(|e| e as Box<dyn std::error::Error>)
+}
\ No newline at end of file
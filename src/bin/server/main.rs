@@ -3,14 +3,30 @@ mod stores;
 mod datatypes;
 mod fillers;
 mod server;
+mod workers;
+mod metrics;
+mod logging;
+mod content_policy;
+mod message_filter;
+mod topic_query;
+mod mentions;
+mod state_file;
+mod state_format;
 
-use std::io::Write;
+use std::io::{BufReader, BufWriter};
 use std::path::PathBuf;
+use std::time::{Duration, Instant};
 use std::{fs::File, net::SocketAddr, path::Path, str::FromStr, sync::Arc};
-use tokio::task;
-use server::Server;
+use server::{ListenerConfig, Server};
+use stores::{RateLimits, TopicRateLimits};
+use content_policy::{AnsiEscapes, BannedWords, ContentPolicies, ContentPolicy, ControlCharacters, NormalizeUnicode, TopicMaxLength, Violation};
 use clap::arg;
-use clap::Parser;
+use clap::{Parser, Subcommand};
+use logging::{init_logging, LogFormat};
+use tracing::{error, info};
+
+/// Environment variable with the admin token, used when `--admin-token` is not given
+const ADMIN_TOKEN_VARIABLE: &str = "BROKER_ADMIN_TOKEN";
 
 #[derive(Parser, Debug, Clone)]
 pub struct CliArgs {
@@ -19,62 +35,251 @@ pub struct CliArgs {
 
     #[arg(short, long, default_value_t = String::from("server.save.bin"))]
     pub state_file: String,
+
+    /// Seconds to wait for connections to drain on shutdown
+    #[arg(short, long, default_value_t = 5.0)]
+    pub grace_period: f64,
+
+    /// Amount of worker threads serving connections. Defaults to the number of CPU cores
+    #[arg(short, long)]
+    pub workers: Option<usize>,
+
+    /// Seconds a client may stay silent before it is disconnected. 0 disables the timeout
+    #[arg(short, long, default_value_t = 60.0)]
+    pub idle_timeout: f64,
+
+    /// Seconds of silence before TCP keepalive probes are sent to a client. 0 leaves the OS default
+    #[arg(short, long, default_value_t = 15.0)]
+    pub keepalive: f64,
+
+    /// Address to serve Prometheus metrics on, at `/metrics`. Metrics are not served if not set
+    #[arg(short, long)]
+    pub metrics_address: Option<SocketAddr>,
+
+    /// Log output format
+    #[arg(long, value_enum, default_value_t = LogFormat::Text)]
+    pub log_format: LogFormat,
+
+    /// Log filter, e.g. `debug` or `info,server::services=debug`. Defaults to `RUST_LOG`, or `info` if it is not set
+    #[arg(short, long)]
+    pub log_filter: Option<String>,
+
+    /// Secret that grants access to the admin service. Defaults to `BROKER_ADMIN_TOKEN`. The admin service is disabled without one
+    #[arg(long, value_name = "TOKEN")]
+    pub admin_token: Option<String>,
+
+    /// Messages per second each connection and each user may post. 0 disables the limit
+    #[arg(long, default_value_t = 20.0)]
+    pub rate_limit_messages: f64,
+
+    /// Content bytes per second each connection and each user may post. 0 disables the limit
+    #[arg(long, default_value_t = 65536.0)]
+    pub rate_limit_bytes: f64,
+
+    /// Subscription and history calls per second each connection and each user may make. 0 disables the limit
+    #[arg(long, default_value_t = 10.0)]
+    pub rate_limit_reads: f64,
+
+    /// Extra limits of a topic, on top of the global ones, e.g. `general:messages=5,bytes=4096,reads=2`. Can be repeated
+    #[arg(long = "topic-rate-limit", value_name = "TOPIC:LIMITS")]
+    pub topic_rate_limits: Vec<TopicRateLimits>,
+
+    /// Longest allowed message content, in bytes. 0 disables the limit
+    #[arg(long, default_value_t = 4096)]
+    pub max_content_length: usize,
+
+    /// Longest allowed message content of a topic, replacing the global one, e.g. `logs:65536`. 0 makes the topic unlimited. Can be repeated
+    #[arg(long = "topic-max-content-length", value_name = "TOPIC:BYTES")]
+    pub topic_max_content_lengths: Vec<TopicMaxLength>,
+
+    /// Largest allowed binary payload of a message, in bytes. 0 disables the limit
+    #[arg(long, default_value_t = 1 << 20)]
+    pub max_payload_length: usize,
+
+    /// What to do with control characters and ANSI escape sequences in messages
+    #[arg(long, value_enum, default_value_t = Violation::Strip)]
+    pub on_violation: Violation,
+
+    /// Remove ANSI escape sequences instead of only their escape character. Rejects them with `--on-violation reject`
+    #[arg(long)]
+    pub strip_ansi: bool,
+
+    /// Apply Unicode NFC normalization to messages
+    #[arg(long)]
+    pub normalize_unicode: bool,
+
+    /// File with words that may not appear in messages, one per line
+    #[arg(long)]
+    pub banned_words: Option<PathBuf>,
+
+    /// Work with the state file offline instead of serving
+    #[command(subcommand)]
+    pub command: Option<StateCommand>,
+}
+
+#[derive(Subcommand, Debug, Clone)]
+pub enum StateCommand {
+    /// Print the topics of the state file with their message counts
+    Inspect,
+
+    /// Write every topic, message and notification of the state file as NDJSON
+    Export {
+        /// Defaults to the standard output
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+    },
+
+    /// Create the state file from NDJSON made by `export`. The state file must not exist yet
+    Import {
+        /// Defaults to the standard input
+        input: Option<PathBuf>,
+    },
 }
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let args = CliArgs::parse();
+    // Tools may print their results into the standard output, so their logs go elsewhere
+    init_logging(args.log_format, args.log_filter.as_deref(), args.command.is_some())?;
+
     // Load server
     let path: PathBuf = PathBuf::from_str(&args.state_file)?;
-    let addr = "127.0.0.1:8080";
+    if let Some(command) = args.command {
+        return run_state_command(command, &path);
+    }
+
+    let started = Instant::now();
+    let mut server = match load_server(&path)? {
+        Some(server) => {
+            server.metrics().snapshot_load_duration.observe(started.elapsed().as_secs_f64());
+            server
+        }
+        None => Server::default(),
+    };
+    server.set_state_file(path);
+    server.set_content_policies(content_policies(&args)?);
+    let admin_token = args.admin_token.or_else(|| std::env::var(ADMIN_TOKEN_VARIABLE).ok());
+    server.set_admin_token(admin_token.filter(|token| !token.is_empty()));
+    server.set_rate_limits(
+        RateLimits {
+            messages: positive(args.rate_limit_messages),
+            bytes: positive(args.rate_limit_bytes),
+            reads: positive(args.rate_limit_reads),
+        },
+        args.topic_rate_limits,
+    );
 
     // Run server
-    let server = Arc::new(load_server(&path)?.unwrap_or_default());
+    let server = Arc::new(server);
     server.set_interrupt_handler();
 
-    run_server(server.clone(), addr).await;
+    if let Some(metrics_address) = args.metrics_address {
+        let server = server.clone();
+        tokio::spawn(async move {
+            if let Err(e) = metrics::serve_metrics(metrics_address, server).await {
+                error!(error = %e, "Metrics endpoint stopped");
+            }
+        });
+    }
 
-    let server = Arc::into_inner(server).expect("Some inner jobs did not shut down in time");
+    let config = ListenerConfig {
+        workers: args.workers
+            .unwrap_or_else(|| std::thread::available_parallelism().map_or(1, |n| n.get()))
+            .max(1),
+        grace_period: Duration::from_secs_f64(args.grace_period),
+        idle_timeout: positive_seconds(args.idle_timeout),
+        keepalive: positive_seconds(args.keepalive),
+    };
+    run_server(server.clone(), args.address, config).await;
 
-    // Save server
-    save_state(&server, &path).await?;
+    // Save server. Connections that outlived the grace period may still hold it, which is fine for reading.
+    server.save_state()?;
 
     Ok(())
 }
 
-async fn run_server(server: Arc<Server>, addr: &str) {
-    println!("Starting the server on `{addr}`...");
-    
-    let future = server.listen(addr);
-    let run_result = task::LocalSet::new().run_until(future).await;
-    
-    if run_result.is_err() {
-        eprintln!("Server shutdown with error: {}", run_result.unwrap_err());
-    } else {
-        println!("Server stopped.");
+fn run_state_command(command: StateCommand, path: &Path) -> Result<(), Box<dyn std::error::Error>> {
+    if let StateCommand::Import { input } = command {
+        if path.exists() {
+            return Err(format!("State file '{}' already exists, import needs a fresh one", path.display()).into());
+        }
+
+        let mut server = match input {
+            Some(input) => state_file::import(BufReader::new(File::open(input)?))?,
+            None => state_file::import(std::io::stdin().lock())?,
+        };
+        server.set_state_file(path.to_path_buf());
+        let bytes = server.save_state()?;
+        info!(messages = server.messages().len(), bytes, "Imported the state");
+        return Ok(());
     }
+
+    let server = load_server(path)?
+        .ok_or_else(|| format!("State file '{}' does not exist", path.display()))?;
+
+    match command {
+        StateCommand::Inspect => state_file::inspect(&server, std::io::stdout().lock())?,
+        StateCommand::Export { output } => {
+            let lines = match output {
+                Some(output) => state_file::export(&server, BufWriter::new(File::create(output)?))?,
+                None => state_file::export(&server, BufWriter::new(std::io::stdout().lock()))?,
+            };
+            info!(lines, "Exported the state");
+        }
+        StateCommand::Import { .. } => unreachable!("Import is handled above"),
+    }
+    Ok(())
 }
 
-async fn save_state(server: &Server, save_path: &Path) -> std::io::Result<()> {
-    println!("Saving server state into '{}'...", save_path.display());
-    
-    let mut file = File::create(save_path)?;
+/// Policies run in a fixed order: normalization, escape sequences, control characters, banned words.
+fn content_policies(args: &CliArgs) -> std::io::Result<ContentPolicies> {
+    let mut policies: Vec<Box<dyn ContentPolicy>> = vec![];
 
-    bincode::serialize_into(&mut file, &*server)
-        .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+    if args.normalize_unicode {
+        policies.push(Box::new(NormalizeUnicode));
+    }
+    if args.strip_ansi {
+        policies.push(Box::new(AnsiEscapes(args.on_violation)));
+    }
+    policies.push(Box::new(ControlCharacters(args.on_violation)));
+    if let Some(path) = &args.banned_words {
+        let words = std::fs::read_to_string(path)?;
+        policies.push(Box::new(BannedWords::new(words.lines().map(str::to_string))));
+    }
+
+    let max_length = (args.max_content_length > 0).then_some(args.max_content_length);
+    let max_payload_length = (args.max_payload_length > 0).then_some(args.max_payload_length);
+    Ok(ContentPolicies::new(policies, max_length, args.topic_max_content_lengths.clone())
+        .with_max_payload_length(max_payload_length))
+}
 
-    file.flush().unwrap();
+fn positive_seconds(seconds: f64) -> Option<Duration> {
+    positive(seconds).map(Duration::from_secs_f64)
+}
 
-    Ok(())
+fn positive(value: f64) -> Option<f64> {
+    (value > 0.0).then_some(value)
+}
+
+async fn run_server(server: Arc<Server>, addr: SocketAddr, config: ListenerConfig) {
+    info!("Starting the server on `{addr}`");
+    
+    let run_result = server.listen(addr, config).await;
+    
+    if let Err(error) = run_result {
+        error!(%error, "Server shutdown with error");
+    } else {
+        info!("Server stopped");
+    }
 }
 
 fn load_server(save_path: &Path) -> Result<Option<Server>, std::io::Error> {
     if Path::exists(save_path) {
-        println!("Loading existing server state from '{}'...", save_path.display());
+        info!("Loading existing server state from '{}'", save_path.display());
 
         let file = File::open(save_path)?;
 
-        let server = bincode::deserialize_from::<_, Server>(file)
+        let server = state_format::read(BufReader::new(file))
             .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
         Ok(Some(server))
     } else {
@@ -0,0 +1,79 @@
+use crate::datatypes::{Username, MAX_USERNAME_LENGTH};
+
+/// Users a single message may notify, the rest of the mentions are ignored.
+const MAX_MENTIONS: usize = 16;
+/// Characters of the content a notification keeps.
+const EXCERPT_LENGTH: usize = 100;
+
+/// Sorted, unique usernames mentioned as `@name`.
+/// An `@` right after a letter or a digit, like in e-mail addresses, is not a mention.
+pub fn parse_mentions(content: &str) -> Vec<Username> {
+    let mut mentions = vec![];
+
+    for (index, _) in content.match_indices('@') {
+        if content[..index].chars().next_back().is_some_and(|before| before.is_alphanumeric() || before == '@') {
+            continue;
+        }
+
+        let rest = &content[index + 1..];
+        let end = rest.find(|c: char| !is_username_char(c)).unwrap_or(rest.len());
+        // Punctuation after the name is not a part of it, e.g. in `thanks @alice.`
+        let name = rest[..end].trim_end_matches(['.', '-']);
+        if !name.is_empty() && name.len() <= MAX_USERNAME_LENGTH {
+            mentions.push(name.to_string());
+        }
+    }
+
+    mentions.sort();
+    mentions.dedup();
+    mentions.truncate(MAX_MENTIONS);
+    mentions
+}
+
+/// Beginning of the content, shown in notifications.
+pub fn excerpt(content: &str) -> String {
+    match content.char_indices().nth(EXCERPT_LENGTH) {
+        Some((end, _)) => format!("{}...", &content[..end]),
+        None => content.to_string(),
+    }
+}
+
+fn is_username_char(c: char) -> bool {
+    c.is_alphanumeric() || matches!(c, '_' | '-' | '.')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_sorted_unique_mentions() {
+        assert_eq!(parse_mentions("@carol hi @alice, @bob and @alice again"), ["alice", "bob", "carol"]);
+        assert_eq!(parse_mentions("@first.last_name-2 @élodie"), ["first.last_name-2", "élodie"]);
+    }
+
+    #[test]
+    fn ignores_addresses_and_punctuation() {
+        assert_eq!(parse_mentions("mail alice@example.com or @@bob"), Vec::<String>::new());
+        assert_eq!(parse_mentions("thanks @alice. ping @bob-- (@carol)"), ["alice", "bob", "carol"]);
+        assert_eq!(parse_mentions("just an @ sign and @."), Vec::<String>::new());
+    }
+
+    #[test]
+    fn limits_mentions() {
+        let long_name = "a".repeat(MAX_USERNAME_LENGTH + 1);
+        assert!(parse_mentions(&format!("@{long_name}")).is_empty());
+
+        let content = (0..MAX_MENTIONS + 4).map(|index| format!("@user{index:02}")).collect::<Vec<_>>().join(" ");
+        let mentions = parse_mentions(&content);
+        assert_eq!(mentions.len(), MAX_MENTIONS);
+        assert_eq!(mentions[0], "user00");
+    }
+
+    #[test]
+    fn excerpts_by_characters() {
+        assert_eq!(excerpt("short"), "short");
+        assert_eq!(excerpt(&"é".repeat(EXCERPT_LENGTH)), "é".repeat(EXCERPT_LENGTH));
+        assert_eq!(excerpt(&"é".repeat(EXCERPT_LENGTH + 1)), format!("{}...", "é".repeat(EXCERPT_LENGTH)));
+    }
+}
@@ -0,0 +1,215 @@
+use broker::message_capnp::{content_filter, key_filter, message_filter};
+use capnp::Error;
+use regex::{Regex, RegexBuilder};
+
+use crate::datatypes::Message;
+
+/// Keeps a hostile regex from taking the memory of the server.
+const MAX_REGEX_SIZE: usize = 1 << 20;
+
+#[derive(Clone, Debug, Default)]
+pub enum KeyFilter {
+    #[default]
+    Any,
+    Equals(String),
+    Prefix(String),
+}
+
+#[derive(Clone, Debug, Default)]
+pub enum ContentFilter {
+    #[default]
+    Any,
+    Contains(String),
+    Regex(Regex),
+}
+
+/// Conditions a message has to match to be delivered to a subscriber. The default one matches everything.
+#[derive(Clone, Debug, Default)]
+pub struct MessageFilter {
+    pub author_name: Option<String>,
+    pub key: KeyFilter,
+    pub headers: Vec<(String, String)>,
+    pub content: ContentFilter,
+    /// Empty matches every partition
+    pub partitions: Vec<u32>,
+}
+
+impl MessageFilter {
+    /// Reads the filter of a request. The outer error is a broken request, the inner one is the reason to reject the filter.
+    pub fn read_capnp(reader: message_filter::Reader<'_>) -> Result<Result<Self, String>, Error> {
+        let author_name = match reader.get_author_name()?.to_str()? {
+            "" => None,
+            author_name => Some(author_name.to_string()),
+        };
+
+        let key = match reader.get_key()?.which()? {
+            key_filter::Which::Any(()) => KeyFilter::Any,
+            key_filter::Which::Equals(key) => KeyFilter::Equals(key?.to_string()?),
+            key_filter::Which::Prefix(prefix) => KeyFilter::Prefix(prefix?.to_string()?),
+        };
+
+        let headers = reader.get_headers()?.iter()
+            .map(|header| Ok((header.get_key()?.to_string()?, header.get_value()?.to_string()?)))
+            .collect::<Result<_, Error>>()?;
+
+        let content = match reader.get_content()?.which()? {
+            content_filter::Which::Any(()) => ContentFilter::Any,
+            content_filter::Which::Contains(text) => ContentFilter::Contains(text?.to_string()?),
+            content_filter::Which::Regex(pattern) => {
+                let regex = RegexBuilder::new(pattern?.to_str()?)
+                    .size_limit(MAX_REGEX_SIZE)
+                    .build();
+                match regex {
+                    Ok(regex) => ContentFilter::Regex(regex),
+                    Err(e) => return Ok(Err(format!("Invalid regex: {e}"))),
+                }
+            }
+        };
+
+        let partitions = reader.get_partitions()?.iter().collect();
+
+        Ok(Ok(Self { author_name, key, headers, content, partitions }))
+    }
+
+    pub fn matches(&self, message: &Message) -> bool {
+        if !self.partitions.is_empty() && !self.partitions.contains(&message.partition) {
+            return false;
+        }
+        if self.author_name.as_ref().is_some_and(|author_name| *author_name != message.author_name) {
+            return false;
+        }
+
+        let key = message.key.as_deref();
+        let key_matches = match &self.key {
+            KeyFilter::Any => true,
+            KeyFilter::Equals(expected) => key == Some(expected.as_str()),
+            KeyFilter::Prefix(prefix) => key.is_some_and(|key| key.starts_with(prefix.as_str())),
+        };
+        if !key_matches {
+            return false;
+        }
+
+        let headers_match = self.headers.iter()
+            .all(|(key, value)| message.headers.get(key) == Some(value));
+        if !headers_match {
+            return false;
+        }
+
+        // Binary messages are matched by their caption
+        match &self.content {
+            ContentFilter::Any => true,
+            ContentFilter::Contains(text) => message.content.contains(text.as_str()),
+            ContentFilter::Regex(regex) => regex.is_match(&message.content),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+enum Segment {
+    Exact(String),
+    /// `*`
+    One,
+    /// `#`
+    Any,
+}
+
+/// Hierarchical topic name pattern, like `builds.linux.*` or `alerts.#`.
+#[derive(Clone, Debug)]
+pub struct TopicPattern {
+    segments: Vec<Segment>,
+}
+
+impl TopicPattern {
+    pub fn parse(pattern: &str) -> Result<Self, String> {
+        let segments = pattern.split('.')
+            .map(|segment| match segment {
+                "" => Err(format!("Pattern '{pattern}' has an empty segment")),
+                "*" => Ok(Segment::One),
+                "#" => Ok(Segment::Any),
+                _ if segment.contains(['*', '#']) => Err(format!("Wildcards have to be whole segments, got '{segment}'")),
+                _ => Ok(Segment::Exact(segment.to_string())),
+            })
+            .collect::<Result<_, _>>()?;
+
+        Ok(Self { segments })
+    }
+
+    pub fn matches(&self, topic_name: &str) -> bool {
+        let name = topic_name.split('.').collect::<Vec<_>>();
+
+        // `matched[i]` is whether the segments so far match the first `i` segments of the name
+        let mut matched = vec![false; name.len() + 1];
+        matched[0] = true;
+        for segment in &self.segments {
+            let mut next = vec![false; name.len() + 1];
+            for i in 0..=name.len() {
+                next[i] = match segment {
+                    Segment::Any => matched[i] || (i > 0 && next[i - 1]),
+                    Segment::One => i > 0 && matched[i - 1],
+                    Segment::Exact(exact) => i > 0 && matched[i - 1] && name[i - 1] == exact,
+                };
+            }
+            matched = next;
+        }
+        matched[name.len()]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matches(pattern: &str, topic_name: &str) -> bool {
+        TopicPattern::parse(pattern).unwrap().matches(topic_name)
+    }
+
+    #[test]
+    fn rejects_empty_segments() {
+        assert!(TopicPattern::parse("").is_err());
+        assert!(TopicPattern::parse("alerts.").is_err());
+        assert!(TopicPattern::parse("a..b").is_err());
+    }
+
+    #[test]
+    fn rejects_partial_wildcards() {
+        assert!(TopicPattern::parse("alerts*").is_err());
+        assert!(TopicPattern::parse("a.b#").is_err());
+    }
+
+    #[test]
+    fn exact_segments() {
+        assert!(matches("builds.linux", "builds.linux"));
+        assert!(!matches("builds.linux", "builds.linux.x86"));
+        assert!(!matches("builds.linux", "builds"));
+    }
+
+    #[test]
+    fn star_matches_one_segment() {
+        assert!(matches("builds.*", "builds.linux"));
+        assert!(!matches("builds.*", "builds"));
+        assert!(!matches("builds.*", "builds.linux.x86"));
+        assert!(matches("*.linux", "builds.linux"));
+    }
+
+    #[test]
+    fn trailing_hash_matches_zero_or_more_segments() {
+        assert!(matches("alerts.#", "alerts"));
+        assert!(matches("alerts.#", "alerts.disk"));
+        assert!(matches("alerts.#", "alerts.disk.full"));
+        assert!(!matches("alerts.#", "alert"));
+        assert!(!matches("alerts.#", "other.alerts"));
+    }
+
+    #[test]
+    fn hash_in_the_middle() {
+        assert!(matches("a.#.z", "a.z"));
+        assert!(matches("a.#.z", "a.b.c.z"));
+        assert!(!matches("a.#.z", "a.b.c"));
+    }
+
+    #[test]
+    fn lone_hash_matches_everything() {
+        assert!(matches("#", "news"));
+        assert!(matches("#", "a.b.c"));
+    }
+}
@@ -0,0 +1,180 @@
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use broker::concurrent_list::{ConcurrentList, APPEND_LOCKS, APPEND_MISSES, READ_LOCKS, TOTAL_APPENDS, TOTAL_READS};
+use prometheus::{Encoder, Histogram, HistogramOpts, IntCounter, IntCounterVec, IntGauge, IntGaugeVec, Opts, Registry, TextEncoder};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tracing::{info, warn};
+
+use crate::server::Server;
+
+/// Prometheus metrics of the server. Cloning creates new references to the same metrics.
+///
+/// Services update the metrics as things happen.
+/// Gauges that mirror the state of the stores are refreshed on every scrape, see [`Metrics::update_list_stats`].
+#[derive(Clone)]
+pub struct Metrics {
+    registry: Registry,
+
+    pub connections_total: IntCounter,
+    pub connections_active: IntGauge,
+    pub logins_total: IntCounter,
+    pub logged_in_peers: IntGauge,
+
+    pub messages_posted: IntCounterVec,
+    pub subscribers: IntGaugeVec,
+    pub pattern_subscribers: IntGauge,
+    pub delivery_latency: Histogram,
+
+    pub list_len: IntGauge,
+    pub list_nodes: IntGauge,
+    list_reads: IntCounter,
+    list_read_locks: IntCounter,
+    list_appends: IntCounter,
+    list_append_locks: IntCounter,
+    list_append_misses: IntCounter,
+
+    pub snapshot_save_duration: Histogram,
+    pub snapshot_load_duration: Histogram,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        let registry = Registry::new_custom(Some("broker".to_string()), None)
+            .expect("Metrics prefix is valid");
+
+        let latency_buckets = prometheus::exponential_buckets(0.0005, 2.0, 16).unwrap();
+        let snapshot_buckets = prometheus::exponential_buckets(0.001, 4.0, 10).unwrap();
+
+        let metrics = Self {
+            connections_total: IntCounter::new("connections_total", "Accepted connections").unwrap(),
+            connections_active: IntGauge::new("connections_active", "Currently open connections").unwrap(),
+            logins_total: IntCounter::new("logins_total", "Successful logins").unwrap(),
+            logged_in_peers: IntGauge::new("logged_in_peers", "Currently logged in peers").unwrap(),
+
+            messages_posted: IntCounterVec::new(Opts::new("messages_posted_total", "Posted messages per topic"), &["topic"]).unwrap(),
+            subscribers: IntGaugeVec::new(Opts::new("subscribers", "Active subscriptions per topic"), &["topic"]).unwrap(),
+            pattern_subscribers: IntGauge::new("pattern_subscribers", "Active subscriptions to topic patterns").unwrap(),
+            delivery_latency: Histogram::with_opts(
+                HistogramOpts::new("delivery_latency_seconds", "Time from posting a message to handing it to a subscriber")
+                    .buckets(latency_buckets)
+            ).unwrap(),
+
+            list_len: IntGauge::new("message_list_len", "Elements in the messages `ConcurrentList`").unwrap(),
+            list_nodes: IntGauge::new("message_list_nodes", "Allocated nodes of the messages `ConcurrentList`").unwrap(),
+            list_reads: IntCounter::new("concurrent_list_reads_total", "Element reads of all `ConcurrentList`s").unwrap(),
+            list_read_locks: IntCounter::new("concurrent_list_read_locks_total", "Reads of all `ConcurrentList`s that had to wait on a lock").unwrap(),
+            list_appends: IntCounter::new("concurrent_list_appends_total", "Appends to all `ConcurrentList`s").unwrap(),
+            list_append_locks: IntCounter::new("concurrent_list_append_locks_total", "Appends to all `ConcurrentList`s that had to wait on a lock").unwrap(),
+            list_append_misses: IntCounter::new("concurrent_list_append_misses_total", "Nodes allocated by appends and instantly deallocated").unwrap(),
+
+            snapshot_save_duration: Histogram::with_opts(
+                HistogramOpts::new("snapshot_save_seconds", "Time to save the server state").buckets(snapshot_buckets.clone())
+            ).unwrap(),
+            snapshot_load_duration: Histogram::with_opts(
+                HistogramOpts::new("snapshot_load_seconds", "Time to load the server state").buckets(snapshot_buckets)
+            ).unwrap(),
+
+            registry,
+        };
+        metrics.register_all();
+        metrics
+    }
+
+    fn register_all(&self) {
+        let collectors: [Box<dyn prometheus::core::Collector>; 17] = [
+            Box::new(self.connections_total.clone()),
+            Box::new(self.connections_active.clone()),
+            Box::new(self.logins_total.clone()),
+            Box::new(self.logged_in_peers.clone()),
+            Box::new(self.messages_posted.clone()),
+            Box::new(self.subscribers.clone()),
+            Box::new(self.pattern_subscribers.clone()),
+            Box::new(self.delivery_latency.clone()),
+            Box::new(self.list_len.clone()),
+            Box::new(self.list_nodes.clone()),
+            Box::new(self.list_reads.clone()),
+            Box::new(self.list_read_locks.clone()),
+            Box::new(self.list_appends.clone()),
+            Box::new(self.list_append_locks.clone()),
+            Box::new(self.list_append_misses.clone()),
+            Box::new(self.snapshot_save_duration.clone()),
+            Box::new(self.snapshot_load_duration.clone()),
+        ];
+        for collector in collectors {
+            self.registry.register(collector).expect("Metric names are unique");
+        }
+    }
+
+    /// Copies the state of the messages list and the global `ConcurrentList` counters into the metrics.
+    pub fn update_list_stats<T>(&self, list: &ConcurrentList<T>) {
+        self.list_len.set(list.len() as i64);
+        self.list_nodes.set(list.nodes_count() as i64);
+
+        sync_counter(&self.list_reads, &TOTAL_READS);
+        sync_counter(&self.list_read_locks, &READ_LOCKS);
+        sync_counter(&self.list_appends, &TOTAL_APPENDS);
+        sync_counter(&self.list_append_locks, &APPEND_LOCKS);
+        sync_counter(&self.list_append_misses, &APPEND_MISSES);
+    }
+
+    /// Encodes all metrics in the Prometheus text format.
+    pub fn render(&self) -> String {
+        let mut buffer = vec![];
+        TextEncoder::new()
+            .encode(&self.registry.gather(), &mut buffer)
+            .expect("Encoding into a Vec does not fail");
+        String::from_utf8(buffer).expect("Prometheus text format is UTF-8")
+    }
+}
+
+fn sync_counter(counter: &IntCounter, source: &AtomicUsize) {
+    let current = source.load(Ordering::Relaxed) as u64;
+    counter.inc_by(current.saturating_sub(counter.get()));
+}
+
+/// Serves `GET /metrics` over plain HTTP. Every scrape renders [`Server::render_metrics`].
+pub async fn serve_metrics(addr: SocketAddr, server: Arc<Server>) -> std::io::Result<()> {
+    let listener = TcpListener::bind(addr).await?;
+    info!("Serving metrics on `http://{addr}/metrics`");
+
+    loop {
+        let (stream, peer) = listener.accept().await?;
+        let server = server.clone();
+
+        tokio::spawn(async move {
+            if let Err(e) = respond(stream, &server).await {
+                warn!(peer = %peer, error = %e, "Failed to serve metrics");
+            }
+        });
+    }
+}
+
+async fn respond(mut stream: TcpStream, server: &Server) -> std::io::Result<()> {
+    // Only the request line matters, the rest of the request is ignored
+    let mut buf = [0u8; 1024];
+    let read = stream.read(&mut buf).await?;
+    let request = String::from_utf8_lossy(&buf[..read]);
+    let mut request_line = request.lines().next().unwrap_or_default().split(' ');
+
+    let (status, content_type, body) = match (request_line.next(), request_line.next()) {
+        (Some("GET"), Some("/metrics")) => ("200 OK", prometheus::TEXT_FORMAT, server.render_metrics()),
+        (Some("GET"), Some(_)) => ("404 Not Found", "text/plain", "Not found\n".to_string()),
+        _ => ("405 Method Not Allowed", "text/plain", "Only GET is supported\n".to_string()),
+    };
+
+    let response = format!(
+        "HTTP/1.1 {status}\r\nContent-Type: {content_type}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
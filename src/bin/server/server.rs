@@ -1,24 +1,55 @@
+use std::cell::RefCell;
+use std::fs::File;
+use std::io::Write;
 use std::net::SocketAddr;
-use std::sync::Arc;
+use std::path::PathBuf;
+use std::rc::Rc;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 use broker::concurrent_list::ConcurrentList;
+use chrono::Utc;
 use capnp_rpc::RpcSystem;
 use serde::ser::SerializeStruct;
+use socket2::{SockRef, TcpKeepalive};
 use tokio::net::{TcpStream, TcpListener};
-use tokio::sync::Notify;
+use tokio_util::sync::CancellationToken;
+use tokio_util::task::TaskTracker;
+use tracing::{error, info, warn, Instrument, Span};
 use serde::{Serialize, Serializer, Deserialize, Deserializer};
 
-use broker::util::{stream_to_rpc_network, Handle, StoreRegistry};
-use broker::main_capnp::root_service;
+use broker::util::{stream_to_tracked_rpc_network, Activity, Handle, StoreRegistry};
+use broker::main_capnp::{root_service, session_listener};
 
-use crate::services::{AuthService, MessageService, RootService, TopicService};
+use crate::services::{AdminService, AuthService, MessageService, RootService, TopicService};
 use crate::datatypes::{Topic, Message};
-use crate::stores::{CrudStore, LoginStore};
+use crate::metrics::Metrics;
+use crate::content_policy::ContentPolicies;
+use crate::stores::{ConversationIndex, CrudStore, LoginStore, MessageEdits, NotificationEvents, NotificationStore, PresenceStore, RateLimiter, RateLimits, ReadOnlyMode, SessionStore, TopicEvents, TopicIndex, TopicStats, TopicRateLimits, TypingNotices};
+use crate::state_format;
+use crate::workers::WorkerPool;
+
+/// Settings of a single [`Server::listen`] call.
+#[derive(Debug, Clone)]
+pub struct ListenerConfig {
+    /// Amount of worker threads connections are distributed across.
+    pub workers: usize,
+    /// How long connections may take to drain after the interrupt.
+    pub grace_period: Duration,
+    /// Peers that send nothing for this long are disconnected. `None` keeps idle peers forever.
+    pub idle_timeout: Option<Duration>,
+    /// Silence after which the OS starts sending TCP keepalive probes. `None` leaves the OS default.
+    pub keepalive: Option<Duration>,
+}
 
 pub struct Server {
-    interrupt: Notify,
+    shutdown: CancellationToken,
     stores: StoreRegistry,
+    metrics: Metrics,
 
     messages: ConcurrentList<Message>,
+
+    state_file: Option<PathBuf>,
+    snapshot_lock: Mutex<()>,
 }
 
 impl Default for Server {
@@ -31,85 +62,309 @@ impl Server {
     pub fn new() -> Self {
         let topics = CrudStore::<Topic>::default();
         let messages = ConcurrentList::<Message>::default();
-        Self::from_messages(topics, messages)
+        Self::from_messages(topics, messages, NotificationStore::default())
     }
 
-    pub fn from_messages(topics: CrudStore<Topic>, messages: ConcurrentList<Message>) -> Self {
+    pub fn from_messages(topics: CrudStore<Topic>, messages: ConcurrentList<Message>, mut notifications: NotificationStore) -> Self {
         let mut stores = StoreRegistry::new();
 
+        let metrics = Metrics::new();
+
+        // Statistics and known users are not saved, they are counted once from the loaded messages
+        let mut topic_stats = TopicStats::default();
+        let mut handle = messages.reference();
+        handle.drain_backwards();
+        for elem in handle {
+            if let Some(message) = &*elem {
+                topic_stats.record_message(message);
+                notifications.add_known_user(&message.author_name);
+            }
+        }
+
+        let mut topic_index = TopicIndex::default();
+        let mut conversations = ConversationIndex::default();
+        for (uuid, topic) in topics.get_all() {
+            topic_index.insert(uuid, &topic, topic_stats.last_activity(uuid, topic.timestamp));
+            conversations.insert(uuid, &topic);
+        }
         let topics = Handle::from(topics);
 
         stores.add(messages.reference());
         stores.add(topics);
         stores.add(Handle::<LoginStore>::new());
+        stores.add(Handle::<SessionStore>::new());
+        stores.add(ReadOnlyMode::default());
+        stores.add(TopicEvents::default());
+        stores.add(MessageEdits::default());
+        stores.add(TypingNotices::default());
+        stores.add(Handle::<PresenceStore>::new());
+        stores.add(Handle::from(notifications));
+        stores.add(NotificationEvents::default());
+        stores.add(Handle::from(topic_stats));
+        stores.add(Handle::from(topic_index));
+        stores.add(Handle::from(conversations));
+        stores.add(Handle::<RateLimiter>::new());
+        stores.add(Handle::<ContentPolicies>::new());
+        stores.add(metrics.clone());
 
         Self {
-            interrupt: Notify::new(),
+            shutdown: CancellationToken::new(),
             stores,
+            metrics,
             messages,
+            state_file: None,
+            snapshot_lock: Mutex::new(()),
         }
     }
 
-    pub async fn listen(self: Arc<Self>, addr: &str) -> std::io::Result<()> {
+    pub fn metrics(&self) -> &Metrics {
+        &self.metrics
+    }
+
+    pub fn messages(&self) -> &ConcurrentList<Message> {
+        &self.messages
+    }
+
+    pub fn stores(&self) -> &StoreRegistry {
+        &self.stores
+    }
+
+    /// Secret that peers present to get the [`AdminService`]. `None` disables it.
+    pub fn set_admin_token(&self, token: Option<String>) {
+        self.stores.get::<Handle<LoginStore>>()
+            .get_mut()
+            .set_admin_token(token);
+    }
+
+    /// Limits of every connection and every user. Topics listed in `per_topic` get their own limits on top.
+    pub fn set_rate_limits(&self, global: RateLimits, per_topic: impl IntoIterator<Item = TopicRateLimits>) {
+        self.stores.get::<Handle<RateLimiter>>()
+            .get_mut()
+            .configure(global, per_topic);
+    }
+
+    /// Checks and transformations of posted content.
+    pub fn set_content_policies(&self, policies: ContentPolicies) {
+        *self.stores.get::<Handle<ContentPolicies>>().get_mut() = policies;
+    }
+
+    /// File that [`Server::save_state`] writes into.
+    pub fn set_state_file(&mut self, path: PathBuf) {
+        self.state_file = Some(path);
+    }
+
+    /// Saves the state into the state file and returns its size in bytes.
+    /// Concurrent saves are serialized, a failed save never corrupts the previous state.
+    pub fn save_state(&self) -> std::io::Result<u64> {
+        let Some(save_path) = &self.state_file else {
+            return Err(std::io::Error::new(std::io::ErrorKind::NotFound, "State file is not set"));
+        };
+        let _guard = self.snapshot_lock.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+
+        info!("Saving server state into '{}'", save_path.display());
+        let started = Instant::now();
+    
+        // Write into a temporary file first, so a failed save never corrupts the previous state
+        let tmp_path = save_path.with_extension("tmp");
+        let mut file = File::create(&tmp_path)?;
+
+        state_format::write(self, &mut file)
+            .map_err(std::io::Error::other)?;
+
+        file.flush()?;
+        file.sync_all()?;
+        let bytes = file.metadata()?.len();
+        std::fs::rename(&tmp_path, save_path)?;
+
+        self.metrics.snapshot_save_duration.observe(started.elapsed().as_secs_f64());
+        Ok(bytes)
+    }
+
+    /// Refreshes the gauges that mirror the stores and renders all metrics in the Prometheus text format.
+    pub fn render_metrics(&self) -> String {
+        self.metrics.update_list_stats(&self.messages);
+
+        let logged_in = self.stores.get::<Handle<LoginStore>>().get().logged_in_count();
+        self.metrics.logged_in_peers.set(logged_in as i64);
+
+        self.metrics.render()
+    }
+
+    /// Accepts connections until interrupted, then drains them.
+    /// 
+    /// Connections are served by a [`WorkerPool`] of `config.workers` threads.
+    /// On interrupt the listener is closed, every session is notified, subscribers deliver
+    /// the messages that were already posted, and connections are closed gracefully.
+    /// Connections that did not finish within the grace period are dropped along with their workers.
+    pub async fn listen(self: Arc<Self>, addr: SocketAddr, config: ListenerConfig) -> std::io::Result<()> {
         let listener = TcpListener::bind(addr).await?;
-        let mut connections = vec!();
+        let connections = TaskTracker::new();
+        let workers = WorkerPool::new(self.clone(), config.clone(), connections.clone())?;
+        info!(workers = workers.workers_count(), "Serving connections");
+
+        // Users become idle without any request, so presence is checked on a timer
+        let mut idle_check = tokio::time::interval(Duration::from_secs(10));
         
         loop {
             tokio::select! {
-                _ = self.interrupt.notified() => {
-                    eprintln!("Stopping the listener.");
+                _ = self.shutdown.cancelled() => {
+                    info!("Stopping the listener");
                     break;
                 }
+                _ = idle_check.tick() => {
+                    self.stores.get::<Handle<PresenceStore>>().get_mut().mark_idle(Utc::now());
+                }
                 accepted = listener.accept() => {
-                    match accepted {
-                        Err(e) => eprintln!("Failed to accept connection: {e}"),
-                        Ok((stream, addr)) => {
-                            let process_fut = self.clone().process_connection(stream, addr);
-                            // let send = ; TODO: ?????
-                            let spawn = tokio::task::spawn_local(process_fut);
-                            connections.push(spawn);
-                        }
-                    };
+                    let dispatched = accepted.and_then(|(stream, addr)| workers.dispatch(stream, addr));
+                    if let Err(e) = dispatched {
+                        warn!(error = %e, "Failed to accept connection");
+                    }
                 }
             }
         }
+        drop(listener);
 
-        println!("Aborting {} coroutines...", connections.len());
-        for conn in connections {
-            conn.abort();
+        connections.close();
+        info!(connections = connections.len(), grace_period = ?config.grace_period, "Draining connections");
+        match tokio::time::timeout(config.grace_period, connections.wait()).await {
+            Ok(()) => info!("All connections drained"),
+            Err(_) => warn!(connections = connections.len(), "Connections did not drain in time, dropping them"),
         }
-        println!("Aborted.");
+        workers.join();
 
         Ok(())
     }
 
-    async fn process_connection(self: Arc<Self>, stream: TcpStream, addr: SocketAddr) {
-        println!("Accepted connection from addr: {addr}");
+    /// Serves a single peer until it disconnects, goes idle for longer than `config.idle_timeout`, or the server shuts down.
+    #[tracing::instrument(name = "connection", skip_all, fields(peer = %addr))]
+    pub async fn process_connection(self: Arc<Self>, stream: TcpStream, addr: SocketAddr, config: ListenerConfig) {
+        info!("Accepted connection");
+        self.metrics.connections_total.inc();
+        self.metrics.connections_active.inc();
+
+        if let Some(keepalive) = config.keepalive {
+            let params = TcpKeepalive::new().with_time(keepalive).with_interval(keepalive);
+            if let Err(e) = SockRef::from(&stream).set_tcp_keepalive(&params) {
+                warn!(error = %e, "Failed to enable TCP keepalive");
+            }
+        }
+
+        let subscriptions = TaskTracker::new();
+        // Cancelled once the peer is gone, so the tasks watching events for it stop
+        let connection = self.shutdown.child_token();
+        let listener = Rc::new(RefCell::new(None));
+        let kicked = self.stores.get::<Handle<SessionStore>>().get_mut().open(addr);
 
         // Services
-        let auth = AuthService::new(addr, &self.stores);
-        let topic = TopicService::new(addr, &self.stores);
-        let message = MessageService::new(addr, &self.stores);
+        let auth = AuthService::new(addr, &self.stores, connection.clone(), subscriptions.clone());
+        let topic = TopicService::new(addr, &self.stores, connection.clone(), subscriptions.clone());
+        let message = MessageService::new(addr, &self.stores, self.shutdown.clone(), connection.clone(), subscriptions.clone());
+        let admin = AdminService::new(addr, self.clone());
 
         // Root service
         let root = RootService {
+            peer: addr,
+            login_store: self.stores.get::<Handle<LoginStore>>().clone(),
+
             auth: capnp_rpc::new_client(auth), 
             topic: capnp_rpc::new_client(topic),
             message: capnp_rpc::new_client(message),
+            admin: capnp_rpc::new_client(admin),
+            listener: listener.clone(),
         };
         let root_client: root_service::Client = capnp_rpc::new_client(root);
 
         // Network
-        let network = stream_to_rpc_network(stream);
+        let activity = Activity::new();
+        let network = stream_to_tracked_rpc_network(stream, activity.clone());
         let rpc_system = RpcSystem::new(Box::new(network), Some(root_client.client));
+        let disconnector = rpc_system.get_disconnector();
         
         // Launch
-        tokio::task::spawn_local(rpc_system).await.unwrap().unwrap();
-        println!("Peer {addr} disconnected");
+        let mut rpc_task = tokio::task::spawn_local(rpc_system.instrument(Span::current()));
+
+        let result = tokio::select! {
+            result = &mut rpc_task => result,
+            _ = Self::wait_until_idle(&activity, config.idle_timeout) => {
+                info!(idle_for = ?activity.idle_for(), "Peer is idle, disconnecting");
+                let _ = disconnector.await;
+                rpc_task.await
+            }
+            _ = kicked.cancelled() => {
+                info!("Peer was kicked, disconnecting");
+                let listener = listener.borrow().clone();
+                if let Some(listener) = listener {
+                    let mut request = listener.kicked_request();
+                    request.get().set_reason("Kicked by an admin");
+                    let _ = request.send().promise.await;
+                }
+
+                let _ = disconnector.await;
+                rpc_task.await
+            }
+            _ = self.shutdown.cancelled() => {
+                let listener = listener.borrow().clone();
+                Self::drain_connection(listener, subscriptions).await;
+
+                let _ = disconnector.await;
+                rpc_task.await
+            }
+        };
+
+        // Services are gone along with the RPC system, so is the session of the peer
+        connection.cancel();
+        let username = self.stores.get::<Handle<LoginStore>>()
+            .get_mut()
+            .log_peer_out(&addr);
+        if let Some(username) = username {
+            self.stores.get::<Handle<PresenceStore>>()
+                .get_mut()
+                .disconnect(&username, Utc::now());
+        }
+        self.stores.get::<Handle<SessionStore>>()
+            .get_mut()
+            .close(&addr);
+        self.stores.get::<Handle<RateLimiter>>()
+            .get_mut()
+            .forget_peer(&addr);
+        self.metrics.connections_active.dec();
+
+        match result {
+            Ok(Ok(())) => info!("Peer disconnected"),
+            Ok(Err(e)) => warn!(error = %e, "Peer disconnected with error"),
+            Err(e) => error!(error = %e, "Connection task failed"),
+        }
+    }
+
+    /// Resolves once nothing was received for `idle_timeout`. Never resolves without a timeout.
+    async fn wait_until_idle(activity: &Activity, idle_timeout: Option<Duration>) {
+        let Some(idle_timeout) = idle_timeout else {
+            return std::future::pending().await;
+        };
+
+        loop {
+            let idle_for = activity.idle_for();
+            if idle_for >= idle_timeout {
+                return;
+            }
+            tokio::time::sleep(idle_timeout - idle_for).await;
+        }
+    }
+
+    /// Notifies the peer about the shutdown and waits until its subscribers deliver all posted messages.
+    async fn drain_connection(listener: Option<session_listener::Client>, subscriptions: TaskTracker) {
+        if let Some(listener) = listener {
+            let mut request = listener.server_shutdown_request();
+            request.get().set_reason("Server is shutting down");
+            let _ = request.send().promise.await;
+        }
+
+        subscriptions.close();
+        subscriptions.wait().await;
     }
 
     fn interrupt(&self) {
-        self.interrupt.notify_waiters();
+        self.shutdown.cancel();
     }
 }
 
@@ -120,7 +375,7 @@ impl Server {
         let result = ctrlc::set_handler(
             move || match weak.upgrade() {
                 Some(server) => server.interrupt(),
-                None => eprintln!("Server no longer exists, nothing to interrupt."),
+                None => warn!("Server no longer exists, nothing to interrupt"),
             }
         );
 
@@ -128,13 +383,13 @@ impl Server {
             Ok(_) => {},
 
             Err(ctrlc::Error::NoSuchSignal(signal_type)) => 
-                eprintln!("Signal {signal_type:?} not found, CTRL + C interrupt will not be handled gracefully."),
+                warn!("Signal {signal_type:?} not found, CTRL + C interrupt will not be handled gracefully"),
 
             Err(ctrlc::Error::MultipleHandlers) =>
-                eprintln!("CTRL + C interrupt already has a handler, interrupt may not be handled gracefully."),
+                warn!("CTRL + C interrupt already has a handler, interrupt may not be handled gracefully"),
 
             Err(ctrlc::Error::System(err)) =>
-                eprintln!("CTRL + C interrupt not set, interrupt may not be handled gracefully. Reason: {err}."),
+                warn!(error = %err, "CTRL + C interrupt not set, interrupt may not be handled gracefully"),
         }
     }
 }
@@ -147,11 +402,14 @@ impl Serialize for Server {
         // Get read access to the topics store
         let topics_handle = self.stores.get::<Handle<CrudStore<Topic>>>();
         let topics_store = topics_handle.get();
+        let notifications_handle = self.stores.get::<Handle<NotificationStore>>();
+        let notifications_store = notifications_handle.get();
         
-        // Create a serializer for our two data fields
-        let mut state = serializer.serialize_struct("Server", 2)?;
+        // Create a serializer for our data fields
+        let mut state = serializer.serialize_struct("Server", 3)?;
         state.serialize_field("messages", &self.messages)?;
         state.serialize_field("topics", &*topics_store)?;
+        state.serialize_field("notifications", &*notifications_store)?;
         state.end()
     }
 }
@@ -166,11 +424,12 @@ impl<'de> Deserialize<'de> for Server {
         struct ServerData {
             messages: ConcurrentList<Message>,
             topics: CrudStore<Topic>,
+            notifications: NotificationStore,
         }
 
         let data = ServerData::deserialize(deserializer)?;
         
         // Create new server instance with default stores
-        Ok(Server::from_messages(data.topics, data.messages))
+        Ok(Server::from_messages(data.topics, data.messages, data.notifications))
     }
 }
\ No newline at end of file
@@ -0,0 +1,183 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Instant;
+
+use broker::concurrent_list::{APPEND_LOCKS, APPEND_MISSES, READ_LOCKS, TOTAL_APPENDS, TOTAL_READS};
+use broker::main_capnp::admin_service::{self, GetStatsParams, GetStatsResults, KickParams, KickResults, ListSessionsParams, ListSessionsResults, ListSubscriptionsParams, ListSubscriptionsResults, SetReadOnlyParams, SetReadOnlyResults, SnapshotParams, SnapshotResults};
+use broker::util::Handle;
+use capnp::{capability::Promise, Error};
+use capnp_rpc::pry;
+use tracing::info;
+use uuid::Uuid;
+
+use crate::datatypes::Topic;
+use crate::fillers::{fill_capnp_timestamp, fill_capnp_uuid};
+use crate::server::Server;
+use crate::stores::{CrudStore, LoginStore, ReadOnlyMode, Session, SessionStore};
+use super::traced::{record_username, rpc_span, traced};
+
+
+/// Inspection and control of the whole server. Only available to peers that presented the admin token, see [`LoginStore::grant_admin`].
+pub struct AdminService {
+    peer: SocketAddr,
+    server: Arc<Server>,
+
+    login_store: Handle<LoginStore>,
+    session_store: Handle<SessionStore>,
+    topic_store: Handle<CrudStore<Topic>>,
+    read_only: ReadOnlyMode,
+}
+
+impl AdminService {
+    pub fn new(peer: SocketAddr, server: Arc<Server>) -> Self {
+        let stores = server.stores();
+
+        Self {
+            peer,
+            login_store: stores.get::<Handle<LoginStore>>().clone(),
+            session_store: stores.get::<Handle<SessionStore>>().clone(),
+            topic_store: stores.get::<Handle<CrudStore<Topic>>>().clone(),
+            read_only: stores.get::<ReadOnlyMode>().clone(),
+            server,
+        }
+    }
+}
+
+fn fill_capnp_session(mut builder: admin_service::session::Builder, peer: &SocketAddr, session: &Session, login_store: &LoginStore) -> Result<(), Error> {
+    builder.set_peer(peer.to_string());
+    if let Some(username) = login_store.get_login(peer) {
+        builder.reborrow().init_username().set_t(&username)?;
+    }
+    fill_capnp_timestamp(builder.reborrow().init_connected_at(), session.connected_at);
+    builder.set_subscriptions((session.subscriptions.len() + session.pattern_subscriptions.len()) as u32);
+    Ok(())
+}
+
+impl admin_service::Server for AdminService {
+    fn list_sessions(&mut self, _: ListSessionsParams, mut results: ListSessionsResults) -> Promise<(), Error> {
+        traced(rpc_span!("AdminService", "list_sessions", self.peer), || {
+            let login_store = self.login_store.get();
+            record_username(&pry!(login_store.check_admin(&self.peer)));
+
+            let session_store = self.session_store.get();
+            let mut builder = results.get().init_sessions(session_store.sessions_count() as u32);
+
+            for (index, (peer, session)) in session_store.get_all().enumerate() {
+                pry!(fill_capnp_session(builder.reborrow().get(index as u32), peer, session, &login_store));
+            }
+
+            Promise::ok(())
+        })
+    }
+
+    fn list_subscriptions(&mut self, _: ListSubscriptionsParams, mut results: ListSubscriptionsResults) -> Promise<(), Error> {
+        traced(rpc_span!("AdminService", "list_subscriptions", self.peer), || {
+            let login_store = self.login_store.get();
+            record_username(&pry!(login_store.check_admin(&self.peer)));
+
+            // Group the sessions by the topics they are subscribed to
+            let session_store = self.session_store.get();
+            let mut per_topic: HashMap<Uuid, Vec<(&SocketAddr, &Session)>> = HashMap::new();
+            for (peer, session) in session_store.get_all() {
+                for topic_uuid in &session.subscriptions {
+                    per_topic.entry(*topic_uuid).or_default().push((peer, session));
+                }
+            }
+
+            let topic_store = self.topic_store.get();
+            let mut builder = results.get().init_topics(per_topic.len() as u32);
+
+            for (index, (topic_uuid, subscribers)) in per_topic.into_iter().enumerate() {
+                let mut topic_builder = builder.reborrow().get(index as u32);
+                fill_capnp_uuid(topic_builder.reborrow().init_topic_id(), topic_uuid);
+                if let Some(topic) = topic_store.get(topic_uuid) {
+                    topic_builder.set_topic_name(&topic.name);
+                }
+
+                let mut subscribers_builder = topic_builder.init_subscribers(subscribers.len() as u32);
+                for (index, (peer, session)) in subscribers.into_iter().enumerate() {
+                    pry!(fill_capnp_session(subscribers_builder.reborrow().get(index as u32), peer, session, &login_store));
+                }
+            }
+
+            Promise::ok(())
+        })
+    }
+
+    fn kick(&mut self, params: KickParams, mut results: KickResults) -> Promise<(), Error> {
+        traced(rpc_span!("AdminService", "kick", self.peer), || {
+            record_username(&pry!(self.login_store.get().check_admin(&self.peer)));
+
+            let peer = pry!(pry!(pry!(params.get()).get_peer()).to_str());
+            let peer: SocketAddr = pry!(peer.parse().map_err(|e| Error::failed(format!("Invalid peer address '{peer}': {e}"))));
+
+            let kicked = self.session_store.get().kick(&peer);
+            if kicked {
+                info!(target_peer = %peer, "Kicking a session");
+            }
+            results.get().set_kicked(kicked);
+
+            Promise::ok(())
+        })
+    }
+
+    fn snapshot(&mut self, _: SnapshotParams, mut results: SnapshotResults) -> Promise<(), Error> {
+        traced(rpc_span!("AdminService", "snapshot", self.peer), || {
+            record_username(&pry!(self.login_store.get().check_admin(&self.peer)));
+
+            // Saving may take a while, so it does not block the worker
+            let server = self.server.clone();
+            Promise::from_future(async move {
+                let started = Instant::now();
+                let bytes = tokio::task::spawn_blocking(move || server.save_state())
+                    .await
+                    .map_err(|e| Error::failed(format!("Snapshot task failed: {e}")))?
+                    .map_err(|e| Error::failed(format!("Failed to save the state: {e}")))?;
+
+                let mut results = results.get();
+                results.set_bytes(bytes);
+                results.set_duration_ms(started.elapsed().as_secs_f64() * 1000.0);
+                Ok(())
+            })
+        })
+    }
+
+    fn get_stats(&mut self, _: GetStatsParams, mut results: GetStatsResults) -> Promise<(), Error> {
+        traced(rpc_span!("AdminService", "get_stats", self.peer), || {
+            let login_store = self.login_store.get();
+            record_username(&pry!(login_store.check_admin(&self.peer)));
+
+            let messages = self.server.messages();
+            let mut stats = results.get().init_stats();
+
+            stats.set_topics(self.topic_store.get().count(|_| true) as u64);
+            stats.set_messages(messages.len() as u64);
+            stats.set_message_nodes(messages.nodes_count() as u64);
+            stats.set_sessions(self.session_store.get().sessions_count() as u64);
+            stats.set_logged_in(login_store.logged_in_count() as u64);
+            stats.set_read_only(self.read_only.is_enabled());
+
+            stats.set_list_reads(TOTAL_READS.load(Ordering::Relaxed) as u64);
+            stats.set_list_read_locks(READ_LOCKS.load(Ordering::Relaxed) as u64);
+            stats.set_list_appends(TOTAL_APPENDS.load(Ordering::Relaxed) as u64);
+            stats.set_list_append_locks(APPEND_LOCKS.load(Ordering::Relaxed) as u64);
+            stats.set_list_append_misses(APPEND_MISSES.load(Ordering::Relaxed) as u64);
+
+            Promise::ok(())
+        })
+    }
+
+    fn set_read_only(&mut self, params: SetReadOnlyParams, _: SetReadOnlyResults) -> Promise<(), Error> {
+        traced(rpc_span!("AdminService", "set_read_only", self.peer), || {
+            record_username(&pry!(self.login_store.get().check_admin(&self.peer)));
+
+            let enabled = pry!(params.get()).get_enabled();
+            self.read_only.set(enabled);
+            info!(enabled, "Read-only mode changed");
+
+            Promise::ok(())
+        })
+    }
+}
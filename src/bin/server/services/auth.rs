@@ -1,39 +1,110 @@
 use std::net::SocketAddr;
 
-use broker::{auth_capnp::auth_service, util::{Handle, StoreRegistry}};
+use broker::{auth_capnp::{auth_service, presence_watcher}, util::{Handle, StoreRegistry}};
 use capnp::{capability::Promise, Error};
 use capnp_rpc::pry;
+use chrono::Utc;
+use tokio::sync::broadcast;
+use tokio_util::sync::CancellationToken;
+use tokio_util::task::TaskTracker;
+use tracing::Instrument;
 
-use crate::stores::LoginStore;
+use crate::fillers::fill_capnp_presence;
+use crate::metrics::Metrics;
+use super::traced::{record_username, rpc_span, traced};
+use crate::stores::{forward, LoginStore, NotificationStore, PresenceStore, UserPresence};
 
 pub struct AuthService {
     peer: SocketAddr,
 
     login_store: Handle<LoginStore>,
+    presence: Handle<PresenceStore>,
+    notifications: Handle<NotificationStore>,
+    metrics: Metrics,
+
+    connection: CancellationToken,
+    watcher_tasks: TaskTracker,
 }
 
 impl AuthService {
-    pub fn new(peer: SocketAddr, stores: &StoreRegistry) -> Self {
+    pub fn new(peer: SocketAddr, stores: &StoreRegistry, connection: CancellationToken, watcher_tasks: TaskTracker) -> Self {
         Self {
             peer,
             login_store: stores.get::<Handle<LoginStore>>().clone(),
+            presence: stores.get::<Handle<PresenceStore>>().clone(),
+            notifications: stores.get::<Handle<NotificationStore>>().clone(),
+            metrics: stores.get::<Metrics>().clone(),
+            connection,
+            watcher_tasks,
         }
     }
 }
 
 impl auth_service::Server for AuthService {
     fn login(&mut self, params: auth_service::LoginParams, _: auth_service::LoginResults) -> Promise<(), Error> {
-        let username = pry!(pry!(pry!(params.get()).get_username()).to_string());
+        traced(rpc_span!("AuthService", "login", self.peer), || {
+            let username = pry!(pry!(pry!(params.get()).get_username()).to_string());
+            record_username(&username);
+
+            let previous = self.login_store.get_mut()
+                .log_peer_in(self.peer, username.to_string());
+            self.metrics.logins_total.inc();
 
-        self.login_store.get_mut()
-            .log_peer_in(self.peer, username.to_string());
+            let now = Utc::now();
+            let mut presence = self.presence.get_mut();
+            if let Some(previous) = previous {
+                presence.disconnect(&previous, now);
+            }
+            presence.connect(&username, now);
+            self.notifications.get_mut().add_known_user(&username);
 
-        Promise::ok(())
+            Promise::ok(())
+        })
     }
-    fn logout(&mut self, _params: auth_service::LogoutParams<>, _: auth_service::LogoutResults<>) -> Promise<(), Error> { 
-        self.login_store.get_mut()
-            .log_peer_out(&self.peer);
+    fn logout(&mut self, _params: auth_service::LogoutParams<>, _: auth_service::LogoutResults<>) -> Promise<(), Error> {
+        traced(rpc_span!("AuthService", "logout", self.peer), || {
+            let username = self.login_store.get_mut()
+                .log_peer_out(&self.peer);
+            if let Some(username) = username {
+                self.presence.get_mut().disconnect(&username, Utc::now());
+            }
 
-        Promise::ok(())
+            Promise::ok(())
+        })
     }
-}
\ No newline at end of file
+
+    fn watch_presence(&mut self, params: auth_service::WatchPresenceParams, mut results: auth_service::WatchPresenceResults) -> Promise<(), Error> {
+        traced(rpc_span!("AuthService", "watch_presence", self.peer), || {
+            let username = pry!(self.login_store.get().check_login(&self.peer));
+            record_username(&username);
+
+            let watcher = pry!(pry!(params.get()).get_watcher());
+
+            // Subscribed under the same lock the snapshot is taken, so no change falls in between
+            let (users, changes) = {
+                let presence = self.presence.get();
+                (presence.get_all(), presence.watch())
+            };
+            self.watcher_tasks.spawn_local(
+                forward_presence_changes(changes, watcher, self.connection.clone())
+                    .instrument(tracing::info_span!("presence_watcher", peer = %self.peer))
+            );
+
+            let mut capnp_users = results.get().init_users(users.len() as u32);
+            for (index, user) in users.iter().enumerate() {
+                fill_capnp_presence(capnp_users.reborrow().get(index as u32), user);
+            }
+
+            Promise::ok(())
+        })
+    }
+}
+
+/// Sends the changes to the watcher until it is gone or `connection` is cancelled.
+async fn forward_presence_changes(changes: broadcast::Receiver<UserPresence>, watcher: presence_watcher::Client, connection: CancellationToken) {
+    forward(changes, connection, |change| {
+        let mut request = watcher.changed_request();
+        fill_capnp_presence(request.get().init_presence(), &change);
+        Some(request.send())
+    }).await
+}
@@ -1,221 +1,821 @@
 use std::net::SocketAddr;
 use std::ops::Deref;
+use std::rc::{self, Rc};
 use std::sync::{Arc, RwLockReadGuard, Weak};
-use std::time::Duration;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::time::{Duration, Instant};
 
 use broker::concurrent_list::ConcurrentListRef;
 use broker::message_capnp::reverse_message_iterator::{NextParams, NextResults, StopParams, StopResults};
-use broker::message_capnp::{message_receiver, reverse_message_iterator};
+use broker::message_capnp::{message_receiver, notification_watcher, pattern_receiver, reverse_message_iterator};
 use broker::util::{Handle, ReverseIterator, StoreRegistry};
-use broker::message_capnp::message_service::{self, DeleteMessageParams, DeleteMessageResults, GetMessagesSyncParams, GetMessagesSyncResults, PostMessageParams, SubscribeParams, SubscribeResults, UnsubscribeParams, UnsubscribeResults};
+use broker::message_capnp::message_service::{self, DeleteMessageParams, DeleteMessageResults, EditMessageParams, EditMessageResults, GetMessagesSyncParams, GetNotificationsParams, GetNotificationsResults, GetThreadParams, GetThreadResults, MarkReadParams, MarkReadResults, WatchNotificationsParams, WatchNotificationsResults, SendDirectParams, SendDirectResults, SendTypingParams, SendTypingResults, GetRevisionsParams, GetRevisionsResults, GetMessagesSyncResults, PostMessageParams, SubscribeParams, SubscribePatternParams, SubscribePatternResults, SubscribeResults, UnsubscribeParams, UnsubscribePatternParams, UnsubscribePatternResults, UnsubscribeResults};
 use broker::message_capnp::message_service::PostMessageResults;
 use capnp::{capability::Promise, Error};
 use capnp_rpc::pry;
-use chrono::Utc;
+use chrono::{DateTime, Utc};
+use tokio::sync::broadcast::{self, error::TryRecvError};
+use tracing::{warn, Instrument};
+use tokio_util::sync::CancellationToken;
+use tokio_util::task::TaskTracker;
 use uuid::Uuid;
 
-use crate::datatypes::Message;
-use crate::fillers::fill_capnp_message;
-use crate::{datatypes::Topic, stores::{CrudStore, LoginStore}};
+use crate::content_policy::ContentPolicies;
+use crate::datatypes::{message_size, Message, Notification, Revision, MAX_USERNAME_LENGTH};
+use crate::fillers::{fill_capnp_message, fill_capnp_notification, fill_capnp_timestamp};
+use crate::mentions::{excerpt, parse_mentions};
+use crate::message_filter::{MessageFilter, TopicPattern};
+use crate::metrics::Metrics;
+use super::traced::{record_outcome, record_topic, record_username, rpc_span, traced};
+use crate::{datatypes::{Topic, Username}, stores::{forward, ConversationIndex, CrudStore, LoginStore, MessageEdits, NotificationEvents, NotificationStore, PresenceStore, RateLimiter, ReadOnlyMode, SessionStore, TopicEvent, TopicEventKind, TopicEvents, TopicIndex, TopicStats, TypingNotice, TypingNotices}};
 
+/// Edits a message may have, so its history can not grow forever.
+const MAX_REVISIONS: usize = 50;
+const MAX_CONVERSATION_PARTICIPANTS: usize = 8;
+/// Typing notices of a connection repeated within this interval are dropped
+const TYPING_INTERVAL: Duration = Duration::from_secs(1);
 
 pub struct MessageService {
     peer: SocketAddr,
 
     login_store: Handle<LoginStore>,
     topic_store: Handle<CrudStore<Topic>>,
+    topic_stats: Handle<TopicStats>,
+    topic_index: Handle<TopicIndex>,
+    conversations: Handle<ConversationIndex>,
+    message_edits: MessageEdits,
+    topic_events: TopicEvents,
+    typing_notices: TypingNotices,
+    presence: Handle<PresenceStore>,
+    notifications: Handle<NotificationStore>,
+    notification_events: NotificationEvents,
+    session_store: Handle<SessionStore>,
+    read_only: ReadOnlyMode,
+    rate_limiter: Handle<RateLimiter>,
+    content_policies: Handle<ContentPolicies>,
 
     messages_reader: ConcurrentListRef<Message>,
     messages_writer: ConcurrentListRef<Message>,
     subscribers: Vec<Arc<(Uuid, message_receiver::Client)>>,
+    pattern_subscribers: Vec<Rc<(String, pattern_receiver::Client)>>,
     message_iterators: Vec<reverse_message_iterator::Client>,
+
+    shutdown: CancellationToken,
+    connection: CancellationToken,
+    subscription_tasks: TaskTracker,
+    metrics: Metrics,
+
+    /// Partition of the next unkeyed message, so this connection spreads them evenly
+    next_partition: u32,
+    /// Last typing notice sent to every topic from this connection
+    last_typing: HashMap<Uuid, Instant>,
 }
 
 impl MessageService {
-    pub fn new(peer: SocketAddr, stores: &StoreRegistry) -> Self {
+    pub fn new(peer: SocketAddr, stores: &StoreRegistry, shutdown: CancellationToken, connection: CancellationToken, subscription_tasks: TaskTracker) -> Self {
         let messages_handle = stores.get::<ConcurrentListRef<Message>>().clone();
 
         Self {
             peer,
             login_store: stores.get::<Handle<LoginStore>>().clone(),
             topic_store: stores.get::<Handle<CrudStore<Topic>>>().clone(),
+            topic_stats: stores.get::<Handle<TopicStats>>().clone(),
+            topic_index: stores.get::<Handle<TopicIndex>>().clone(),
+            conversations: stores.get::<Handle<ConversationIndex>>().clone(),
+            message_edits: stores.get::<MessageEdits>().clone(),
+            topic_events: stores.get::<TopicEvents>().clone(),
+            typing_notices: stores.get::<TypingNotices>().clone(),
+            presence: stores.get::<Handle<PresenceStore>>().clone(),
+            notifications: stores.get::<Handle<NotificationStore>>().clone(),
+            notification_events: stores.get::<NotificationEvents>().clone(),
+            session_store: stores.get::<Handle<SessionStore>>().clone(),
+            read_only: stores.get::<ReadOnlyMode>().clone(),
+            rate_limiter: stores.get::<Handle<RateLimiter>>().clone(),
+            content_policies: stores.get::<Handle<ContentPolicies>>().clone(),
 
             messages_reader: messages_handle.clone(),
             messages_writer: messages_handle,
 
             subscribers: Default::default(),
+            pattern_subscribers: Default::default(),
             message_iterators: Default::default(),
+
+            shutdown,
+            connection,
+            subscription_tasks,
+            metrics: stores.get::<Metrics>().clone(),
+            next_partition: 0,
+            last_typing: HashMap::new(),
         }
     }
-}
 
-fn sanitize_text(input: &str) -> String {
-    input.chars()
-        .filter(|&c| {
-            // Preserve tabs, spaces, and non-control characters
-            c == '\t' || c == ' ' || !c.is_control()
-        })
-        // Optional: Add this line to remove ANSI escape sequences
-        //.filter(|&c| c != '\x1B' && !('\x80'..='\x9F').contains(&c))
-        .collect()
+    /// Pushes an accepted message to the DB-like structure, where subscriptions pick it up.
+    /// Users mentioned in the message get a notification, if the server knows them and they can see the topic.
+    fn store_message(&mut self, message: Message, topic: &Topic) {
+        let topic_uuid = message.topic_uuid;
+        let notifications = {
+            let notifications = self.notifications.get();
+            parse_mentions(&message.content).into_iter()
+                .filter(|mentioned| *mentioned != message.author_name && topic.is_visible_to(mentioned) && notifications.is_known(mentioned))
+                .map(|mentioned| Notification {
+                    username: mentioned,
+                    message_uuid: message.uuid,
+                    topic_uuid,
+                    author_name: message.author_name.clone(),
+                    excerpt: excerpt(&message.content),
+                    timestamp: message.timestamp,
+                    read: false,
+                })
+                .collect::<Vec<_>>()
+        };
+
+        self.presence.get_mut().touch(&message.author_name, message.timestamp);
+        self.topic_stats.get_mut().record_message(&message);
+        self.topic_index.get_mut().touch(topic_uuid, message.timestamp);
+        self.messages_writer.push(message);
+        self.metrics.messages_posted
+            .with_label_values(&[&topic_uuid.to_string()])
+            .inc();
+
+        // Only after the message is stored, so it can be read right away
+        for notification in notifications {
+            self.notifications.get_mut().add(notification.clone());
+            self.notification_events.publish(notification);
+        }
+    }
 }
 
 impl message_service::Server for MessageService {
-    fn post_message(&mut self, params: PostMessageParams, mut results: PostMessageResults) -> Promise<(), Error> { 
-        let username = pry!(self.login_store.get().check_login(&self.peer));
-        let reader = pry!(params.get());
+    fn post_message(&mut self, params: PostMessageParams, mut results: PostMessageResults) -> Promise<(), Error> {
+        traced(rpc_span!("MessageService", "post_message", self.peer), || {
+            let username = pry!(self.login_store.get().check_login(&self.peer));
+            record_username(&username);
+            if self.shutdown.is_cancelled() {
+                return Promise::err(Error::disconnected("Server is shutting down".into()));
+            }
+            let reader = pry!(params.get());
 
-        let topic_uuid = pry!(reader.get_topic_id());
-        let topic_uuid = Uuid::from_u64_pair(topic_uuid.get_upper(), topic_uuid.get_lower());
+            let topic_uuid = pry!(reader.get_topic_id());
+            let topic_uuid = Uuid::from_u64_pair(topic_uuid.get_upper(), topic_uuid.get_lower());
+            record_topic(topic_uuid);
 
-        let content = pry!(pry!(reader.get_content()).to_str()).trim();
-        let content = sanitize_text(content);
+            if self.read_only.is_enabled() {
+                results.get().init_message().init_err().set_read_only(());
+                record_outcome("readOnly");
+                return Promise::ok(());
+            }
 
-        let key = pry!(reader.get_key());
-        let key = if key.has_t() {
-            Some(pry!(pry!(key.get_t()).to_string()))
-        } else {
-            None
-        };
+            let content = pry!(pry!(reader.get_content()).to_str());
+            let payload = pry!(reader.get_payload());
+            let content_type = pry!(pry!(reader.get_content_type()).to_str());
 
-        // Check valid content
-        if content.is_empty() {
-            results.get().init_message().init_err().set_invalid_content(());
-            return Promise::ok(());
-        }
+            let key = pry!(reader.get_key());
+            let key = if key.has_t() {
+                Some(pry!(pry!(key.get_t()).to_string()))
+            } else {
+                None
+            };
+            let headers = pry!(pry!(reader.get_headers()).iter()
+                .map(|header| Ok::<_, Error>((header.get_key()?.to_string()?, header.get_value()?.to_string()?)))
+                .collect::<Result<Vec<_>, _>>());
+            let reply_to = pry!(reader.get_reply_to());
+            let reply_to = if reply_to.has_t() {
+                let reply_to = pry!(reply_to.get_t());
+                Some(Uuid::from_u64_pair(reply_to.get_upper(), reply_to.get_lower()))
+            } else {
+                None
+            };
 
-        // Check that topic exists
-        if self.topic_store.get().get(topic_uuid).is_none() {
-            results.get().init_message().init_err().set_entity_does_not_exist(());
-            return Promise::ok(());
-        }
+            // Check that topic exists
+            let Some(topic) = self.topic_store.get().get(topic_uuid).filter(|topic| topic.is_visible_to(&username)) else {
+                results.get().init_message().init_err().set_entity_does_not_exist(());
+                record_outcome("entityDoesNotExist");
+                return Promise::ok(());
+            };
 
-        let message = Message {
-            uuid: Uuid::new_v4(),
-            topic_uuid,
-            author_name: username,
-            content: content.to_owned(),
-            timestamp: Utc::now(),
-            key,
-        };
+            if reply_to.is_some_and(|reply_to| !topic_has_message(&self.messages_reader, topic_uuid, reply_to)) {
+                results.get().init_message().init_err().set_reply_target_not_found(());
+                record_outcome("replyTargetNotFound");
+                return Promise::ok(());
+            }
 
-        // Fill message response
-        let capnp_message = results.get().init_message().init_ok();
-        fill_capnp_message(capnp_message, &message);
+            // Check valid content. Binary payloads are stored as is
+            let validated = if payload.is_empty() {
+                self.content_policies.get().validate(&topic.name, content)
+                    .map(|content| (content, String::new()))
+            } else {
+                self.content_policies.get().validate_binary(&topic.name, content, payload, content_type)
+            };
+            let validated = validated.and_then(|(content, content_type)| {
+                let headers = self.content_policies.get().validate_headers(headers)?;
+                Ok((content, content_type, headers))
+            });
+            let (content, content_type, headers) = match validated {
+                Ok(validated) => validated,
+                Err(reason) => {
+                    results.get().init_message().init_err().set_invalid_content(&reason);
+                    record_outcome("invalidContent");
+                    return Promise::ok(());
+                }
+            };
 
-        // Push the message to DB-like structure
-        self.messages_writer.push(message);
+            if let Err(retry_after) = self.rate_limiter.get_mut().check_post(self.peer, &username, topic_uuid, &topic.name, message_size(&content, payload, &headers)) {
+                results.get().init_message().init_err().set_rate_limited(retry_after.as_secs_f64());
+                record_outcome("rateLimited");
+                return Promise::ok(());
+            }
+
+            let partition = match (&key, topic.partitions) {
+                (_, 0 | 1) => 0,
+                (Some(key), partitions) => key_partition(key, partitions),
+                (None, partitions) => {
+                    self.next_partition = self.next_partition.wrapping_add(1);
+                    self.next_partition % partitions
+                }
+            };
+
+            let message = Message {
+                uuid: Uuid::new_v4(),
+                topic_uuid,
+                author_name: username,
+                content,
+                timestamp: Utc::now(),
+                key,
+                payload: payload.to_vec(),
+                content_type,
+                headers,
+                partition,
+                revisions: vec![],
+                edited_at: None,
+                reply_to,
+            };
+
+            // Fill message response
+            let capnp_message = results.get().init_message().init_ok();
+            fill_capnp_message(capnp_message, &message);
+
+            self.store_message(message, &topic);
+            Promise::ok(())
+        })
+    }
+
+    fn send_direct(&mut self, params: SendDirectParams, mut results: SendDirectResults) -> Promise<(), Error> {
+        traced(rpc_span!("MessageService", "send_direct", self.peer), || {
+            let username = pry!(self.login_store.get().check_login(&self.peer));
+            record_username(&username);
+            if self.shutdown.is_cancelled() {
+                return Promise::err(Error::disconnected("Server is shutting down".into()));
+            }
+            let reader = pry!(params.get());
 
-        Promise::ok(())
+            if self.read_only.is_enabled() {
+                results.get().init_message().init_err().set_read_only(());
+                record_outcome("readOnly");
+                return Promise::ok(());
+            }
+
+            let recipients = pry!(pry!(reader.get_recipients()).iter()
+                .map(|recipient| Ok::<_, Error>(recipient?.to_str()?.trim().to_string()))
+                .collect::<Result<Vec<_>, _>>());
+            let participants = match conversation_participants(&username, recipients) {
+                Ok(participants) => participants,
+                Err(reason) => {
+                    results.get().init_message().init_err().set_invalid_recipients(&reason);
+                    record_outcome("invalidRecipients");
+                    return Promise::ok(());
+                }
+            };
+
+            // A new conversation is only started once the message is accepted
+            let existing = self.conversations.get().get(&participants)
+                .and_then(|uuid| Some((uuid, self.topic_store.get().get(uuid)?)));
+            let (topic_uuid, topic) = existing.unwrap_or_else(|| (Uuid::new_v4(), Topic {
+                name: participants.join(", "),
+                creator: username.clone(),
+                timestamp: Utc::now(),
+                participants,
+                ..Default::default()
+            }));
+            record_topic(topic_uuid);
+
+            let content = match self.content_policies.get().validate(&topic.name, pry!(pry!(reader.get_content()).to_str())) {
+                Ok(content) => content,
+                Err(reason) => {
+                    results.get().init_message().init_err().set_invalid_content(&reason);
+                    record_outcome("invalidContent");
+                    return Promise::ok(());
+                }
+            };
+
+            // Direct messages carry neither payloads nor headers, they are still counted like any post
+            if let Err(retry_after) = self.rate_limiter.get_mut().check_post(self.peer, &username, topic_uuid, &topic.name, message_size(&content, &[], &BTreeMap::new())) {
+                results.get().init_message().init_err().set_rate_limited(retry_after.as_secs_f64());
+                record_outcome("rateLimited");
+                return Promise::ok(());
+            }
+
+            // Both participants may start the conversation at the same time, the first one wins
+            let topic_uuid = {
+                let mut conversations = self.conversations.get_mut();
+                match conversations.get(&topic.participants) {
+                    Some(uuid) => uuid,
+                    None => {
+                        conversations.insert(topic_uuid, &topic);
+                        self.topic_store.get_mut().insert(topic_uuid, topic.clone());
+                        drop(conversations);
+                        self.topic_events.publish(TopicEvent::new(topic_uuid, &topic, TopicEventKind::Created));
+                        topic_uuid
+                    }
+                }
+            };
+
+            let message = Message {
+                uuid: Uuid::new_v4(),
+                topic_uuid,
+                author_name: username,
+                content,
+                timestamp: Utc::now(),
+                ..Default::default()
+            };
+            fill_capnp_message(results.get().init_message().init_ok(), &message);
+
+            self.store_message(message, &topic);
+            Promise::ok(())
+        })
     }
     
-    fn delete_message(&mut self, _params: DeleteMessageParams, mut _results: DeleteMessageResults) -> Promise<(), Error> { 
-        let _username = pry!(self.login_store.get().check_login(&self.peer));
-        todo!("Messages deletion is not implemented yet");
-        // Promise::ok(())
+    fn edit_message(&mut self, params: EditMessageParams, mut results: EditMessageResults) -> Promise<(), Error> {
+        traced(rpc_span!("MessageService", "edit_message", self.peer), || {
+            let username = pry!(self.login_store.get().check_login(&self.peer));
+            record_username(&username);
+            let reader = pry!(params.get());
+
+            let message_uuid = pry!(reader.get_message_id());
+            let message_uuid = Uuid::from_u64_pair(message_uuid.get_upper(), message_uuid.get_lower());
+            let content = pry!(pry!(reader.get_content()).to_str());
+
+            if self.read_only.is_enabled() {
+                results.get().init_message().init_err().set_read_only(());
+                record_outcome("readOnly");
+                return Promise::ok(());
+            }
+
+            // The message is validated on a copy, its write lock is only held while the edit is applied
+            let Some((cursor, message)) = find_message(&self.messages_writer, message_uuid) else {
+                results.get().init_message().init_err().set_entity_does_not_exist(());
+                record_outcome("entityDoesNotExist");
+                return Promise::ok(());
+            };
+            record_topic(message.topic_uuid);
+
+            // Visibility goes first, so hidden messages look the same whoever wrote them
+            let Some(topic) = self.topic_store.get().get(message.topic_uuid).filter(|topic| topic.is_visible_to(&username)) else {
+                results.get().init_message().init_err().set_entity_does_not_exist(());
+                record_outcome("entityDoesNotExist");
+                return Promise::ok(());
+            };
+            if message.author_name != username {
+                results.get().init_message().init_err().set_not_author(());
+                record_outcome("notAuthor");
+                return Promise::ok(());
+            }
+
+            // Captions of binary messages may be removed, text has to stay
+            let validated = match content.trim() {
+                "" if !message.payload.is_empty() => Ok(String::new()),
+                content => self.content_policies.get().validate(&topic.name, content),
+            };
+            let content = match validated.and_then(|content| check_revisions(&message).map(|()| content)) {
+                Ok(content) => content,
+                Err(reason) => {
+                    results.get().init_message().init_err().set_invalid_content(&reason);
+                    record_outcome("invalidContent");
+                    return Promise::ok(());
+                }
+            };
+
+            if let Err(retry_after) = self.rate_limiter.get_mut().check_post(self.peer, &username, message.topic_uuid, &topic.name, message_size(&content, &message.payload, &message.headers)) {
+                results.get().init_message().init_err().set_rate_limited(retry_after.as_secs_f64());
+                record_outcome("rateLimited");
+                return Promise::ok(());
+            }
+
+            let now = Utc::now();
+            let Some(applied) = cursor.get_mut().and_then(|mut guard| guard.as_mut().map(|message| apply_edit(message, content, now))) else {
+                results.get().init_message().init_err().set_entity_does_not_exist(());
+                record_outcome("entityDoesNotExist");
+                return Promise::ok(());
+            };
+            let message = match applied {
+                Ok(Some((old_size, message))) => {
+                    self.topic_stats.get_mut().record_edit(message.topic_uuid, old_size, message.size());
+                    self.presence.get_mut().touch(&username, now);
+                    self.message_edits.publish(message.clone());
+                    message
+                }
+                Ok(None) => message,
+                Err(reason) => {
+                    results.get().init_message().init_err().set_invalid_content(&reason);
+                    record_outcome("invalidContent");
+                    return Promise::ok(());
+                }
+            };
+
+            fill_capnp_message(results.get().init_message().init_ok(), &message);
+            Promise::ok(())
+        })
+    }
+
+    fn get_revisions(&mut self, params: GetRevisionsParams, mut results: GetRevisionsResults) -> Promise<(), Error> {
+        traced(rpc_span!("MessageService", "get_revisions", self.peer), || {
+            let username = pry!(self.login_store.get().check_login(&self.peer));
+            record_username(&username);
+
+            let message_uuid = pry!(pry!(params.get()).get_message_id());
+            let message_uuid = Uuid::from_u64_pair(message_uuid.get_upper(), message_uuid.get_lower());
+
+            let Some((_, message, topic)) = find_visible_message(&self.messages_reader, &self.topic_store.get(), &username, message_uuid) else {
+                results.get().init_revisions().init_err().set_entity_does_not_exist(());
+                record_outcome("entityDoesNotExist");
+                return Promise::ok(());
+            };
+            record_topic(message.topic_uuid);
+
+            if let Err(retry_after) = self.rate_limiter.get_mut().check_read(self.peer, &username, message.topic_uuid, &topic.name) {
+                results.get().init_revisions().init_err().set_rate_limited(retry_after.as_secs_f64());
+                record_outcome("rateLimited");
+                return Promise::ok(());
+            }
+
+            let mut builder = results.get().init_revisions().initn_ok(message.revisions.len() as u32);
+            for (index, revision) in message.revisions.iter().enumerate() {
+                let mut revision_builder = builder.reborrow().get(index as u32);
+                revision_builder.set_content(&revision.content);
+                fill_capnp_timestamp(revision_builder.init_timestamp(), revision.timestamp);
+            }
+            Promise::ok(())
+        })
+    }
+
+    fn get_thread(&mut self, params: GetThreadParams, mut results: GetThreadResults) -> Promise<(), Error> {
+        traced(rpc_span!("MessageService", "get_thread", self.peer), || {
+            let username = pry!(self.login_store.get().check_login(&self.peer));
+            record_username(&username);
+
+            let root_uuid = pry!(pry!(params.get()).get_root_message_id());
+            let root_uuid = Uuid::from_u64_pair(root_uuid.get_upper(), root_uuid.get_lower());
+
+            // The replies are only collected once the caller may read the thread
+            let Some((cursor, root, topic)) = find_visible_message(&self.messages_reader, &self.topic_store.get(), &username, root_uuid) else {
+                results.get().init_messages().init_err().set_entity_does_not_exist(());
+                record_outcome("entityDoesNotExist");
+                return Promise::ok(());
+            };
+            record_topic(root.topic_uuid);
+
+            if let Err(retry_after) = self.rate_limiter.get_mut().check_read(self.peer, &username, root.topic_uuid, &topic.name) {
+                results.get().init_messages().init_err().set_rate_limited(retry_after.as_secs_f64());
+                record_outcome("rateLimited");
+                return Promise::ok(());
+            }
+
+            // Replies are always posted after the message they answer, so a single pass on from the root finds all of them
+            let mut thread = vec![root];
+            for elem in cursor {
+                let Some(message) = elem.as_ref() else { continue };
+                if message.topic_uuid == thread[0].topic_uuid
+                    && message.reply_to.is_some_and(|reply_to| thread.iter().any(|parent| parent.uuid == reply_to)) {
+                    thread.push(message.clone());
+                }
+            }
+
+            let mut builder = results.get().init_messages().initn_ok(thread.len() as u32);
+            for (index, message) in thread.iter().enumerate() {
+                fill_capnp_message(builder.reborrow().get(index as u32), message);
+            }
+            Promise::ok(())
+        })
+    }
+
+    fn send_typing(&mut self, params: SendTypingParams, mut results: SendTypingResults) -> Promise<(), Error> {
+        traced(rpc_span!("MessageService", "send_typing", self.peer), || {
+            let username = pry!(self.login_store.get().check_login(&self.peer));
+            record_username(&username);
+
+            let topic_uuid = pry!(pry!(params.get()).get_topic_id());
+            let topic_uuid = Uuid::from_u64_pair(topic_uuid.get_upper(), topic_uuid.get_lower());
+            record_topic(topic_uuid);
+
+            if self.topic_store.get().get(topic_uuid).filter(|topic| topic.is_visible_to(&username)).is_none() {
+                results.get().init_result().init_err().set_entity_does_not_exist(());
+                record_outcome("entityDoesNotExist");
+                return Promise::ok(());
+            }
+
+            // Clients may send a notice on every key press, subscribers only need one in a while
+            let now = Instant::now();
+            let is_repeated = self.last_typing.get(&topic_uuid).is_some_and(|last| now.duration_since(*last) < TYPING_INTERVAL);
+            if !is_repeated {
+                self.last_typing.insert(topic_uuid, now);
+                self.typing_notices.publish(TypingNotice { topic_uuid, username: username.clone() });
+                self.presence.get_mut().touch(&username, Utc::now());
+            }
+
+            results.get().init_result().init_ok();
+            Promise::ok(())
+        })
+    }
+
+    fn get_notifications(&mut self, params: GetNotificationsParams, mut results: GetNotificationsResults) -> Promise<(), Error> {
+        traced(rpc_span!("MessageService", "get_notifications", self.peer), || {
+            let username = pry!(self.login_store.get().check_login(&self.peer));
+            record_username(&username);
+            let reader = pry!(params.get());
+
+            let notifications = self.notifications.get().get(&username, reader.get_unread_only(), reader.get_limit() as usize);
+
+            let topic_store = self.topic_store.get();
+            let mut builder = results.get().init_notifications(notifications.len() as u32);
+            for (index, notification) in notifications.iter().enumerate() {
+                let topic_name = topic_store.get(notification.topic_uuid).map(|topic| topic.name).unwrap_or_default();
+                fill_capnp_notification(builder.reborrow().get(index as u32), notification, &topic_name);
+            }
+            Promise::ok(())
+        })
+    }
+
+    fn mark_read(&mut self, params: MarkReadParams, mut results: MarkReadResults) -> Promise<(), Error> {
+        traced(rpc_span!("MessageService", "mark_read", self.peer), || {
+            let username = pry!(self.login_store.get().check_login(&self.peer));
+            record_username(&username);
+
+            let message_uuids = pry!(pry!(params.get()).get_message_ids()).iter()
+                .map(|uuid| Uuid::from_u64_pair(uuid.get_upper(), uuid.get_lower()))
+                .collect::<Vec<_>>();
+
+            let marked = self.notifications.get_mut().mark_read(&username, &message_uuids);
+            results.get().set_marked(marked as u32);
+            Promise::ok(())
+        })
+    }
+
+    fn watch_notifications(&mut self, params: WatchNotificationsParams, _: WatchNotificationsResults) -> Promise<(), Error> {
+        traced(rpc_span!("MessageService", "watch_notifications", self.peer), || {
+            let username = pry!(self.login_store.get().check_login(&self.peer));
+            record_username(&username);
+
+            let watcher = pry!(pry!(params.get()).get_watcher());
+            let forwarding = forward_notifications(self.notification_events.watch(), watcher, username, self.topic_store.clone(), self.connection.clone());
+            self.subscription_tasks.spawn_local(forwarding.instrument(tracing::info_span!("notification_watcher", peer = %self.peer)));
+
+            Promise::ok(())
+        })
+    }
+
+    fn delete_message(&mut self, _params: DeleteMessageParams, mut _results: DeleteMessageResults) -> Promise<(), Error> {
+        traced(rpc_span!("MessageService", "delete_message", self.peer), || {
+            let username = pry!(self.login_store.get().check_login(&self.peer));
+            record_username(&username);
+            todo!("Messages deletion is not implemented yet");
+            // Promise::ok(())
+        })
     }
     
-    fn get_messages_sync(&mut self, params: GetMessagesSyncParams, mut results: GetMessagesSyncResults) -> Promise<(), Error> { 
-        let _username = pry!(self.login_store.get().check_login(&self.peer));
+    fn get_messages_sync(&mut self, params: GetMessagesSyncParams, mut results: GetMessagesSyncResults) -> Promise<(), Error> {
+        traced(rpc_span!("MessageService", "get_messages_sync", self.peer), || {
+            let username = pry!(self.login_store.get().check_login(&self.peer));
+            record_username(&username);
 
-        let topic_uuid = pry!(pry!(params.get()).get_topic_id());
-        let topic_uuid = Uuid::from_u64_pair(topic_uuid.get_upper(), topic_uuid.get_lower());
+            let topic_uuid = pry!(pry!(params.get()).get_topic_id());
+            let topic_uuid = Uuid::from_u64_pair(topic_uuid.get_upper(), topic_uuid.get_lower());
+            record_topic(topic_uuid);
         
-        // Check that topic exists
-        if self.topic_store.get().get(topic_uuid).is_none() {
-            results.get().init_messages().init_err().set_entity_does_not_exist(());
-            return Promise::ok(());
-        }
+            // Check that topic exists
+            let Some(topic) = self.topic_store.get().get(topic_uuid).filter(|topic| topic.is_visible_to(&username)) else {
+                results.get().init_messages().init_err().set_entity_does_not_exist(());
+                record_outcome("entityDoesNotExist");
+                return Promise::ok(());
+            };
+
+            if let Err(retry_after) = self.rate_limiter.get_mut().check_read(self.peer, &username, topic_uuid, &topic.name) {
+                results.get().init_messages().init_err().set_rate_limited(retry_after.as_secs_f64());
+                record_outcome("rateLimited");
+                return Promise::ok(());
+            }
 
-        // We need to know amount of messages beforehand
-        let filter_fn = |msg: &RwLockReadGuard<'_, Option<Message>>| 
-            msg.is_some() && 
-            msg.as_ref().unwrap().topic_uuid == topic_uuid;
-
-        self.messages_reader.drain_backwards();
-        let messages_count = self.messages_reader.clone().filter(filter_fn).count();
-
-        // Return all the messages
-        let mut builder = results.get().init_messages().initn_ok(messages_count as u32);
-
-        let messages = self.messages_reader.clone()
-            .filter(filter_fn)
-            .enumerate();
-        for (index, message) in messages
-        {
-            let message = message.as_ref().unwrap();
-            let capnp_message = builder.reborrow().get(index as u32);
-            fill_capnp_message(capnp_message, message);
-        } 
+            // We need to know amount of messages beforehand
+            let filter_fn = |msg: &RwLockReadGuard<'_, Option<Message>>| 
+                msg.is_some() && 
+                msg.as_ref().unwrap().topic_uuid == topic_uuid;
+
+            self.messages_reader.drain_backwards();
+            let messages_count = self.messages_reader.clone().filter(filter_fn).count();
+
+            // Return all the messages
+            let mut builder = results.get().init_messages().initn_ok(messages_count as u32);
+
+            let messages = self.messages_reader.clone()
+                .filter(filter_fn)
+                .enumerate();
+            for (index, message) in messages
+            {
+                let message = message.as_ref().unwrap();
+                let capnp_message = builder.reborrow().get(index as u32);
+                fill_capnp_message(capnp_message, message);
+            } 
         
-        Promise::ok(())
+            Promise::ok(())
+        })
     }
     
     fn subscribe(&mut self, params: SubscribeParams, mut results: SubscribeResults) -> Promise<(), Error> {
-        let _username = pry!(self.login_store.get().check_login(&self.peer));
-        let reader = pry!(params.get());
+        traced(rpc_span!("MessageService", "subscribe", self.peer), || {
+            let username = pry!(self.login_store.get().check_login(&self.peer));
+            record_username(&username);
+            if self.shutdown.is_cancelled() {
+                return Promise::err(Error::disconnected("Server is shutting down".into()));
+            }
+            let reader = pry!(params.get());
 
-        let topic_uuid = pry!(reader.get_topic_id());
-        let topic_uuid = Uuid::from_u64_pair(topic_uuid.get_upper(), topic_uuid.get_lower());
+            let topic_uuid = pry!(reader.get_topic_id());
+            let topic_uuid = Uuid::from_u64_pair(topic_uuid.get_upper(), topic_uuid.get_lower());
+            record_topic(topic_uuid);
 
-        // Check that topic exists
-        if self.topic_store.get().get(topic_uuid).is_none() {
-            results.get().init_messages().init_err().set_entity_does_not_exist(());
-            return Promise::ok(());
-        }
+            // Check that topic exists
+            let Some(topic) = self.topic_store.get().get(topic_uuid).filter(|topic| topic.is_visible_to(&username)) else {
+                results.get().init_messages().init_err().set_entity_does_not_exist(());
+                record_outcome("entityDoesNotExist");
+                return Promise::ok(());
+            };
+
+            let filter = pry!(reader.get_filter());
+            let filter = if filter.has_t() {
+                match pry!(MessageFilter::read_capnp(pry!(filter.get_t()))) {
+                    Ok(filter) => filter,
+                    Err(reason) => {
+                        results.get().init_messages().init_err().set_invalid_filter(&reason);
+                        record_outcome("invalidFilter");
+                        return Promise::ok(());
+                    }
+                }
+            } else {
+                MessageFilter::default()
+            };
 
-        let mut reader_handle = self.messages_reader.clone();
-        reader_handle.drain_forward();
-        // Create an Arc-Weak pair of message receiver
-        {
-            let receiver_arc=  Arc::new((topic_uuid, pry!(reader.get_receiver())));
-            let receiver_weak = Arc::downgrade(&receiver_arc);
+            if let Err(retry_after) = self.rate_limiter.get_mut().check_read(self.peer, &username, topic_uuid, &topic.name) {
+                results.get().init_messages().init_err().set_rate_limited(retry_after.as_secs_f64());
+                record_outcome("rateLimited");
+                return Promise::ok(());
+            }
+
+            let mut reader_handle = self.messages_reader.clone();
+            reader_handle.drain_forward();
+            // Create an Arc-Weak pair of message receiver
+            {
+                let receiver_arc=  Arc::new((topic_uuid, pry!(reader.get_receiver())));
+                let receiver_weak = Arc::downgrade(&receiver_arc);
             
-            self.subscribers.push(receiver_arc); // `self` owns an Arc, task owns a Weak. This way, task will stop itself, `self` is dropped.
+                self.subscribers.push(receiver_arc); // `self` owns an Arc, task owns a Weak. This way, task will stop itself, `self` is dropped.
 
-            tokio::task::spawn_local(spin_on_messages(reader_handle.clone(), receiver_weak));
-        }
+                let typing = forward_typing(self.typing_notices.watch(), receiver_weak.clone(), username.clone(), self.connection.clone());
+                self.subscription_tasks.spawn_local(typing.instrument(tracing::info_span!("typing", topic = %topic_uuid)));
+
+                let delivery = spin_on_messages(reader_handle.clone(), self.message_edits.watch(), receiver_weak, filter.clone(), self.shutdown.clone(), self.metrics.clone());
+                self.subscription_tasks.spawn_local(delivery.instrument(tracing::info_span!("subscription", topic = %topic_uuid)));
+                self.session_store.get_mut().add_subscription(&self.peer, topic_uuid);
+            }
 
-        // Create a message iterator (for user to request messages history.)
-        {
-            let message_iterator = ReverseMessageIterator::new(reader_handle, topic_uuid);
-            let message_iterator: reverse_message_iterator::Client = capnp_rpc::new_client(message_iterator);
-            self.message_iterators.push(message_iterator.clone()); 
+            // Create a message iterator (for user to request messages history.)
+            {
+                let message_iterator = ReverseMessageIterator::new(self.peer, username, reader_handle, topic_uuid, filter, self.topic_store.clone(), self.rate_limiter.clone());
+                let message_iterator: reverse_message_iterator::Client = capnp_rpc::new_client(message_iterator);
+                self.message_iterators.push(message_iterator.clone()); 
 
-            // Send the iterator to the client 
-            pry!(results.get().init_messages().set_ok(message_iterator));
-        }
+                // Send the iterator to the client 
+                pry!(results.get().init_messages().set_ok(message_iterator));
+            }
 
-        Promise::ok(())
+            Promise::ok(())
+        })
     }
     
     fn unsubscribe(&mut self, params: UnsubscribeParams, mut _results: UnsubscribeResults) ->  Promise<(), Error>{
-        let _username = pry!(self.login_store.get().check_login(&self.peer));
-        let reader = pry!(params.get());
+        traced(rpc_span!("MessageService", "unsubscribe", self.peer), || {
+            let username = pry!(self.login_store.get().check_login(&self.peer));
+            record_username(&username);
+            let reader = pry!(params.get());
 
-        let topic_uuid = pry!(reader.get_topic_id());
-        let topic_uuid = Uuid::from_u64_pair(topic_uuid.get_upper(), topic_uuid.get_lower());
-        let _receiver = pry!(reader.get_receiver());
+            let topic_uuid = pry!(reader.get_topic_id());
+            let topic_uuid = Uuid::from_u64_pair(topic_uuid.get_upper(), topic_uuid.get_lower());
+            record_topic(topic_uuid);
+            let _receiver = pry!(reader.get_receiver());
 
-        let indices = self.subscribers.iter().enumerate()
-            .filter(|(_, elem)| elem.0 == topic_uuid)
-            .map(|(i, _)| i)
-            .collect::<Vec<_>>();
+            let indices = self.subscribers.iter().enumerate()
+                .filter(|(_, elem)| elem.0 == topic_uuid)
+                .map(|(i, _)| i)
+                .collect::<Vec<_>>();
 
-        for idx in indices.into_iter().rev() {
-            self.subscribers.remove(idx);
-        }
+            for idx in indices.into_iter().rev() {
+                self.subscribers.remove(idx);
+            }
+            self.session_store.get_mut().remove_subscriptions(&self.peer, topic_uuid);
 
-        Promise::ok(())
+            Promise::ok(())
+        })
     }
+
+    fn subscribe_pattern(&mut self, params: SubscribePatternParams, mut results: SubscribePatternResults) -> Promise<(), Error> {
+        traced(rpc_span!("MessageService", "subscribe_pattern", self.peer), || {
+            let username = pry!(self.login_store.get().check_login(&self.peer));
+            record_username(&username);
+            if self.shutdown.is_cancelled() {
+                return Promise::err(Error::disconnected("Server is shutting down".into()));
+            }
+            let reader = pry!(params.get());
+
+            let pattern_str = pry!(pry!(reader.get_pattern()).to_string());
+            let pattern = match TopicPattern::parse(&pattern_str) {
+                Ok(pattern) => pattern,
+                Err(reason) => {
+                    results.get().init_result().init_err().set_invalid_filter(&reason);
+                    record_outcome("invalidFilter");
+                    return Promise::ok(());
+                }
+            };
+
+            let filter = pry!(reader.get_filter());
+            let filter = if filter.has_t() {
+                match pry!(MessageFilter::read_capnp(pry!(filter.get_t()))) {
+                    Ok(filter) => filter,
+                    Err(reason) => {
+                        results.get().init_result().init_err().set_invalid_filter(&reason);
+                        record_outcome("invalidFilter");
+                        return Promise::ok(());
+                    }
+                }
+            } else {
+                MessageFilter::default()
+            };
+
+            if let Err(retry_after) = self.rate_limiter.get_mut().check_global_read(self.peer, &username) {
+                results.get().init_result().init_err().set_rate_limited(retry_after.as_secs_f64());
+                record_outcome("rateLimited");
+                return Promise::ok(());
+            }
+
+            let mut reader_handle = self.messages_reader.clone();
+            reader_handle.drain_forward();
+
+            let receiver_rc = Rc::new((pattern_str, pry!(reader.get_receiver())));
+            let receiver_weak = Rc::downgrade(&receiver_rc);
+            self.session_store.get_mut().add_pattern_subscription(&self.peer, &receiver_rc.0);
+            self.pattern_subscribers.push(receiver_rc);
+
+            let reads = TopicReads { peer: self.peer, username, rate_limiter: self.rate_limiter.clone(), charged: HashSet::new() };
+            let delivery = spin_on_pattern(reader_handle, self.message_edits.watch(), receiver_weak, pattern, filter, self.topic_store.clone(), reads, self.shutdown.clone(), self.metrics.clone());
+            self.subscription_tasks.spawn_local(delivery.instrument(tracing::info_span!("pattern_subscription")));
+
+            results.get().init_result().init_ok();
+            Promise::ok(())
+        })
+    }
+
+    fn unsubscribe_pattern(&mut self, params: UnsubscribePatternParams, _: UnsubscribePatternResults) -> Promise<(), Error> {
+        traced(rpc_span!("MessageService", "unsubscribe_pattern", self.peer), || {
+            let username = pry!(self.login_store.get().check_login(&self.peer));
+            record_username(&username);
+
+            let pattern = pry!(pry!(pry!(params.get()).get_pattern()).to_str());
+            self.pattern_subscribers.retain(|subscriber| subscriber.0 != pattern);
+            self.session_store.get_mut().remove_pattern_subscriptions(&self.peer, pattern);
+
+            Promise::ok(())
+        })
+    }
+}
+
+/// FNV-1a of the key, so keys map to the same partitions across restarts and versions.
+fn key_partition(key: &str, partitions: u32) -> u32 {
+    let hash = key.bytes().fold(0xcbf29ce484222325_u64, |hash, byte| (hash ^ byte as u64).wrapping_mul(0x100000001b3));
+    (hash % partitions as u64) as u32
 }
 
-async fn spin_on_messages(mut messages_reader: ConcurrentListRef<Message>, uuid_receiver: Weak<(Uuid, message_receiver::Client)>) {
+/// Delivers new messages of the topic that match the filter to the receiver until it is unsubscribed.
+/// After `shutdown` is cancelled, delivers the remaining messages once and stops.
+async fn spin_on_messages(mut messages_reader: ConcurrentListRef<Message>, mut edits: broadcast::Receiver<Message>, uuid_receiver: Weak<(Uuid, message_receiver::Client)>, filter: MessageFilter, shutdown: CancellationToken, metrics: Metrics) {
+    let subscribers = match uuid_receiver.upgrade() {
+        Some(arc) => metrics.subscribers.with_label_values(&[&arc.0.to_string()]),
+        None => return,
+    };
+    subscribers.inc();
+
     'main: loop {
         let (topic_uuid, receiver) = match uuid_receiver.upgrade() {
             Some(arc) => (arc.0, (&arc.1).clone()),
             None => break,
         };
+        // Checked before draining, so messages posted right before the shutdown are still delivered
+        let is_last_pass = shutdown.is_cancelled();
 
         while let Some(next) = messages_reader.next() {
             let message = match next.deref() {
@@ -223,60 +823,404 @@ async fn spin_on_messages(mut messages_reader: ConcurrentListRef<Message>, uuid_
                 Some(x) => x,
             };
 
-            if message.topic_uuid != topic_uuid {
+            if message.topic_uuid != topic_uuid || !filter.matches(message) {
                 continue;
             }
 
             let mut request = receiver.receive_request();
             fill_capnp_message(request.get().init_message(), message);
+            let timestamp = message.timestamp;
+            // Released before waiting for the peer, so edits of the message do not wait for it
+            drop(next);
+            if request.send().await.is_err() {
+                break 'main;
+            }
+
+            let latency = (Utc::now() - timestamp).to_std().unwrap_or_default();
+            metrics.delivery_latency.observe(latency.as_secs_f64());
+        }
+
+        while let Some(edited) = next_edit(&mut edits) {
+            if edited.topic_uuid != topic_uuid || !filter.matches(&edited) {
+                continue;
+            }
+
+            let mut request = receiver.edited_request();
+            fill_capnp_message(request.get().init_message(), &edited);
             if request.send().await.is_err() {
                 break 'main;
             }
         }
+        if is_last_pass {
+            break;
+        }
         // TODO
         tokio::time::sleep(Duration::from_millis(10)).await;
     }
+
+    subscribers.dec();
+}
+
+/// Delivers new messages of all the topics matching the pattern, along with the current name of their topic.
+/// Names are looked up for every message, so renamed topics start or stop matching right away.
+/// The first delivery from every topic waits for the read limits of that topic.
+#[allow(clippy::too_many_arguments)]
+async fn spin_on_pattern(
+    mut messages_reader: ConcurrentListRef<Message>,
+    mut edits: broadcast::Receiver<Message>,
+    pattern_receiver: rc::Weak<(String, pattern_receiver::Client)>,
+    pattern: TopicPattern,
+    filter: MessageFilter,
+    topic_store: Handle<CrudStore<Topic>>,
+    mut reads: TopicReads,
+    shutdown: CancellationToken,
+    metrics: Metrics,
+) {
+    metrics.pattern_subscribers.inc();
+
+    'main: loop {
+        let receiver = match pattern_receiver.upgrade() {
+            Some(arc) => arc.1.clone(),
+            None => break,
+        };
+        let is_last_pass = shutdown.is_cancelled();
+
+        while let Some((message, topic)) = next_pattern_delivery(&mut messages_reader, &pattern, &filter, &topic_store) {
+            reads.charge(message.topic_uuid, &topic.name, &shutdown).await;
+
+            let mut request = receiver.receive_request();
+            fill_capnp_message(request.get().init_message(), &message);
+            request.get().set_topic_name(&topic.name);
+            if request.send().await.is_err() {
+                break 'main;
+            }
+
+            let latency = (Utc::now() - message.timestamp).to_std().unwrap_or_default();
+            metrics.delivery_latency.observe(latency.as_secs_f64());
+        }
+
+        while let Some(edited) = next_edit(&mut edits) {
+            let Some(topic) = topic_store.get().get(edited.topic_uuid).filter(|topic| topic.participants.is_empty()) else {
+                continue;
+            };
+            if !pattern.matches(&topic.name) || !filter.matches(&edited) {
+                continue;
+            }
+            reads.charge(edited.topic_uuid, &topic.name, &shutdown).await;
+
+            let mut request = receiver.edited_request();
+            fill_capnp_message(request.get().init_message(), &edited);
+            request.get().set_topic_name(&topic.name);
+            if request.send().await.is_err() {
+                break 'main;
+            }
+        }
+        if is_last_pass {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+
+    metrics.pattern_subscribers.dec();
+}
+
+/// Copy of the next message a pattern subscription delivers, along with its topic, or `None` once the reader is drained.
+/// Not async, so the read lock of the message is released before the delivery is awaited.
+fn next_pattern_delivery(messages_reader: &mut ConcurrentListRef<Message>, pattern: &TopicPattern, filter: &MessageFilter, topic_store: &Handle<CrudStore<Topic>>) -> Option<(Message, Topic)> {
+    for next in messages_reader.by_ref() {
+        let message = next.as_ref()?;
+
+        // Messages of deleted topics and of direct conversations are not delivered
+        let Some(topic) = topic_store.get().get(message.topic_uuid).filter(|topic| topic.participants.is_empty()) else {
+            continue;
+        };
+        if pattern.matches(&topic.name) && filter.matches(message) {
+            return Some((message.clone(), topic));
+        }
+    }
+    None
+}
+
+/// Read limits of the topics a pattern subscription delivers from. Each topic takes one read, like subscribing to it would.
+struct TopicReads {
+    peer: SocketAddr,
+    username: Username,
+    rate_limiter: Handle<RateLimiter>,
+    charged: HashSet<Uuid>,
+}
+
+impl TopicReads {
+    /// Waits until the topic fits into its read limits, the first time it is delivered from.
+    /// Stops waiting once `shutdown` is cancelled, so the remaining messages are still delivered.
+    async fn charge(&mut self, topic_uuid: Uuid, topic_name: &str, shutdown: &CancellationToken) {
+        if self.charged.contains(&topic_uuid) {
+            return;
+        }
+
+        loop {
+            let checked = self.rate_limiter.get_mut().check_topic_read(self.peer, &self.username, topic_uuid, topic_name);
+            let Err(retry_after) = checked else { break };
+            tokio::select! {
+                _ = tokio::time::sleep(retry_after) => {}
+                _ = shutdown.cancelled() => break,
+            }
+        }
+        self.charged.insert(topic_uuid);
+    }
+}
+
+/// Sends notifications of the user to the watcher until it is gone or `connection` is cancelled.
+/// Notifications missed by a lagging watcher are still stored, the user finds them with `getNotifications`.
+async fn forward_notifications(notifications: broadcast::Receiver<Notification>, watcher: notification_watcher::Client, username: Username, topic_store: Handle<CrudStore<Topic>>, connection: CancellationToken) {
+    forward(notifications, connection, |notification| {
+        if notification.username != username {
+            return None;
+        }
+
+        let topic_name = topic_store.get().get(notification.topic_uuid).map(|topic| topic.name).unwrap_or_default();
+        let mut request = watcher.notified_request();
+        fill_capnp_notification(request.get().init_notification(), &notification, &topic_name);
+        Some(request.send())
+    }).await
+}
+
+/// Sends typing notices of the topic to the subscriber until it unsubscribes or `connection` is cancelled.
+/// Notices of the subscribed user are not sent back.
+async fn forward_typing(notices: broadcast::Receiver<TypingNotice>, uuid_receiver: Weak<(Uuid, message_receiver::Client)>, username: Username, connection: CancellationToken) {
+    forward(notices, connection, |notice| {
+        let Some(arc) = uuid_receiver.upgrade() else {
+            // Stops the forwarding once the topic is unsubscribed
+            return Some(Promise::err(Error::disconnected("Unsubscribed".to_owned())));
+        };
+        let (topic_uuid, receiver) = (arc.0, &arc.1);
+        if notice.topic_uuid != topic_uuid || notice.username == username {
+            return None;
+        }
+
+        let mut request = receiver.typing_request();
+        request.get().set_username(&notice.username);
+        Some(request.send())
+    }).await
+}
+
+/// Next edit that is already published, if any. Edits missed by a lagging subscription are skipped.
+fn next_edit(edits: &mut broadcast::Receiver<Message>) -> Option<Message> {
+    loop {
+        match edits.try_recv() {
+            Ok(edited) => return Some(edited),
+            Err(TryRecvError::Lagged(skipped)) => warn!(skipped, "Subscription missed message edits"),
+            Err(TryRecvError::Empty | TryRecvError::Closed) => return None,
+        }
+    }
+}
+
+/// Sorted, unique usernames of a conversation between the sender and the recipients, or the reason to reject them.
+fn conversation_participants(sender: &str, recipients: Vec<Username>) -> Result<Vec<Username>, String> {
+    if let Some(invalid) = recipients.iter().find(|recipient| recipient.is_empty() || recipient.len() > MAX_USERNAME_LENGTH || recipient.chars().any(char::is_control)) {
+        return Err(format!("Usernames must be 1 to {MAX_USERNAME_LENGTH} bytes long, got '{}'", invalid.escape_debug()));
+    }
+
+    let mut participants = recipients;
+    participants.push(sender.to_string());
+    participants.sort();
+    participants.dedup();
+
+    match participants.len() {
+        0 | 1 => Err("Direct messages need a recipient other than the sender".to_string()),
+        count if count > MAX_CONVERSATION_PARTICIPANTS => Err(format!("Conversations may have at most {MAX_CONVERSATION_PARTICIPANTS} participants")),
+        _ => Ok(participants),
+    }
+}
+
+/// Whether the topic has the message, searched from the newest one, since replies are mostly about recent messages.
+fn topic_has_message(messages: &ConcurrentListRef<Message>, topic_uuid: Uuid, uuid: Uuid) -> bool {
+    let mut cursor = messages.clone();
+    cursor.drain_forward();
+    ReverseIterator::from(&mut cursor)
+        .any(|elem| elem.as_ref().is_some_and(|message| message.uuid == uuid && message.topic_uuid == topic_uuid))
+}
+
+/// Copy of the message, searched from the newest one, since edits are mostly about recent messages.
+/// The returned cursor stays on the message, so its write lock can be taken later without searching again.
+fn find_message(messages: &ConcurrentListRef<Message>, uuid: Uuid) -> Option<(ConcurrentListRef<Message>, Message)> {
+    let mut cursor = messages.clone();
+    cursor.drain_forward();
+
+    while let Some(elem) = cursor.next_back() {
+        let found = elem.as_ref().filter(|message| message.uuid == uuid).cloned();
+        drop(elem);
+        if let Some(message) = found {
+            return Some((cursor, message));
+        }
+    }
+    None
+}
+
+fn check_revisions(message: &Message) -> Result<(), String> {
+    match message.revisions.len() {
+        MAX_REVISIONS.. => Err(format!("Messages can be edited at most {MAX_REVISIONS} times")),
+        _ => Ok(()),
+    }
+}
+
+/// Same as [`find_message`], along with the topic, if the user can see the topic. Messages of deleted topics are hidden from everybody.
+fn find_visible_message(messages: &ConcurrentListRef<Message>, topics: &CrudStore<Topic>, username: &str, uuid: Uuid) -> Option<(ConcurrentListRef<Message>, Message, Topic)> {
+    let (cursor, message) = find_message(messages, uuid)?;
+    let topic = topics.get(message.topic_uuid).filter(|topic| topic.is_visible_to(username))?;
+    Some((cursor, message, topic))
+}
+
+/// Replaces the content and keeps the previous one as a revision. Checked again under the write lock,
+/// since another edit may have landed after the copy was validated.
+/// Returns the size before the edit and the edited message, or `None` when the content does not change.
+fn apply_edit(message: &mut Message, content: String, now: DateTime<Utc>) -> Result<Option<(usize, Message)>, String> {
+    check_revisions(message)?;
+    // Editing to the same content changes nothing
+    if content == message.content {
+        return Ok(None);
+    }
+
+    let old_size = message.size();
+    let revision = Revision {
+        content: std::mem::replace(&mut message.content, content),
+        timestamp: message.edited_at.unwrap_or(message.timestamp),
+    };
+    message.revisions.push(revision);
+    message.edited_at = Some(now);
+    Ok(Some((old_size, message.clone())))
 }
 
 struct ReverseMessageIterator {
+    peer: SocketAddr,
+    username: Username,
     messages_reader: Option<ConcurrentListRef<Message>>,
     topic_uuid: Uuid,
+    filter: MessageFilter,
+
+    topic_store: Handle<CrudStore<Topic>>,
+    rate_limiter: Handle<RateLimiter>,
 }
 
 impl ReverseMessageIterator {
-    pub fn new(handle: ConcurrentListRef<Message>, topic_uuid: Uuid) -> Self {
+    pub fn new(peer: SocketAddr, username: Username, handle: ConcurrentListRef<Message>, topic_uuid: Uuid, filter: MessageFilter, topic_store: Handle<CrudStore<Topic>>, rate_limiter: Handle<RateLimiter>) -> Self {
         Self {
+            peer,
+            username,
             messages_reader: Some(handle),
-            topic_uuid
+            topic_uuid,
+            filter,
+            topic_store,
+            rate_limiter,
         }
     }
 }
 
 impl reverse_message_iterator::Server for ReverseMessageIterator {
     fn next(&mut self, params: NextParams, mut results: NextResults) -> Promise<(), Error> {
-        let count = pry!(params.get()).get_count();
+        traced(rpc_span!("ReverseMessageIterator", "next", self.peer), || {
+            record_topic(self.topic_uuid);
+            let count = pry!(params.get()).get_count();
+
+            if self.messages_reader.is_none() {
+                return Promise::err(Error::failed("Iterator was stopped".into()))
+            }
+
+            // Every page is a history call, so paging through a topic is limited like subscribing to it
+            let Some(topic) = self.topic_store.get().get(self.topic_uuid) else {
+                results.get().init_messages().init_err().set_entity_does_not_exist(());
+                record_outcome("entityDoesNotExist");
+                return Promise::ok(());
+            };
+            if let Err(retry_after) = self.rate_limiter.get_mut().check_read(self.peer, &self.username, self.topic_uuid, &topic.name) {
+                results.get().init_messages().init_err().set_rate_limited(retry_after.as_secs_f64());
+                record_outcome("rateLimited");
+                return Promise::ok(());
+            }
+            let reader = self.messages_reader.as_mut().unwrap();
+
+            let reader = ReverseIterator::from(reader);
+            let messages = reader
+                .filter_map(|guard| guard.as_ref().cloned())
+                .filter(|message| message.topic_uuid == self.topic_uuid && self.filter.matches(message))
+                .take(count as usize)
+                .collect::<Vec<_>>();
 
-        if self.messages_reader.is_none() {
-            return Promise::err(Error::failed("Iterator was stopped".into()))
+            let mut capnp_messages = results.get().init_messages().initn_ok(messages.len() as u32);
+            for (i, message) in messages.into_iter().enumerate() {
+                fill_capnp_message(capnp_messages.reborrow().get(i as u32), &message);
+            }
+
+            Promise::ok(())
+        })
+    }
+    fn stop(&mut self, _params: StopParams, _results: StopResults) -> Promise<(), Error> {
+        traced(rpc_span!("ReverseMessageIterator", "stop", self.peer), || {
+            self.messages_reader = None;
+            Promise::ok(())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use broker::concurrent_list::ConcurrentList;
+
+    use super::*;
+
+    #[test]
+    fn edits_messages_several_chunks_back() {
+        // Chunks of two messages, so the first message is three chunks behind the newest one
+        let list = ConcurrentList::new(2);
+        let mut writer = list.reference();
+        let messages = (0..7).map(|index| Message { uuid: Uuid::new_v4(), content: format!("message {index}"), ..Default::default() }).collect::<Vec<_>>();
+        for message in &messages {
+            writer.push(message.clone());
         }
-        let reader = self.messages_reader.as_mut().unwrap();
-
-        let reader = ReverseIterator::from(reader);
-        let messages = reader
-            .filter_map(|guard| guard.as_ref().cloned())
-            .filter(|message| message.topic_uuid == self.topic_uuid)
-            .take(count as usize)
-            .collect::<Vec<_>>();
-
-        let mut capnp_messages = results.get().init_messages(messages.len() as u32);
-        for (i, message) in messages.into_iter().enumerate() {
-            fill_capnp_message(capnp_messages.reborrow().get(i as u32), &message);
+
+        let (cursor, found) = find_message(&list.reference(), messages[0].uuid).unwrap();
+        assert_eq!(found.content, "message 0");
+
+        let edited = cursor.get_mut().and_then(|mut guard| guard.as_mut().map(|message| apply_edit(message, "edited".to_string(), Utc::now())));
+        let (_, edited) = edited.unwrap().unwrap().unwrap();
+        assert_eq!(edited.uuid, messages[0].uuid);
+        assert_eq!(edited.revisions[0].content, "message 0");
+
+        // Only the first message changed
+        let (_, found) = find_message(&list.reference(), messages[0].uuid).unwrap();
+        assert_eq!(found.content, "edited");
+        let (_, found) = find_message(&list.reference(), messages[2].uuid).unwrap();
+        assert_eq!(found.content, "message 2");
+        assert!(topic_has_message(&list.reference(), Uuid::nil(), messages[1].uuid));
+    }
+
+    #[test]
+    fn found_messages_are_followed_by_newer_ones() {
+        let list = ConcurrentList::new(2);
+        let mut writer = list.reference();
+        let messages = (0..5).map(|index| Message { uuid: Uuid::new_v4(), content: format!("message {index}"), ..Default::default() }).collect::<Vec<_>>();
+        for message in &messages {
+            writer.push(message.clone());
         }
 
-        Promise::ok(())
+        // Threads collect the replies by reading on from the root
+        let (cursor, _) = find_message(&list.reference(), messages[1].uuid).unwrap();
+        let newer = cursor.filter_map(|elem| elem.as_ref().map(|message| message.content.clone())).collect::<Vec<_>>();
+        assert_eq!(newer, ["message 2", "message 3", "message 4"]);
     }
-    fn stop(&mut self, _params: StopParams, _results: StopResults) -> Promise<(), Error> {
-        self.messages_reader = None;
-        Promise::ok(())
+
+    #[test]
+    fn hides_messages_of_deleted_conversations() {
+        let mut topics = CrudStore::default();
+        let topic_uuid = topics.create(Topic { participants: vec!["alice".to_string(), "bob".to_string()], ..Default::default() });
+        let list = ConcurrentList::new(4);
+        let message = Message { uuid: Uuid::new_v4(), topic_uuid, author_name: "alice".to_string(), ..Default::default() };
+        list.reference().push(message.clone());
+
+        assert!(find_visible_message(&list.reference(), &topics, "bob", message.uuid).is_some());
+        assert!(find_visible_message(&list.reference(), &topics, "carol", message.uuid).is_none());
+
+        topics.remove(topic_uuid);
+        assert!(find_visible_message(&list.reference(), &topics, "alice", message.uuid).is_none());
+        assert!(find_visible_message(&list.reference(), &topics, "carol", message.uuid).is_none());
     }
-}
\ No newline at end of file
+}
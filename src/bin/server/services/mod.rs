@@ -1,8 +1,11 @@
+mod admin;
 mod auth;
 mod root;
 mod topic;
 mod message;
+mod traced;
 
+pub use admin::AdminService;
 pub use auth::AuthService;
 pub use root::RootService;
 pub use topic::TopicService;
@@ -1,17 +1,33 @@
+use std::cell::RefCell;
+use std::net::SocketAddr;
+use std::rc::Rc;
+
 use broker::message_capnp::message_service;
 use broker::topic_capnp::topic_service;
 use broker::auth_capnp::auth_service;
-use broker::main_capnp::root_service::{MessageParams, MessageResults, TopicParams, TopicResults};
+use broker::main_capnp::root_service::{AdminParams, AdminResults, MessageParams, MessageResults, PingParams, PingResults, SetListenerParams, SetListenerResults, TopicParams, TopicResults};
+use broker::main_capnp::{admin_service, session_listener};
+use broker::util::Handle;
 use capnp::{capability::Promise, Error};
+use capnp_rpc::pry;
 
 use broker::main_capnp::root_service;
 
 use root_service::{AuthParams, AuthResults};
 
+use crate::stores::LoginStore;
+
 pub struct RootService {
+    pub peer: SocketAddr,
+    pub login_store: Handle<LoginStore>,
+
     pub auth: auth_service::Client,
     pub topic: topic_service::Client,
     pub message: message_service::Client,
+    pub admin: admin_service::Client,
+
+    /// Session-level callbacks of the peer. Shared with the connection task, which notifies it on shutdown.
+    pub listener: Rc<RefCell<Option<session_listener::Client>>>,
 }
 
 impl root_service::Server for RootService {
@@ -29,4 +45,21 @@ impl root_service::Server for RootService {
         results.get().set_service(self.message.clone());
         Promise::ok(())
     }
+
+    fn set_listener(&mut self, params: SetListenerParams, _: SetListenerResults) -> Promise<(), Error> {
+        let listener = pry!(pry!(params.get()).get_listener());
+        *self.listener.borrow_mut() = Some(listener);
+        Promise::ok(())
+    }
+
+    fn ping(&mut self, _: PingParams, _: PingResults) -> Promise<(), Error> {
+        Promise::ok(())
+    }
+
+    fn admin(&mut self, params: AdminParams, mut results: AdminResults) -> Promise<(), Error> {
+        let token = pry!(pry!(pry!(params.get()).get_token()).to_str());
+        pry!(self.login_store.get_mut().grant_admin(self.peer, token));
+        results.get().set_service(self.admin.clone());
+        Promise::ok(())
+    }
 }
\ No newline at end of file
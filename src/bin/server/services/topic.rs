@@ -1,12 +1,24 @@
 use std::net::SocketAddr;
 
-use broker::{topic_capnp::topic_service::{CreateTopicParams, CreateTopicResults, DeleteTopicParams, DeleteTopicResults, GetAllTopicsParams, GetAllTopicsResults, GetTopicParams, GetTopicResults, UpdateTopicParams, UpdateTopicResults}, util::{Handle, StoreRegistry}};
-use broker::topic_capnp::topic_service;
+use broker::{topic_capnp::topic_service::{CreateTopicParams, CreateTopicResults, DeleteTopicParams, DeleteTopicResults, GetAllTopicsParams, GetAllTopicsResults, GetSubscribersParams, GetSubscribersResults, GetTopicParams, GetTopicResults, GetTopicStatsParams, GetTopicStatsResults, ListConversationsParams, ListConversationsResults, ListTopicsParams, ListTopicsResults, PinMessageParams, PinMessageResults, UnpinMessageParams, UnpinMessageResults, UpdateTopicMetadataParams, UpdateTopicMetadataResults, UpdateTopicParams, UpdateTopicResults, WatchTopicsParams, WatchTopicsResults}, util::{Handle, StoreRegistry}};
+use broker::concurrent_list::ConcurrentListRef;
+use broker::topic_capnp::{topic_service, topic_watcher};
 use capnp::{capability::Promise, Error};
 use capnp_rpc::pry;
 use chrono::{Duration, Utc};
+use tokio::sync::broadcast;
+use tokio_util::sync::CancellationToken;
+use tokio_util::task::TaskTracker;
+use tracing::Instrument;
 
-use crate::{datatypes::Topic, fillers::fill_capnp_topic, stores::{CrudStore, LoginStore}};
+use crate::{datatypes::{Message, Topic, Username}, fillers::{fill_capnp_presence, fill_capnp_timestamp, fill_capnp_topic}, stores::{forward, ConversationIndex, CrudStore, LoginStore, PresenceStore, ReadOnlyMode, SessionStore, TopicEvent, TopicEventKind, TopicEvents, TopicIndex, TopicStats}, message_filter::TopicPattern, topic_query::{matches_search, TopicQuery}};
+use super::traced::{record_outcome, record_topic, record_username, rpc_span, traced};
+
+const MAX_PARTITIONS: u32 = 1024;
+const MAX_DESCRIPTION_LENGTH: usize = 1024;
+const MAX_TAGS: usize = 16;
+const MAX_TAG_LENGTH: usize = 64;
+const MAX_PINNED_MESSAGES: usize = 10;
 
 
 pub struct TopicService {
@@ -14,142 +26,583 @@ pub struct TopicService {
 
     login_store: Handle<LoginStore>,
     topic_store: Handle<CrudStore<Topic>>,
+    read_only: ReadOnlyMode,
+    topic_events: TopicEvents,
+    topic_stats: Handle<TopicStats>,
+    topic_index: Handle<TopicIndex>,
+    conversations: Handle<ConversationIndex>,
+    session_store: Handle<SessionStore>,
+    presence: Handle<PresenceStore>,
+    messages: ConcurrentListRef<Message>,
+
+    /// Cancelled once the peer is gone, stops its topic watchers
+    connection: CancellationToken,
+    watcher_tasks: TaskTracker,
 }
 
 impl TopicService {
-    pub fn new(peer: SocketAddr, stores: &StoreRegistry) -> Self {
+    pub fn new(peer: SocketAddr, stores: &StoreRegistry, connection: CancellationToken, watcher_tasks: TaskTracker) -> Self {
 
         Self {
             peer,
             login_store: stores.get::<Handle<LoginStore>>().clone(),
             topic_store: stores.get::<Handle<CrudStore<Topic>>>().clone(),
+            read_only: stores.get::<ReadOnlyMode>().clone(),
+            topic_events: stores.get::<TopicEvents>().clone(),
+            topic_stats: stores.get::<Handle<TopicStats>>().clone(),
+            topic_index: stores.get::<Handle<TopicIndex>>().clone(),
+            conversations: stores.get::<Handle<ConversationIndex>>().clone(),
+            session_store: stores.get::<Handle<SessionStore>>().clone(),
+            presence: stores.get::<Handle<PresenceStore>>().clone(),
+            messages: stores.get::<ConcurrentListRef<Message>>().clone(),
+            connection,
+            watcher_tasks,
         }
     }
+
+    /// Moves the changed topic to its new place in the listing.
+    fn reindex(&self, uuid: uuid::Uuid, topic: &Topic) {
+        let last_activity = self.topic_stats.get().last_activity(uuid, topic.timestamp);
+        self.topic_index.get_mut().insert(uuid, topic, last_activity);
+    }
 }
 
 impl topic_service::Server for TopicService {
-    fn create_topic(&mut self, params: CreateTopicParams, mut results: CreateTopicResults) -> Promise<(), Error> { 
-        let username = pry!(self.login_store.get().check_login(&self.peer));
-        let now = Utc::now();
-        let name = pry!(pry!(pry!(params.get()).get_name()).to_string());
-
-        if self.topic_store.get().count(|topic| topic.name == name) > 0 {
-            // AlreadyExists
-            results.get().init_topic().init_err().set_already_exists(());
-            return Promise::ok(());
-        }
-
-        let new_topic = Topic {
-            name,
-            creator: username,
-            timestamp: now,
-            retention: None,
-        };
+    fn create_topic(&mut self, params: CreateTopicParams, mut results: CreateTopicResults) -> Promise<(), Error> {
+        traced(rpc_span!("TopicService", "create_topic", self.peer), || {
+            let username = pry!(self.login_store.get().check_login(&self.peer));
+            record_username(&username);
+            if self.read_only.is_enabled() {
+                results.get().init_topic().init_err().set_read_only(());
+                record_outcome("readOnly");
+                return Promise::ok(());
+            }
+            let now = Utc::now();
+            let reader = pry!(params.get());
+            let name = pry!(pry!(reader.get_name()).to_string());
+
+            let partitions = reader.get_partitions();
+            if partitions > MAX_PARTITIONS {
+                results.get().init_topic().init_err().set_too_many_partitions(MAX_PARTITIONS);
+                record_outcome("tooManyPartitions");
+                return Promise::ok(());
+            }
 
-        let uuid = self.topic_store.get_mut().create(new_topic.clone());
-    
-        let capnp_topic = results.get().init_topic().init_ok();
-        fill_capnp_topic(capnp_topic, uuid, &new_topic);
+            // Conversations are hidden, so their names do not clash with public topics
+            if self.topic_store.get().count(|topic| topic.participants.is_empty() && topic.name == name) > 0 {
+                // AlreadyExists
+                results.get().init_topic().init_err().set_already_exists(());
+                record_outcome("alreadyExists");
+                return Promise::ok(());
+            }
 
-        Promise::ok(())
+            let new_topic = Topic {
+                name,
+                creator: username,
+                timestamp: now,
+                retention: None,
+                partitions,
+                description: String::new(),
+                tags: vec![],
+                pinned_messages: vec![],
+                participants: vec![],
+            };
+
+            let uuid = self.topic_store.get_mut().create(new_topic.clone());
+            self.reindex(uuid, &new_topic);
+            self.topic_events.publish(TopicEvent::new(uuid, &new_topic, TopicEventKind::Created));
+
+            let capnp_topic = results.get().init_topic().init_ok();
+            fill_capnp_topic(capnp_topic, uuid, &new_topic);
+
+            Promise::ok(())
+        })
     }
 
-    fn get_topic(&mut self, params: GetTopicParams, mut results: GetTopicResults) -> Promise<(), Error> { 
-        let _username = pry!(self.login_store.get().check_login(&self.peer));
+    fn get_topic(&mut self, params: GetTopicParams, mut results: GetTopicResults) -> Promise<(), Error> {
+        traced(rpc_span!("TopicService", "get_topic", self.peer), || {
+            let username = pry!(self.login_store.get().check_login(&self.peer));
+            record_username(&username);
 
-        let uuid = pry!(pry!(params.get()).get_topic_id());
-        let uuid = uuid::Uuid::from_u64_pair(uuid.get_upper(), uuid.get_lower());
+            let uuid = pry!(pry!(params.get()).get_topic_id());
+            let uuid = uuid::Uuid::from_u64_pair(uuid.get_upper(), uuid.get_lower());
+            record_topic(uuid);
 
-        let topic = self.topic_store.get().get(uuid);
+            let topic = self.topic_store.get().get(uuid).filter(|topic| topic.is_visible_to(&username));
 
-        match topic {
-            None => {
-                results.get().init_topic().init_err().set_not_found(());
+            match topic {
+                None => {
+                    results.get().init_topic().init_err().set_not_found(());
+                    record_outcome("notFound");
+                }
+
+                Some(topic) => {
+                    let capnp_topic = results.get().init_topic().init_ok();
+                    fill_capnp_topic(capnp_topic, uuid, &topic);
+                }
             }
 
-            Some(topic) => {
-                let capnp_topic = results.get().init_topic().init_ok();
+            Promise::ok(())
+        })
+    }
+
+    fn get_all_topics(&mut self, params: GetAllTopicsParams, mut results: GetAllTopicsResults) -> Promise<(), Error> {
+        traced(rpc_span!("TopicService", "get_all_topics", self.peer), || {
+            let username = pry!(self.login_store.get().check_login(&self.peer));
+            record_username(&username);
+
+            let reader = pry!(params.get());
+            let search = pry!(pry!(reader.get_search()).to_str()).trim().to_lowercase();
+            let tags = pry!(pry!(reader.get_tags()).iter()
+                .map(|tag| Ok::<_, Error>(tag?.to_str()?.trim().to_string()))
+                .collect::<Result<Vec<_>, _>>());
+
+            let mut all_topics = self.topic_store.get().get_all();
+            all_topics.retain(|(_, topic)| topic.participants.is_empty() && matches_search(topic, &search, &tags));
+
+            let mut capnp_list = results.get().init_topics(all_topics.len() as u32);
+            for (index, (uuid, topic)) in all_topics.into_iter().enumerate() {
+                let capnp_topic = capnp_list.reborrow().get(index as u32);
                 fill_capnp_topic(capnp_topic, uuid, &topic);
             }
-        }
 
-        Promise::ok(())
+            Promise::ok(())
+        })
     }
 
-    fn get_all_topics(&mut self, _params: GetAllTopicsParams, mut results: GetAllTopicsResults) -> Promise<(), Error> { 
-        let _username = pry!(self.login_store.get().check_login(&self.peer));
+    fn update_topic(&mut self, params: UpdateTopicParams, mut results: UpdateTopicResults) -> Promise<(), Error> {
+        traced(rpc_span!("TopicService", "update_topic", self.peer), || {
+            let username = pry!(self.login_store.get().check_login(&self.peer));
+            record_username(&username);
 
-        let all_topics = self.topic_store.get().get_all();
+            let uuid = pry!(pry!(params.get()).get_topic_id());
+            let uuid = uuid::Uuid::from_u64_pair(uuid.get_upper(), uuid.get_lower());
+            record_topic(uuid);
 
-        let mut capnp_list = results.get().init_topics(all_topics.len() as u32);
-        for (index, (uuid, topic)) in all_topics.into_iter().enumerate() {
-            let capnp_topic = capnp_list.reborrow().get(index as u32);
-            fill_capnp_topic(capnp_topic, uuid, &topic);
-        }
+            if self.read_only.is_enabled() {
+                results.get().init_topic().init_err().set_read_only(());
+                record_outcome("readOnly");
+                return Promise::ok(());
+            }
+        
+            let new_name = pry!(pry!(pry!(params.get()).get_name()).to_str()).trim();
+
+            let new_retention = pry!(pry!(params.get()).get_retention());
+            let new_retention: Option<Duration> = match pry!(new_retention.which()) {
+                broker::topic_capnp::retention::Which::None(()) => None,
+                broker::topic_capnp::retention::Which::Minutes(minutes) => {
+                    let duration = Duration::from_std(std::time::Duration::from_secs_f64(minutes * 60.0));
+                    Some(pry!(duration.map_err(|err| capnp::Error::failed(err.to_string()))))
+                }
+            };
 
-        Promise::ok(())
+            let topic = self.topic_store.get().get(uuid).filter(|topic| topic.is_visible_to(&username));
+
+            match topic {
+                None => {
+                    results.get().init_topic().init_err().set_not_found(());
+                    record_outcome("notFound");
+                }
+
+                Some(mut current_topic) => {
+                    let is_name_taken = current_topic.participants.is_empty()
+                        && current_topic.name != new_name
+                        && self.topic_store.get().count(|t| t.participants.is_empty() && t.name == new_name) > 0;
+                    if is_name_taken {
+                        results.get().init_topic().init_err().set_already_exists(());
+                        record_outcome("alreadyExists");
+                    } else {
+                        let capnp_topic = results.get().init_topic().init_ok();
+                        let old_name = std::mem::replace(&mut current_topic.name, new_name.into());
+                        let old_retention = std::mem::replace(&mut current_topic.retention, new_retention);
+
+                        fill_capnp_topic(capnp_topic, uuid, &current_topic);
+                        self.topic_store.get_mut().update(uuid, current_topic.clone());
+                        if old_name != current_topic.name || old_retention != current_topic.retention {
+                            self.topic_stats.get_mut().record_change(uuid, Utc::now());
+                            self.reindex(uuid, &current_topic);
+                        }
+
+                        if old_name != current_topic.name {
+                            self.topic_events.publish(TopicEvent::new(uuid, &current_topic, TopicEventKind::Renamed { old_name }));
+                        }
+                        if old_retention != current_topic.retention {
+                            self.topic_events.publish(TopicEvent::new(uuid, &current_topic, TopicEventKind::RetentionChanged));
+                        }
+                    }
+                }
+            }
+
+            Promise::ok(())
+        })
     }
 
-    fn update_topic(&mut self, params: UpdateTopicParams, mut results: UpdateTopicResults) -> Promise<(), Error> { 
-        let _username = pry!(self.login_store.get().check_login(&self.peer));
+    fn delete_topic(&mut self, params: DeleteTopicParams, mut results: DeleteTopicResults) -> Promise<(), Error> {
+        traced(rpc_span!("TopicService", "delete_topic", self.peer), || {
+            let username = pry!(self.login_store.get().check_login(&self.peer));
+            record_username(&username);
 
-        let uuid = pry!(pry!(params.get()).get_topic_id());
-        let uuid = uuid::Uuid::from_u64_pair(uuid.get_upper(), uuid.get_lower());
-        
-        let new_name = pry!(pry!(pry!(params.get()).get_name()).to_str()).trim();
+            let uuid = pry!(pry!(params.get()).get_topic_id());
+            let uuid = uuid::Uuid::from_u64_pair(uuid.get_upper(), uuid.get_lower());
+            record_topic(uuid);
+
+            if self.read_only.is_enabled() {
+                results.get().init_result().init_err().set_read_only(());
+                record_outcome("readOnly");
+                return Promise::ok(());
+            }
+
+            let topic = self.topic_store.get().get(uuid).filter(|topic| topic.is_visible_to(&username));
+
+            match topic {
+                None => {
+                    results.get().init_result().init_err().set_not_found(());
+                    record_outcome("notFound");
+                }
 
-        let new_retention = pry!(pry!(params.get()).get_retention());
-        let new_retention: Option<Duration> = match pry!(new_retention.which()) {
-            broker::topic_capnp::retention::Which::None(()) => None,
-            broker::topic_capnp::retention::Which::Minutes(minutes) => {
-                let duration = Duration::from_std(std::time::Duration::from_secs_f64(minutes * 60.0));
-                Some(pry!(duration.map_err(|err| capnp::Error::failed(err.to_string()))))
+                Some(topic) => {
+                    self.topic_store.get_mut().remove(uuid);
+                    self.topic_stats.get_mut().forget(uuid);
+                    self.topic_index.get_mut().remove(uuid);
+                    self.conversations.get_mut().remove(uuid, &topic);
+                    self.topic_events.publish(TopicEvent::new(uuid, &topic, TopicEventKind::Deleted));
+                    results.get().init_result().init_ok();
+                }
             }
-        };
 
-        let topic = self.topic_store.get().get(uuid);
+            Promise::ok(())
+        })
+    }
+    fn watch_topics(&mut self, params: WatchTopicsParams, _: WatchTopicsResults) -> Promise<(), Error> {
+        traced(rpc_span!("TopicService", "watch_topics", self.peer), || {
+            let username = pry!(self.login_store.get().check_login(&self.peer));
+            record_username(&username);
+
+            let watcher = pry!(pry!(params.get()).get_watcher());
+            let events = self.topic_events.watch();
+            self.watcher_tasks.spawn_local(
+                forward_topic_events(events, watcher, username, self.connection.clone())
+                    .instrument(tracing::info_span!("topic_watcher", peer = %self.peer))
+            );
+
+            Promise::ok(())
+        })
+    }
+
+    fn update_topic_metadata(&mut self, params: UpdateTopicMetadataParams, mut results: UpdateTopicMetadataResults) -> Promise<(), Error> {
+        traced(rpc_span!("TopicService", "update_topic_metadata", self.peer), || {
+            let username = pry!(self.login_store.get().check_login(&self.peer));
+            record_username(&username);
+            let reader = pry!(params.get());
+
+            let uuid = pry!(reader.get_topic_id());
+            let uuid = uuid::Uuid::from_u64_pair(uuid.get_upper(), uuid.get_lower());
+            record_topic(uuid);
 
-        match topic {
-            None => {
+            if self.read_only.is_enabled() {
+                results.get().init_topic().init_err().set_read_only(());
+                record_outcome("readOnly");
+                return Promise::ok(());
+            }
+
+            let description = pry!(pry!(reader.get_description()).to_str());
+            let tags = pry!(pry!(reader.get_tags()).iter()
+                .map(|tag| Ok::<_, Error>(tag?.to_str()?.to_string()))
+                .collect::<Result<Vec<_>, _>>());
+
+            let (description, tags) = match validate_metadata(description, tags) {
+                Ok(metadata) => metadata,
+                Err(reason) => {
+                    results.get().init_topic().init_err().set_invalid_metadata(&reason);
+                    record_outcome("invalidMetadata");
+                    return Promise::ok(());
+                }
+            };
+
+            let Some(mut topic) = self.topic_store.get().get(uuid).filter(|topic| topic.is_visible_to(&username)) else {
                 results.get().init_topic().init_err().set_not_found(());
+                record_outcome("notFound");
+                return Promise::ok(());
+            };
+
+            topic.description = description;
+            topic.tags = tags;
+            self.topic_store.get_mut().update(uuid, topic.clone());
+            self.topic_stats.get_mut().record_change(uuid, Utc::now());
+            self.reindex(uuid, &topic);
+            self.topic_events.publish(TopicEvent::new(uuid, &topic, TopicEventKind::MetadataChanged));
+
+            fill_capnp_topic(results.get().init_topic().init_ok(), uuid, &topic);
+            Promise::ok(())
+        })
+    }
+
+    fn pin_message(&mut self, params: PinMessageParams, mut results: PinMessageResults) -> Promise<(), Error> {
+        traced(rpc_span!("TopicService", "pin_message", self.peer), || {
+            let username = pry!(self.login_store.get().check_login(&self.peer));
+            record_username(&username);
+            let reader = pry!(params.get());
+
+            let uuid = pry!(reader.get_topic_id());
+            let uuid = uuid::Uuid::from_u64_pair(uuid.get_upper(), uuid.get_lower());
+            record_topic(uuid);
+            let message_uuid = pry!(reader.get_message_id());
+            let message_uuid = uuid::Uuid::from_u64_pair(message_uuid.get_upper(), message_uuid.get_lower());
+
+            if self.read_only.is_enabled() {
+                results.get().init_topic().init_err().set_read_only(());
+                record_outcome("readOnly");
+                return Promise::ok(());
             }
 
-            Some(mut current_topic) => {
-                if current_topic.name != new_name && self.topic_store.get().count(|t| t.name == new_name) > 0 {
-                    results.get().init_topic().init_err().set_already_exists(());
-                } else {
-                    let capnp_topic = results.get().init_topic().init_ok();
-                    current_topic.name = new_name.into();
-                    current_topic.retention = new_retention;
+            let Some(mut topic) = self.topic_store.get().get(uuid).filter(|topic| topic.is_visible_to(&username)) else {
+                results.get().init_topic().init_err().set_not_found(());
+                record_outcome("notFound");
+                return Promise::ok(());
+            };
+
+            if !topic.pinned_messages.contains(&message_uuid) {
+                if topic.pinned_messages.len() >= MAX_PINNED_MESSAGES {
+                    results.get().init_topic().init_err().set_invalid_metadata(format!("At most {MAX_PINNED_MESSAGES} messages can be pinned"));
+                    record_outcome("invalidMetadata");
+                    return Promise::ok(());
+                }
 
-                    fill_capnp_topic(capnp_topic, uuid, &current_topic);
-                    self.topic_store.get_mut().update(uuid, current_topic);
+                let mut messages = self.messages.clone();
+                messages.drain_backwards();
+                let exists = messages.any(|message| message.as_ref().is_some_and(|message| message.uuid == message_uuid && message.topic_uuid == uuid));
+                if !exists {
+                    results.get().init_topic().init_err().set_message_not_found(());
+                    record_outcome("messageNotFound");
+                    return Promise::ok(());
                 }
+
+                topic.pinned_messages.push(message_uuid);
+                self.topic_store.get_mut().update(uuid, topic.clone());
+                self.topic_stats.get_mut().record_change(uuid, Utc::now());
+                self.reindex(uuid, &topic);
+                self.topic_events.publish(TopicEvent::new(uuid, &topic, TopicEventKind::MetadataChanged));
             }
-        }
 
-        Promise::ok(())
+            fill_capnp_topic(results.get().init_topic().init_ok(), uuid, &topic);
+            Promise::ok(())
+        })
     }
 
-    fn delete_topic(&mut self, params: DeleteTopicParams, mut results: DeleteTopicResults) -> Promise<(), Error> { 
-        let _username = pry!(self.login_store.get().check_login(&self.peer));
+    fn unpin_message(&mut self, params: UnpinMessageParams, mut results: UnpinMessageResults) -> Promise<(), Error> {
+        traced(rpc_span!("TopicService", "unpin_message", self.peer), || {
+            let username = pry!(self.login_store.get().check_login(&self.peer));
+            record_username(&username);
+            let reader = pry!(params.get());
+
+            let uuid = pry!(reader.get_topic_id());
+            let uuid = uuid::Uuid::from_u64_pair(uuid.get_upper(), uuid.get_lower());
+            record_topic(uuid);
+            let message_uuid = pry!(reader.get_message_id());
+            let message_uuid = uuid::Uuid::from_u64_pair(message_uuid.get_upper(), message_uuid.get_lower());
+
+            if self.read_only.is_enabled() {
+                results.get().init_topic().init_err().set_read_only(());
+                record_outcome("readOnly");
+                return Promise::ok(());
+            }
+
+            let Some(mut topic) = self.topic_store.get().get(uuid).filter(|topic| topic.is_visible_to(&username)) else {
+                results.get().init_topic().init_err().set_not_found(());
+                record_outcome("notFound");
+                return Promise::ok(());
+            };
+
+            // Unpinning a message that is not pinned changes nothing
+            if topic.pinned_messages.contains(&message_uuid) {
+                topic.pinned_messages.retain(|pinned| *pinned != message_uuid);
+                self.topic_store.get_mut().update(uuid, topic.clone());
+                self.topic_stats.get_mut().record_change(uuid, Utc::now());
+                self.reindex(uuid, &topic);
+                self.topic_events.publish(TopicEvent::new(uuid, &topic, TopicEventKind::MetadataChanged));
+            }
+
+            fill_capnp_topic(results.get().init_topic().init_ok(), uuid, &topic);
+            Promise::ok(())
+        })
+    }
 
-        let uuid = pry!(pry!(params.get()).get_topic_id());
-        let uuid = uuid::Uuid::from_u64_pair(uuid.get_upper(), uuid.get_lower());
+    fn list_topics(&mut self, params: ListTopicsParams, mut results: ListTopicsResults) -> Promise<(), Error> {
+        traced(rpc_span!("TopicService", "list_topics", self.peer), || {
+            let username = pry!(self.login_store.get().check_login(&self.peer));
+            record_username(&username);
+
+            let query = match pry!(TopicQuery::read_capnp(pry!(pry!(params.get()).get_query()))) {
+                Ok(query) => query,
+                Err(reason) => {
+                    results.get().init_page().init_err().set_invalid_cursor(&reason);
+                    record_outcome("invalidCursor");
+                    return Promise::ok(());
+                }
+            };
 
-        let topic = self.topic_store.get().get(uuid);
+            let (topics, next_cursor) = query.page(&self.topic_index.get(), &self.topic_store.get());
 
-        match topic {
-            None => {
-                results.get().init_result().init_err().set_not_found(());
+            let mut page = results.get().init_page().init_ok();
+            page.set_next_cursor(next_cursor.as_deref().unwrap_or_default());
+            let mut capnp_list = page.init_topics(topics.len() as u32);
+            for (index, (uuid, topic)) in topics.into_iter().enumerate() {
+                fill_capnp_topic(capnp_list.reborrow().get(index as u32), uuid, &topic);
             }
 
-            Some(_) => {
-                self.topic_store.get_mut().remove(uuid);
-                results.get().init_result().init_ok();
+            Promise::ok(())
+        })
+    }
+
+    fn get_topic_stats(&mut self, params: GetTopicStatsParams, mut results: GetTopicStatsResults) -> Promise<(), Error> {
+        traced(rpc_span!("TopicService", "get_topic_stats", self.peer), || {
+            let username = pry!(self.login_store.get().check_login(&self.peer));
+            record_username(&username);
+
+            let uuid = pry!(pry!(params.get()).get_topic_id());
+            let uuid = uuid::Uuid::from_u64_pair(uuid.get_upper(), uuid.get_lower());
+            record_topic(uuid);
+
+            let Some(topic) = self.topic_store.get().get(uuid).filter(|topic| topic.is_visible_to(&username)) else {
+                results.get().init_stats().init_err().set_not_found(());
+                record_outcome("notFound");
+                return Promise::ok(());
+            };
+
+            // Sessions are few compared to messages, so subscribers are counted on demand
+            let subscribers = self.session_store.get().get_all()
+                .filter(|(_, session)| {
+                    session.subscriptions.contains(&uuid)
+                        || session.pattern_subscriptions.iter()
+                            .any(|pattern| TopicPattern::parse(pattern).is_ok_and(|pattern| pattern.matches(&topic.name)))
+                })
+                .count();
+
+            let now = Utc::now();
+            let counters = self.topic_stats.get().get(uuid);
+
+            let mut stats = results.get().init_stats().init_ok();
+            stats.set_messages(counters.messages);
+            stats.set_bytes(counters.bytes);
+            if let Some(oldest) = counters.oldest_message {
+                fill_capnp_timestamp(stats.reborrow().init_oldest_message().init_t(), oldest);
             }
-        }
+            if let Some(newest) = counters.newest_message {
+                fill_capnp_timestamp(stats.reborrow().init_newest_message().init_t(), newest);
+            }
+            fill_capnp_timestamp(stats.reborrow().init_last_activity(), counters.last_activity(topic.timestamp));
+            stats.set_subscribers(subscribers as u32);
+            stats.set_posts_last_minute(counters.posts_last_minute(now));
+
+            Promise::ok(())
+        })
+    }
+
+    fn list_conversations(&mut self, _: ListConversationsParams, mut results: ListConversationsResults) -> Promise<(), Error> {
+        traced(rpc_span!("TopicService", "list_conversations", self.peer), || {
+            let username = pry!(self.login_store.get().check_login(&self.peer));
+            record_username(&username);
+
+            let mut conversations = self.topic_store.get().get_all();
+            conversations.retain(|(_, topic)| topic.participants.contains(&username));
+
+            let stats = self.topic_stats.get();
+            conversations.sort_by_cached_key(|(uuid, topic)| std::cmp::Reverse(stats.last_activity(*uuid, topic.timestamp)));
 
-        Promise::ok(())
+            let mut capnp_list = results.get().init_topics(conversations.len() as u32);
+            for (index, (uuid, topic)) in conversations.into_iter().enumerate() {
+                fill_capnp_topic(capnp_list.reborrow().get(index as u32), uuid, &topic);
+            }
+
+            Promise::ok(())
+        })
+    }
+
+    fn get_subscribers(&mut self, params: GetSubscribersParams, mut results: GetSubscribersResults) -> Promise<(), Error> {
+        traced(rpc_span!("TopicService", "get_subscribers", self.peer), || {
+            let username = pry!(self.login_store.get().check_login(&self.peer));
+            record_username(&username);
+
+            let uuid = pry!(pry!(params.get()).get_topic_id());
+            let uuid = uuid::Uuid::from_u64_pair(uuid.get_upper(), uuid.get_lower());
+            record_topic(uuid);
+
+            let Some(topic) = self.topic_store.get().get(uuid).filter(|topic| topic.is_visible_to(&username)) else {
+                results.get().init_subscribers().init_err().set_not_found(());
+                record_outcome("notFound");
+                return Promise::ok(());
+            };
+
+            // Patterns never deliver conversations, so only direct subscriptions count there
+            let logins = self.login_store.get();
+            let mut subscribers: Vec<Username> = self.session_store.get().get_all()
+                .filter(|(_, session)| {
+                    session.subscriptions.contains(&uuid)
+                        || (topic.participants.is_empty() && session.pattern_subscriptions.iter()
+                            .any(|pattern| TopicPattern::parse(pattern).is_ok_and(|pattern| pattern.matches(&topic.name))))
+                })
+                .filter_map(|(peer, _)| logins.get_login(peer))
+                .collect();
+            drop(logins);
+            subscribers.sort();
+            subscribers.dedup();
+
+            let presence = self.presence.get();
+            let mut capnp_list = results.get().init_subscribers().initn_ok(subscribers.len() as u32);
+            for (index, subscriber) in subscribers.iter().enumerate() {
+                fill_capnp_presence(capnp_list.reborrow().get(index as u32), &presence.get(subscriber));
+            }
+
+            Promise::ok(())
+        })
+    }
+}
+
+/// Trims the description and the tags and drops repeated tags. Returns the reason to reject them if they are too long.
+fn validate_metadata(description: &str, tags: Vec<String>) -> Result<(String, Vec<String>), String> {
+    let description = description.trim();
+    if description.len() > MAX_DESCRIPTION_LENGTH {
+        return Err(format!("Description is {} bytes long, at most {MAX_DESCRIPTION_LENGTH} are allowed", description.len()));
+    }
+    if description.chars().any(|c| c.is_control()) {
+        return Err("Description contains control characters".to_string());
+    }
+
+    let mut unique_tags = Vec::<String>::new();
+    for tag in tags {
+        let tag = tag.trim();
+        if tag.is_empty() || tag.len() > MAX_TAG_LENGTH || tag.chars().any(|c| c.is_control() || c.is_whitespace()) {
+            return Err(format!("Tags must be 1 to {MAX_TAG_LENGTH} bytes long without spaces, got '{}'", tag.escape_debug()));
+        }
+        if !unique_tags.iter().any(|unique| unique == tag) {
+            unique_tags.push(tag.to_string());
+        }
     }
-}
\ No newline at end of file
+    if unique_tags.len() > MAX_TAGS {
+        return Err(format!("At most {MAX_TAGS} tags are allowed"));
+    }
+
+    Ok((description.to_string(), unique_tags))
+}
+
+/// Sends the events to the watcher until it is gone or `connection` is cancelled.
+/// Events of direct conversations only go to their participants.
+async fn forward_topic_events(events: broadcast::Receiver<TopicEvent>, watcher: topic_watcher::Client, username: Username, connection: CancellationToken) {
+    forward(events, connection, |event| {
+        if !event.topic.is_visible_to(&username) {
+            return None;
+        }
+
+        let mut request = watcher.event_request();
+        let mut builder = request.get().init_event();
+        fill_capnp_topic(builder.reborrow().init_topic(), event.uuid, &event.topic);
+        match event.kind {
+            TopicEventKind::Created => builder.set_created(()),
+            TopicEventKind::Renamed { old_name } => builder.set_renamed(&old_name),
+            TopicEventKind::RetentionChanged => builder.set_retention_changed(()),
+            TopicEventKind::Deleted => builder.set_deleted(()),
+            TopicEventKind::MetadataChanged => builder.set_metadata_changed(()),
+        }
+        Some(request.send())
+    }).await
+}
@@ -0,0 +1,72 @@
+use std::cell::Cell;
+use std::time::Instant;
+
+use capnp::{capability::Promise, Error};
+use tracing::{debug, info, warn, Span};
+use uuid::Uuid;
+
+/// Creates the span of a single RPC call.
+/// `username`, `topic` and `outcome` are filled in later, see [`record_username`], [`record_topic`] and [`record_outcome`].
+macro_rules! rpc_span {
+    ($service:literal, $method:literal, $peer:expr) => {
+        tracing::info_span!(
+            "rpc",
+            service = $service,
+            method = $method,
+            peer = %$peer,
+            username = tracing::field::Empty,
+            topic = tracing::field::Empty,
+            outcome = tracing::field::Empty,
+        )
+    };
+}
+pub(crate) use rpc_span;
+
+thread_local! {
+    static OUTCOME: Cell<&'static str> = const { Cell::new("ok") };
+}
+
+/// Runs the body of an RPC method inside `span`. Once the returned promise resolves,
+/// records the outcome and logs it with the duration of the call.
+pub fn traced<T: 'static>(span: Span, method: impl FnOnce() -> Promise<T, Error>) -> Promise<T, Error> {
+    let started = Instant::now();
+
+    OUTCOME.set("ok");
+    let promise = span.in_scope(method);
+    let outcome = OUTCOME.replace("ok");
+
+    Promise::from_future(async move {
+        let result = promise.await;
+        let duration_ms = started.elapsed().as_secs_f64() * 1000.0;
+
+        match &result {
+            Ok(_) if outcome == "ok" => {
+                span.record("outcome", outcome);
+                debug!(parent: &span, duration_ms, "RPC finished");
+            }
+            Ok(_) => {
+                span.record("outcome", outcome);
+                info!(parent: &span, duration_ms, "RPC rejected");
+            }
+            Err(e) => {
+                span.record("outcome", "failed");
+                warn!(parent: &span, duration_ms, error = %e, "RPC failed");
+            }
+        }
+        result
+    })
+}
+
+pub fn record_username(username: &str) {
+    Span::current().record("username", username);
+}
+
+pub fn record_topic(topic_uuid: Uuid) {
+    Span::current().record("topic", tracing::field::display(topic_uuid));
+}
+
+/// Marks the current call as rejected with a domain error, e.g. `entityDoesNotExist`.
+/// Must be called synchronously from the body passed to [`traced`].
+pub fn record_outcome(outcome: &'static str) {
+    OUTCOME.set(outcome);
+}
\ No newline at end of file
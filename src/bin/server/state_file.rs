@@ -0,0 +1,127 @@
+use std::collections::HashMap;
+use std::io::{BufRead, Write};
+
+use broker::concurrent_list::ConcurrentList;
+use broker::util::Handle;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::datatypes::{Message, Notification, Topic};
+use crate::server::Server;
+use crate::stores::{CrudStore, NotificationStore};
+
+/// A line of an NDJSON export. Topics come first, then messages in the order they were posted, then notifications.
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum Record {
+    Topic {
+        uuid: Uuid,
+        #[serde(flatten)]
+        topic: Topic,
+    },
+    Message(Message),
+    Notification(Notification),
+}
+
+/// Prints the topics of the state with their message counts.
+pub fn inspect(server: &Server, mut output: impl Write) -> std::io::Result<()> {
+    let mut topics = server.stores().get::<Handle<CrudStore<Topic>>>().get().get_all();
+    topics.sort_by_key(|(_, topic)| topic.timestamp);
+
+    let mut counts = HashMap::<Uuid, usize>::new();
+    for_each_message(server.messages(), |message| *counts.entry(message.topic_uuid).or_default() += 1);
+
+    writeln!(output, "Topics: {}", topics.len())?;
+    writeln!(output, "Messages: {}", server.messages().len())?;
+    writeln!(output, "Notifications: {}", server.stores().get::<Handle<NotificationStore>>().get().len())?;
+
+    for (uuid, topic) in &topics {
+        let retention = match topic.retention {
+            Some(retention) => format!("{} min", retention.num_seconds() as f64 / 60.0),
+            None => "-".to_string(),
+        };
+        let count = counts.remove(uuid).unwrap_or(0);
+        writeln!(output, "{uuid}\t{}\t{}\t{count} messages\tretention {retention}", topic.name, topic.creator)?;
+    }
+
+    // Messages of deleted topics are kept until retention removes them
+    let orphaned = counts.values().sum::<usize>();
+    if orphaned > 0 {
+        writeln!(output, "{orphaned} messages of {} deleted topics", counts.len())?;
+    }
+    Ok(())
+}
+
+/// Writes every topic, message and notification as a JSON object per line. Returns the amount of written lines.
+pub fn export(server: &Server, mut output: impl Write) -> std::io::Result<usize> {
+    let mut written = 0;
+
+    let topics = server.stores().get::<Handle<CrudStore<Topic>>>().get().get_all();
+    for (uuid, topic) in topics {
+        write_record(&mut output, &Record::Topic { uuid, topic })?;
+        written += 1;
+    }
+
+    let mut result = Ok(());
+    for_each_message(server.messages(), |message| {
+        if result.is_ok() {
+            result = write_record(&mut output, &Record::Message(message.clone()));
+            written += 1;
+        }
+    });
+    result?;
+
+    let notifications = server.stores().get::<Handle<NotificationStore>>();
+    for notification in notifications.get().get_all() {
+        write_record(&mut output, &Record::Notification(notification.clone()))?;
+        written += 1;
+    }
+
+    output.flush()?;
+    Ok(written)
+}
+
+/// Builds a server from an NDJSON export. Lines are validated one by one, the error names the broken line.
+pub fn import(input: impl BufRead) -> std::io::Result<Server> {
+    let mut topics = CrudStore::<Topic>::default();
+    let messages = ConcurrentList::<Message>::default();
+    let mut messages_writer = messages.reference();
+    let mut notifications = NotificationStore::default();
+
+    for (index, line) in input.lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let record = serde_json::from_str::<Record>(&line)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, format!("Line {}: {e}", index + 1)))?;
+
+        match record {
+            Record::Topic { uuid, topic } => topics.insert(uuid, topic),
+            Record::Message(message) => {
+                messages_writer.push(message);
+            }
+            Record::Notification(notification) => notifications.add(notification),
+        }
+    }
+
+    Ok(Server::from_messages(topics, messages, notifications))
+}
+
+fn write_record(output: &mut impl Write, record: &Record) -> std::io::Result<()> {
+    serde_json::to_writer(&mut *output, record)?;
+    output.write_all(b"\n")
+}
+
+/// Visits the messages from the oldest one, the same way the state file stores them.
+fn for_each_message(messages: &ConcurrentList<Message>, mut action: impl FnMut(&Message)) {
+    let mut handle = messages.reference();
+    handle.drain_backwards();
+
+    for elem in handle {
+        if let Some(message) = &*elem {
+            action(message);
+        }
+    }
+}
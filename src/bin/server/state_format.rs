@@ -0,0 +1,304 @@
+//! Layout of the state file: a magic number and a format version, then the state encoded with bincode.
+//!
+//! Bincode writes fields one after another without names, so a state can only be read with the exact types
+//! it was written with. Every change of the saved types bumps [`FORMAT_VERSION`] and keeps the previous layout
+//! here, so older state files are decoded as they were written and upgraded to the current types.
+
+use std::collections::{BTreeMap, HashMap};
+use std::io::{Read, Write};
+
+use broker::concurrent_list::ConcurrentList;
+use bincode::ErrorKind;
+use chrono::{DateTime, Utc};
+use serde::Deserialize;
+use uuid::Uuid;
+
+use crate::datatypes::{Message, Retention, Revision, Topic, Username};
+use crate::server::Server;
+use crate::stores::{CrudStore, NotificationStore};
+
+/// Starts every versioned state file. State files of version 0 start with the amount of messages instead.
+const MAGIC: [u8; 4] = *b"BRKS";
+
+/// Version of the layout [`write`] produces.
+pub const FORMAT_VERSION: u32 = 8;
+
+pub fn write(server: &Server, mut output: impl Write) -> bincode::Result<()> {
+    output.write_all(&MAGIC)?;
+    bincode::serialize_into(&mut output, &FORMAT_VERSION)?;
+    bincode::serialize_into(output, server)
+}
+
+/// Reads a state file of any version up to [`FORMAT_VERSION`].
+pub fn read(mut input: impl Read) -> bincode::Result<Server> {
+    let mut magic = [0; 4];
+    input.read_exact(&mut magic)?;
+    if magic != MAGIC {
+        // The bytes belong to the unversioned state, a message count this large could never be saved
+        return read_legacy::<MessageV0, TopicV0>(magic.chain(input));
+    }
+
+    let version: u32 = bincode::deserialize_from(&mut input)?;
+    match version {
+        1 => read_legacy::<MessageV1, TopicV0>(input),
+        2 => read_legacy::<MessageV2, TopicV0>(input),
+        3 => read_legacy::<MessageV3, TopicV3>(input),
+        4 => read_legacy::<MessageV3, TopicV4>(input),
+        5 => read_legacy::<MessageV5, TopicV4>(input),
+        6 => read_legacy::<Message, TopicV4>(input),
+        7 => read_legacy::<Message, Topic>(input),
+        FORMAT_VERSION => bincode::deserialize_from(input),
+        version => Err(Box::new(ErrorKind::Custom(format!(
+            "State file has format version {version}, this server reads versions up to {FORMAT_VERSION}"
+        )))),
+    }
+}
+
+/// State as it was saved before notifications, with the messages and topics of an older version.
+#[derive(Deserialize)]
+struct LegacyState<M, T> {
+    messages: Vec<M>,
+    topics: LegacyTopics<T>,
+}
+
+#[derive(Deserialize)]
+struct LegacyTopics<T> {
+    entries: HashMap<Uuid, T>,
+}
+
+fn read_legacy<M, T>(input: impl Read) -> bincode::Result<Server>
+where
+    M: for<'de> Deserialize<'de> + Into<Message>,
+    T: for<'de> Deserialize<'de> + Into<Topic>,
+{
+    let state: LegacyState<M, T> = bincode::deserialize_from(input)?;
+
+    let mut topics = CrudStore::default();
+    for (uuid, topic) in state.topics.entries {
+        topics.insert(uuid, topic.into());
+    }
+    let messages = state.messages.into_iter()
+        .map(Into::into)
+        .collect::<ConcurrentList<Message>>();
+
+    Ok(Server::from_messages(topics, messages, NotificationStore::default()))
+}
+
+/// Message of version 0, before binary payloads.
+#[derive(Deserialize)]
+struct MessageV0 {
+    uuid: Uuid,
+    topic_uuid: Uuid,
+    author_name: Username,
+    content: String,
+    timestamp: DateTime<Utc>,
+    key: Option<String>,
+}
+
+impl From<MessageV0> for Message {
+    fn from(message: MessageV0) -> Self {
+        let MessageV0 { uuid, topic_uuid, author_name, content, timestamp, key } = message;
+        Message { uuid, topic_uuid, author_name, content, timestamp, key, ..Default::default() }
+    }
+}
+
+/// Message of version 1, before headers.
+#[derive(Deserialize)]
+struct MessageV1 {
+    uuid: Uuid,
+    topic_uuid: Uuid,
+    author_name: Username,
+    content: String,
+    timestamp: DateTime<Utc>,
+    key: Option<String>,
+    payload: Vec<u8>,
+    content_type: String,
+}
+
+impl From<MessageV1> for Message {
+    fn from(message: MessageV1) -> Self {
+        let MessageV1 { uuid, topic_uuid, author_name, content, timestamp, key, payload, content_type } = message;
+        Message { uuid, topic_uuid, author_name, content, timestamp, key, payload, content_type, ..Default::default() }
+    }
+}
+
+/// Message of version 2, before partitions.
+#[derive(Deserialize)]
+struct MessageV2 {
+    uuid: Uuid,
+    topic_uuid: Uuid,
+    author_name: Username,
+    content: String,
+    timestamp: DateTime<Utc>,
+    key: Option<String>,
+    payload: Vec<u8>,
+    content_type: String,
+    headers: BTreeMap<String, String>,
+}
+
+impl From<MessageV2> for Message {
+    fn from(message: MessageV2) -> Self {
+        let MessageV2 { uuid, topic_uuid, author_name, content, timestamp, key, payload, content_type, headers } = message;
+        Message { uuid, topic_uuid, author_name, content, timestamp, key, payload, content_type, headers, ..Default::default() }
+    }
+}
+
+/// Message of versions 3 and 4, before edits.
+#[derive(Deserialize)]
+struct MessageV3 {
+    uuid: Uuid,
+    topic_uuid: Uuid,
+    author_name: Username,
+    content: String,
+    timestamp: DateTime<Utc>,
+    key: Option<String>,
+    payload: Vec<u8>,
+    content_type: String,
+    headers: BTreeMap<String, String>,
+    partition: u32,
+}
+
+impl From<MessageV3> for Message {
+    fn from(message: MessageV3) -> Self {
+        let MessageV3 { uuid, topic_uuid, author_name, content, timestamp, key, payload, content_type, headers, partition } = message;
+        Message { uuid, topic_uuid, author_name, content, timestamp, key, payload, content_type, headers, partition, ..Default::default() }
+    }
+}
+
+/// Message of version 5, before replies.
+#[derive(Deserialize)]
+struct MessageV5 {
+    uuid: Uuid,
+    topic_uuid: Uuid,
+    author_name: Username,
+    content: String,
+    timestamp: DateTime<Utc>,
+    key: Option<String>,
+    payload: Vec<u8>,
+    content_type: String,
+    headers: BTreeMap<String, String>,
+    partition: u32,
+    revisions: Vec<Revision>,
+    edited_at: Option<DateTime<Utc>>,
+}
+
+impl From<MessageV5> for Message {
+    fn from(message: MessageV5) -> Self {
+        let MessageV5 { uuid, topic_uuid, author_name, content, timestamp, key, payload, content_type, headers, partition, revisions, edited_at } = message;
+        Message { uuid, topic_uuid, author_name, content, timestamp, key, payload, content_type, headers, partition, revisions, edited_at, ..Default::default() }
+    }
+}
+
+/// Topic of versions 0 to 2, before partitions.
+#[derive(Deserialize)]
+struct TopicV0 {
+    name: String,
+    creator: Username,
+    timestamp: DateTime<Utc>,
+    retention: Retention,
+}
+
+impl From<TopicV0> for Topic {
+    fn from(topic: TopicV0) -> Self {
+        let TopicV0 { name, creator, timestamp, retention } = topic;
+        Topic { name, creator, timestamp, retention, ..Default::default() }
+    }
+}
+
+/// Topic of version 3, before descriptions, tags and pins.
+#[derive(Deserialize)]
+struct TopicV3 {
+    name: String,
+    creator: Username,
+    timestamp: DateTime<Utc>,
+    retention: Retention,
+    partitions: u32,
+}
+
+impl From<TopicV3> for Topic {
+    fn from(topic: TopicV3) -> Self {
+        let TopicV3 { name, creator, timestamp, retention, partitions } = topic;
+        Topic { name, creator, timestamp, retention, partitions, ..Default::default() }
+    }
+}
+
+/// Topic of versions 4 to 6, before direct conversations.
+#[derive(Deserialize)]
+struct TopicV4 {
+    name: String,
+    creator: Username,
+    timestamp: DateTime<Utc>,
+    retention: Retention,
+    partitions: u32,
+    description: String,
+    tags: Vec<String>,
+    pinned_messages: Vec<Uuid>,
+}
+
+impl From<TopicV4> for Topic {
+    fn from(topic: TopicV4) -> Self {
+        let TopicV4 { name, creator, timestamp, retention, partitions, description, tags, pinned_messages } = topic;
+        Topic { name, creator, timestamp, retention, partitions, description, tags, pinned_messages, ..Default::default() }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use broker::util::Handle;
+
+    use super::*;
+
+    #[test]
+    fn reads_unversioned_state() {
+        let topic_uuid = Uuid::new_v4();
+        let timestamp = Utc::now();
+        let messages = vec![(Uuid::new_v4(), topic_uuid, "alice", "hello", timestamp, Some("key"))];
+        let topics = HashMap::from([(topic_uuid, ("news", "alice", timestamp, Retention::None))]);
+        let bytes = bincode::serialize(&(messages, (topics,))).unwrap();
+
+        let server = read(bytes.as_slice()).unwrap();
+
+        let topic = server.stores().get::<Handle<CrudStore<Topic>>>().get().get(topic_uuid).unwrap();
+        assert_eq!(topic.name, "news");
+        assert_eq!(server.messages().len(), 1);
+    }
+
+    #[test]
+    fn reads_state_without_notifications() {
+        let topic_uuid = Uuid::new_v4();
+        let topic = Topic { name: "alice/bob".to_string(), participants: vec!["alice".to_string(), "bob".to_string()], ..Default::default() };
+        let message = Message { topic_uuid, content: "hi @bob".to_string(), ..Default::default() };
+
+        let mut bytes = MAGIC.to_vec();
+        bytes.extend(bincode::serialize(&7u32).unwrap());
+        bytes.extend(bincode::serialize(&(vec![message], (HashMap::from([(topic_uuid, topic)]),))).unwrap());
+
+        let server = read(bytes.as_slice()).unwrap();
+
+        let topic = server.stores().get::<Handle<CrudStore<Topic>>>().get().get(topic_uuid).unwrap();
+        assert_eq!(topic.participants, ["alice", "bob"]);
+        assert_eq!(server.messages().len(), 1);
+        assert_eq!(server.stores().get::<Handle<NotificationStore>>().get().len(), 0);
+    }
+
+    #[test]
+    fn reads_what_it_writes() {
+        let server = Server::new();
+        server.stores().get::<Handle<CrudStore<Topic>>>().get_mut().create(Topic { name: "news".to_string(), ..Default::default() });
+
+        let mut bytes = vec![];
+        write(&server, &mut bytes).unwrap();
+        let read = read(bytes.as_slice()).unwrap();
+
+        let topics = read.stores().get::<Handle<CrudStore<Topic>>>().get().get_all();
+        assert_eq!(topics.len(), 1);
+        assert_eq!(topics[0].1.name, "news");
+    }
+
+    #[test]
+    fn rejects_newer_versions() {
+        let mut bytes = MAGIC.to_vec();
+        bytes.extend(bincode::serialize(&(FORMAT_VERSION + 1)).unwrap());
+        assert!(read(bytes.as_slice()).is_err());
+    }
+}
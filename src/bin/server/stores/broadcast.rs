@@ -0,0 +1,64 @@
+use capnp::capability::Promise;
+use capnp::Error;
+use tokio::sync::broadcast::{self, error::RecvError};
+use tokio_util::sync::CancellationToken;
+use tracing::warn;
+
+/// Items a receiver may fall behind by before it skips the oldest ones.
+const CAPACITY: usize = 256;
+
+/// Fans items out to the receivers on every worker thread. Clones publish into the same channel.
+pub struct Broadcast<T> {
+    sender: broadcast::Sender<T>,
+}
+
+impl<T> Clone for Broadcast<T> {
+    fn clone(&self) -> Self {
+        Self { sender: self.sender.clone() }
+    }
+}
+
+impl<T: Clone> Default for Broadcast<T> {
+    fn default() -> Self {
+        let (sender, _) = broadcast::channel(CAPACITY);
+        Self { sender }
+    }
+}
+
+impl<T: Clone> Broadcast<T> {
+    /// Items published while nobody watches are dropped.
+    pub fn publish(&self, item: T) {
+        let _ = self.sender.send(item);
+    }
+
+    /// Receives the items published from now on.
+    pub fn watch(&self) -> broadcast::Receiver<T> {
+        self.sender.subscribe()
+    }
+}
+
+/// Sends the requests `deliver` makes of the received items until one fails, the channel closes or `stop` is cancelled.
+/// `deliver` returns `None` for the items that are not for this watcher.
+pub async fn forward<T: Clone>(mut receiver: broadcast::Receiver<T>, stop: CancellationToken, mut deliver: impl FnMut(T) -> Option<Promise<(), Error>>) {
+    loop {
+        let item = tokio::select! {
+            item = receiver.recv() => item,
+            _ = stop.cancelled() => break,
+        };
+
+        let item = match item {
+            Ok(item) => item,
+            Err(RecvError::Lagged(missed)) => {
+                warn!(missed, "Watcher is lagging behind, items were dropped");
+                continue;
+            }
+            Err(RecvError::Closed) => break,
+        };
+
+        if let Some(request) = deliver(item) {
+            if request.await.is_err() {
+                break;
+            }
+        }
+    }
+}
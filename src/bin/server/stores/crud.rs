@@ -32,6 +32,11 @@ impl<T: Clone> CrudStore<T> {
         uuid
     }
 
+    /// Inserts the entry under a known UUID, replacing the existing one.
+    pub fn insert(&mut self, uuid: Uuid, entry: T) {
+        self.entries.insert(uuid, entry);
+    }
+
     pub fn update(&mut self, uuid: Uuid, entry: T) {
         if self.entries.contains_key(&uuid) {
             self.entries.insert(uuid, entry);
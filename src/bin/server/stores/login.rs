@@ -1,4 +1,4 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::net::SocketAddr;
 
 use capnp::Error;
@@ -9,6 +9,10 @@ use crate::datatypes::Username;
 #[derive(Default)]
 pub struct LoginStore {
     usernames_per_socket: HashMap<SocketAddr, Username>,
+    /// Secret that grants admin access. Nobody is an admin without it
+    admin_token: Option<String>,
+    /// Peers that presented the admin token
+    admin_peers: HashSet<SocketAddr>,
 }
 
 impl LoginStore {
@@ -30,11 +34,80 @@ impl LoginStore {
         })
     }
 
-    pub fn log_peer_in(&mut self, peer: SocketAddr, username: String) {
-        self.usernames_per_socket.insert(peer, username);
+    /// Checks that the peer is logged in and presented the admin token.
+    pub fn check_admin(&self, peer: &SocketAddr) -> Result<Username, Error> {
+        let username = self.check_login(peer)?;
+
+        if self.admin_peers.contains(peer) {
+            Ok(username)
+        } else {
+            Err(Error::failed("Peer is not an admin".to_owned()))
+        }
+    }
+
+    /// Makes the logged in peer an admin until it logs out, if the token matches the configured one.
+    pub fn grant_admin(&mut self, peer: SocketAddr, token: &str) -> Result<Username, Error> {
+        let username = self.check_login(&peer)?;
+
+        let Some(admin_token) = &self.admin_token else {
+            return Err(Error::failed("Admin service is disabled on this server".to_owned()));
+        };
+        if !constant_time_eq(admin_token.as_bytes(), token.as_bytes()) {
+            return Err(Error::failed("Invalid admin token".to_owned()));
+        }
+
+        self.admin_peers.insert(peer);
+        Ok(username)
+    }
+
+    /// `None` disables the admin service.
+    pub fn set_admin_token(&mut self, token: Option<String>) {
+        self.admin_token = token;
+        self.admin_peers.clear();
+    }
+
+    /// Returns the username the peer was logged in as before, if any.
+    /// Logging in as another user drops the admin rights of the previous one.
+    pub fn log_peer_in(&mut self, peer: SocketAddr, username: String) -> Option<Username> {
+        let previous = self.usernames_per_socket.insert(peer, username.clone());
+        if previous.as_ref().is_some_and(|previous| *previous != username) {
+            self.admin_peers.remove(&peer);
+        }
+        previous
+    }
+
+    pub fn logged_in_count(&self) -> usize {
+        self.usernames_per_socket.len()
+    }
+
+    /// Returns the username the peer was logged in as, if any.
+    pub fn log_peer_out(&mut self, peer: &SocketAddr) -> Option<Username> {
+        self.admin_peers.remove(peer);
+        self.usernames_per_socket.remove(peer)
     }
+}
+
+/// Compares secrets without revealing the length of the matching prefix through timing.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn switching_user_drops_admin() {
+        let peer: SocketAddr = "127.0.0.1:4000".parse().unwrap();
+        let mut logins = LoginStore::new();
+        logins.set_admin_token(Some("secret".to_owned()));
 
-    pub fn log_peer_out(&mut self, peer: &SocketAddr) {
-        self.usernames_per_socket.remove(&peer);
+        logins.log_peer_in(peer, "alice".to_owned());
+        logins.grant_admin(peer, "secret").unwrap();
+        logins.log_peer_in(peer, "alice".to_owned());
+        assert!(logins.check_admin(&peer).is_ok());
+
+        logins.log_peer_in(peer, "mallory".to_owned());
+        assert!(logins.check_admin(&peer).is_err());
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,5 @@
+use crate::datatypes::Message;
+use super::Broadcast;
+
+/// Edited messages. Subscriptions only read the list forward, so they would not notice the edits on their own.
+pub type MessageEdits = Broadcast<Message>;
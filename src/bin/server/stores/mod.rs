@@ -1,5 +1,27 @@
 mod login;
+mod broadcast;
 mod crud;
+mod session;
+mod read_only;
+mod rate_limit;
+mod topic_events;
+mod topic_stats;
+mod topic_index;
+mod message_edits;
+mod presence;
+mod typing_notices;
+mod notifications;
 
 pub use login::*;
-pub use crud::*;
\ No newline at end of file
+pub use broadcast::*;
+pub use crud::*;
+pub use session::*;
+pub use read_only::*;
+pub use rate_limit::*;
+pub use topic_events::*;
+pub use topic_stats::*;
+pub use topic_index::*;
+pub use message_edits::*;
+pub use presence::*;
+pub use typing_notices::*;
+pub use notifications::*;
\ No newline at end of file
@@ -0,0 +1,85 @@
+use std::collections::{HashMap, HashSet};
+
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::datatypes::{Notification, Username};
+use super::Broadcast;
+
+/// Notifications kept for every user, the oldest ones are dropped past it.
+const MAX_NOTIFICATIONS_PER_USER: usize = 500;
+
+/// Mentions of every user, oldest first. Saved along with the messages.
+/// Only known users are notified, so mentions of made-up names are not kept.
+#[derive(Default, Serialize, Deserialize)]
+pub struct NotificationStore {
+    users: HashMap<Username, Vec<Notification>>,
+    /// Users that logged in or posted. Not saved, rebuilt from the messages on load.
+    #[serde(skip)]
+    known_users: HashSet<Username>,
+}
+
+impl NotificationStore {
+    /// The user logged in or posted, so mentions of them are kept from now on.
+    pub fn add_known_user(&mut self, username: &str) {
+        if !self.known_users.contains(username) {
+            self.known_users.insert(username.to_string());
+        }
+    }
+
+    /// Whether mentions of the user are kept. Users notified before are known as well.
+    pub fn is_known(&self, username: &str) -> bool {
+        self.known_users.contains(username) || self.users.contains_key(username)
+    }
+
+    pub fn add(&mut self, notification: Notification) {
+        let notifications = self.users.entry(notification.username.clone()).or_default();
+        notifications.push(notification);
+
+        let excess = notifications.len().saturating_sub(MAX_NOTIFICATIONS_PER_USER);
+        notifications.drain(..excess);
+    }
+
+    /// Newest first. A `limit` of 0 returns all of them.
+    pub fn get(&self, username: &str, unread_only: bool, limit: usize) -> Vec<Notification> {
+        let Some(notifications) = self.users.get(username) else {
+            return vec![];
+        };
+        let limit = if limit == 0 { usize::MAX } else { limit };
+
+        notifications.iter()
+            .rev()
+            .filter(|notification| !unread_only || !notification.read)
+            .take(limit)
+            .cloned()
+            .collect()
+    }
+
+    /// Marks notifications of the messages as read, or all of them if `message_uuids` is empty. Returns how many were unread.
+    pub fn mark_read(&mut self, username: &str, message_uuids: &[Uuid]) -> usize {
+        let Some(notifications) = self.users.get_mut(username) else {
+            return 0;
+        };
+
+        let mut marked = 0;
+        for notification in notifications.iter_mut() {
+            if !notification.read && (message_uuids.is_empty() || message_uuids.contains(&notification.message_uuid)) {
+                notification.read = true;
+                marked += 1;
+            }
+        }
+        marked
+    }
+
+    /// Notifications of every user, for exports.
+    pub fn get_all(&self) -> impl Iterator<Item = &Notification> {
+        self.users.values().flatten()
+    }
+
+    pub fn len(&self) -> usize {
+        self.users.values().map(Vec::len).sum()
+    }
+}
+
+/// New notifications.
+pub type NotificationEvents = Broadcast<Notification>;
@@ -0,0 +1,115 @@
+use std::collections::HashMap;
+
+use chrono::{DateTime, Duration, Utc};
+use tokio::sync::broadcast;
+
+use crate::datatypes::Username;
+use super::Broadcast;
+
+/// Connected users without posts or typing notices for this long are idle.
+const IDLE_AFTER_MINUTES: i64 = 5;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PresenceStatus {
+    Online,
+    Idle,
+    Offline,
+}
+
+#[derive(Clone, Debug)]
+pub struct UserPresence {
+    pub username: Username,
+    pub status: PresenceStatus,
+    /// Last post or typing notice, or when the user went offline
+    pub last_seen: DateTime<Utc>,
+}
+
+struct Entry {
+    /// Connections logged in as the user
+    sessions: usize,
+    status: PresenceStatus,
+    last_seen: DateTime<Utc>,
+}
+
+/// Presence of every user that logged in since the start. Not saved, everybody is offline after a restart.
+/// Changes are published to the watchers on every worker thread.
+#[derive(Default)]
+pub struct PresenceStore {
+    users: HashMap<Username, Entry>,
+    changes: Broadcast<UserPresence>,
+}
+
+impl PresenceStore {
+    /// A connection logged in as the user.
+    pub fn connect(&mut self, username: &str, now: DateTime<Utc>) {
+        let entry = self.users.entry(username.to_string())
+            .or_insert(Entry { sessions: 0, status: PresenceStatus::Offline, last_seen: now });
+        entry.sessions += 1;
+        entry.last_seen = now;
+        self.set_status(username, PresenceStatus::Online);
+    }
+
+    /// A connection of the user logged out or was closed.
+    pub fn disconnect(&mut self, username: &str, now: DateTime<Utc>) {
+        let Some(entry) = self.users.get_mut(username) else {
+            return;
+        };
+        entry.sessions = entry.sessions.saturating_sub(1);
+        if entry.sessions == 0 {
+            entry.last_seen = now;
+            self.set_status(username, PresenceStatus::Offline);
+        }
+    }
+
+    /// The user posted or typed something, which brings an idle user back online.
+    pub fn touch(&mut self, username: &str, now: DateTime<Utc>) {
+        let Some(entry) = self.users.get_mut(username) else {
+            return;
+        };
+        entry.last_seen = now;
+        if entry.status == PresenceStatus::Idle {
+            self.set_status(username, PresenceStatus::Online);
+        }
+    }
+
+    /// Marks online users without activity for a while as idle. Called periodically.
+    pub fn mark_idle(&mut self, now: DateTime<Utc>) {
+        let idle_after = Duration::minutes(IDLE_AFTER_MINUTES);
+        let idle = self.users.iter()
+            .filter(|(_, entry)| entry.status == PresenceStatus::Online && now - entry.last_seen >= idle_after)
+            .map(|(username, _)| username.clone())
+            .collect::<Vec<_>>();
+        for username in idle {
+            self.set_status(&username, PresenceStatus::Idle);
+        }
+    }
+
+    /// Presence of the user, offline for users that never logged in.
+    pub fn get(&self, username: &str) -> UserPresence {
+        match self.users.get(username) {
+            Some(entry) => UserPresence { username: username.to_string(), status: entry.status, last_seen: entry.last_seen },
+            None => UserPresence { username: username.to_string(), status: PresenceStatus::Offline, last_seen: DateTime::<Utc>::MIN_UTC },
+        }
+    }
+
+    pub fn get_all(&self) -> Vec<UserPresence> {
+        self.users.keys()
+            .map(|username| self.get(username))
+            .collect()
+    }
+
+    /// Receives the changes published from now on.
+    pub fn watch(&self) -> broadcast::Receiver<UserPresence> {
+        self.changes.watch()
+    }
+
+    fn set_status(&mut self, username: &str, status: PresenceStatus) {
+        let Some(entry) = self.users.get_mut(username) else {
+            return;
+        };
+        if entry.status != status {
+            entry.status = status;
+            self.changes.publish(self.get(username));
+        }
+    }
+}
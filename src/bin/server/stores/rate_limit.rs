@@ -0,0 +1,315 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+use uuid::Uuid;
+
+use crate::datatypes::Username;
+
+/// Limits of one scope. `None` leaves that kind of calls unlimited.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct RateLimits {
+    /// Posted messages per second
+    pub messages: Option<f64>,
+    /// Posted content bytes per second
+    pub bytes: Option<f64>,
+    /// Subscription and history calls per second
+    pub reads: Option<f64>,
+}
+
+/// Limits of a single topic, parsed from `NAME:messages=5,bytes=1000,reads=2`. Omitted kinds stay unlimited.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TopicRateLimits {
+    pub topic_name: String,
+    pub limits: RateLimits,
+}
+
+impl FromStr for TopicRateLimits {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (topic_name, limits_str) = s.rsplit_once(':')
+            .ok_or_else(|| format!("Expected `TOPIC:kind=rate,...`, got '{s}'"))?;
+
+        let mut limits = RateLimits::default();
+        for limit in limits_str.split(',') {
+            let (kind, rate) = limit.split_once('=')
+                .ok_or_else(|| format!("Expected `kind=rate`, got '{limit}'"))?;
+            let rate = rate.parse::<f64>()
+                .map_err(|e| format!("Invalid rate '{rate}': {e}"))?;
+            let rate = (rate > 0.0).then_some(rate);
+
+            match kind {
+                "messages" => limits.messages = rate,
+                "bytes" => limits.bytes = rate,
+                "reads" => limits.reads = rate,
+                _ => return Err(format!("Unknown limit '{kind}', expected `messages`, `bytes` or `reads`")),
+            }
+        }
+
+        Ok(Self { topic_name: topic_name.to_string(), limits })
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+enum Kind {
+    Messages,
+    Bytes,
+    Reads,
+}
+
+#[derive(Clone, PartialEq, Eq, Hash)]
+enum Subject {
+    Peer(SocketAddr),
+    User(Username),
+}
+
+/// Bucket of a subject. Topic is set for the buckets of per-topic limits.
+type BucketKey = (Subject, Kind, Option<Uuid>);
+
+/// How often buckets that refilled completely are dropped.
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Holds up to one second worth of tokens and refills continuously.
+struct TokenBucket {
+    rate: f64,
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+impl TokenBucket {
+    fn new(rate: f64, now: Instant) -> Self {
+        Self { rate, tokens: rate.max(1.0), refilled_at: now }
+    }
+
+    fn capacity(&self) -> f64 {
+        self.rate.max(1.0)
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.duration_since(self.refilled_at).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.capacity());
+        self.refilled_at = now;
+    }
+
+    /// Full buckets are no different from new ones, so they can be dropped.
+    fn is_full(&self) -> bool {
+        self.tokens >= self.capacity()
+    }
+
+    /// Time until `cost` can be taken. Costs above the capacity only need a full bucket and leave a debt behind.
+    fn wait_time(&self, cost: f64) -> Duration {
+        let needed = cost.min(self.capacity());
+        if self.tokens >= needed {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64((needed - self.tokens) / self.rate)
+        }
+    }
+}
+
+/// Token buckets of every connection and every user.
+///
+/// Global limits apply to everything a subject does. Topics with their own limits get separate buckets on top,
+/// so a call has to fit into both.
+#[derive(Default)]
+pub struct RateLimiter {
+    global: RateLimits,
+    per_topic: HashMap<String, RateLimits>,
+    buckets: HashMap<BucketKey, TokenBucket>,
+    swept_at: Option<Instant>,
+}
+
+impl RateLimiter {
+    pub fn configure(&mut self, global: RateLimits, per_topic: impl IntoIterator<Item = TopicRateLimits>) {
+        self.global = global;
+        self.per_topic = per_topic.into_iter()
+            .map(|topic| (topic.topic_name, topic.limits))
+            .collect();
+        self.buckets.clear();
+    }
+
+    /// Takes one message and its bytes from the buckets, or returns how long to wait before retrying.
+    pub fn check_post(&mut self, peer: SocketAddr, username: &str, topic_uuid: Uuid, topic_name: &str, bytes: usize) -> Result<(), Duration> {
+        self.check(peer, username, true, Some((topic_uuid, topic_name)), &[(Kind::Messages, 1.0), (Kind::Bytes, bytes as f64)])
+    }
+
+    /// Takes one subscription or history call from the buckets, or returns how long to wait before retrying.
+    pub fn check_read(&mut self, peer: SocketAddr, username: &str, topic_uuid: Uuid, topic_name: &str) -> Result<(), Duration> {
+        self.check(peer, username, true, Some((topic_uuid, topic_name)), &[(Kind::Reads, 1.0)])
+    }
+
+    /// Same as [`RateLimiter::check_read`] for calls that span topics, so only the global limits apply.
+    pub fn check_global_read(&mut self, peer: SocketAddr, username: &str) -> Result<(), Duration> {
+        self.check(peer, username, true, None, &[(Kind::Reads, 1.0)])
+    }
+
+    /// Same as [`RateLimiter::check_read`] with only the limits of the topic itself. Pattern subscriptions go through it
+    /// once for every topic they deliver from, the global limits are taken when they subscribe.
+    pub fn check_topic_read(&mut self, peer: SocketAddr, username: &str, topic_uuid: Uuid, topic_name: &str) -> Result<(), Duration> {
+        self.check(peer, username, false, Some((topic_uuid, topic_name)), &[(Kind::Reads, 1.0)])
+    }
+
+    /// Drops the buckets of a closed connection.
+    pub fn forget_peer(&mut self, peer: &SocketAddr) {
+        self.buckets.retain(|(subject, _, _), _| *subject != Subject::Peer(*peer));
+    }
+
+    /// Drops the buckets that refilled completely, so users that went idle do not stay in memory.
+    fn sweep(&mut self, now: Instant) {
+        if self.swept_at.is_some_and(|swept_at| now.duration_since(swept_at) < SWEEP_INTERVAL) {
+            return;
+        }
+        self.swept_at = Some(now);
+
+        self.buckets.retain(|_, bucket| {
+            bucket.refill(now);
+            !bucket.is_full()
+        });
+    }
+
+    fn check(&mut self, peer: SocketAddr, username: &str, global: bool, topic: Option<(Uuid, &str)>, costs: &[(Kind, f64)]) -> Result<(), Duration> {
+        let now = Instant::now();
+        self.sweep(now);
+        let topic_limits = topic.and_then(|(topic_uuid, topic_name)| Some((topic_uuid, *self.per_topic.get(topic_name)?)));
+
+        // Every bucket the call goes through, with its rate
+        let mut charges = vec![];
+        for subject in [Subject::Peer(peer), Subject::User(username.to_string())] {
+            for &(kind, cost) in costs {
+                if let Some(rate) = limit_of(&self.global, kind).filter(|_| global) {
+                    charges.push(((subject.clone(), kind, None), rate, cost));
+                }
+                if let Some((topic_uuid, rate)) = topic_limits.and_then(|(topic_uuid, limits)| Some((topic_uuid, limit_of(&limits, kind)?))) {
+                    charges.push(((subject.clone(), kind, Some(topic_uuid)), rate, cost));
+                }
+            }
+        }
+
+        // Nothing is taken unless the call fits into all the buckets
+        let mut retry_after = Duration::ZERO;
+        for (key, rate, cost) in &charges {
+            let bucket = self.buckets.entry(key.clone()).or_insert_with(|| TokenBucket::new(*rate, now));
+            bucket.refill(now);
+            retry_after = retry_after.max(bucket.wait_time(*cost));
+        }
+        if !retry_after.is_zero() {
+            return Err(retry_after);
+        }
+
+        for (key, _, cost) in charges {
+            if let Some(bucket) = self.buckets.get_mut(&key) {
+                bucket.tokens -= cost;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn limit_of(limits: &RateLimits, kind: Kind) -> Option<f64> {
+    match kind {
+        Kind::Messages => limits.messages,
+        Kind::Bytes => limits.bytes,
+        Kind::Reads => limits.reads,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bucket_allows_a_burst_and_refills_over_time() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(4.0, start);
+
+        bucket.tokens -= 4.0;
+        assert_eq!(bucket.wait_time(1.0), Duration::from_millis(250));
+
+        bucket.refill(start + Duration::from_millis(500));
+        assert_eq!(bucket.tokens, 2.0);
+        assert_eq!(bucket.wait_time(2.0), Duration::ZERO);
+
+        // A long pause only refills up to the burst size
+        bucket.refill(start + Duration::from_secs(10));
+        assert_eq!(bucket.tokens, 4.0);
+        assert!(bucket.is_full());
+    }
+
+    #[test]
+    fn bucket_costs_above_capacity_need_a_full_bucket() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(100.0, start);
+
+        assert_eq!(bucket.wait_time(1000.0), Duration::ZERO);
+        bucket.tokens -= 1000.0;
+        assert_eq!(bucket.wait_time(1.0), Duration::from_secs_f64(901.0 / 100.0));
+    }
+
+    #[test]
+    fn slow_rates_hold_at_least_one_token() {
+        let bucket = TokenBucket::new(0.5, Instant::now());
+        assert_eq!(bucket.wait_time(1.0), Duration::ZERO);
+    }
+
+    #[test]
+    fn sweep_drops_refilled_buckets() {
+        let mut limiter = RateLimiter::default();
+        limiter.configure(RateLimits { messages: Some(1.0), ..Default::default() }, []);
+        let peer = SocketAddr::from(([127, 0, 0, 1], 1234));
+
+        assert_eq!(limiter.check_post(peer, "alice", Uuid::nil(), "news", 10), Ok(()));
+        assert!(limiter.check_post(peer, "alice", Uuid::nil(), "news", 10).is_err());
+        assert_eq!(limiter.buckets.len(), 2);
+
+        let later = limiter.swept_at.unwrap() + SWEEP_INTERVAL;
+        limiter.sweep(later);
+        assert!(limiter.buckets.is_empty());
+    }
+
+    #[test]
+    fn topic_reads_skip_the_global_limits() {
+        let mut limiter = RateLimiter::default();
+        let news = TopicRateLimits::from_str("news:reads=1").unwrap();
+        limiter.configure(RateLimits { reads: Some(1.0), ..Default::default() }, [news]);
+        let peer = SocketAddr::from(([127, 0, 0, 1], 1234));
+        let (news, sports) = (Uuid::new_v4(), Uuid::new_v4());
+
+        assert_eq!(limiter.check_global_read(peer, "alice"), Ok(()));
+        // Topics without limits of their own cost nothing, the others one read each
+        assert_eq!(limiter.check_topic_read(peer, "alice", sports, "sports"), Ok(()));
+        assert_eq!(limiter.check_topic_read(peer, "alice", news, "news"), Ok(()));
+        assert!(limiter.check_topic_read(peer, "alice", news, "news").is_err());
+    }
+
+    #[test]
+    fn parses_topic_limits() {
+        let limits = TopicRateLimits::from_str("news:messages=5,bytes=1000").unwrap();
+        assert_eq!(limits, TopicRateLimits {
+            topic_name: "news".to_string(),
+            limits: RateLimits { messages: Some(5.0), bytes: Some(1000.0), reads: None },
+        });
+    }
+
+    #[test]
+    fn topic_name_may_contain_colons() {
+        let limits = TopicRateLimits::from_str("a:b:reads=2").unwrap();
+        assert_eq!(limits.topic_name, "a:b");
+        assert_eq!(limits.limits.reads, Some(2.0));
+    }
+
+    #[test]
+    fn zero_rate_means_unlimited() {
+        let limits = TopicRateLimits::from_str("news:messages=0").unwrap();
+        assert_eq!(limits.limits.messages, None);
+    }
+
+    #[test]
+    fn rejects_malformed_topic_limits() {
+        assert!(TopicRateLimits::from_str("news").is_err());
+        assert!(TopicRateLimits::from_str("news:messages").is_err());
+        assert!(TopicRateLimits::from_str("news:messages=fast").is_err());
+        assert!(TopicRateLimits::from_str("news:writes=5").is_err());
+    }
+}
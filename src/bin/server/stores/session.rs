@@ -0,0 +1,82 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+
+use chrono::{DateTime, Utc};
+use tokio_util::sync::CancellationToken;
+use uuid::Uuid;
+
+pub struct Session {
+    pub connected_at: DateTime<Utc>,
+    pub subscriptions: Vec<Uuid>,
+    pub pattern_subscriptions: Vec<String>,
+    kick: CancellationToken,
+}
+
+/// Every open connection, logged in or not.
+#[derive(Default)]
+pub struct SessionStore {
+    sessions: HashMap<SocketAddr, Session>,
+}
+
+impl SessionStore {
+    /// Registers the connection. Returned token is cancelled once the session is kicked.
+    pub fn open(&mut self, peer: SocketAddr) -> CancellationToken {
+        let kick = CancellationToken::new();
+        let session = Session {
+            connected_at: Utc::now(),
+            subscriptions: vec![],
+            pattern_subscriptions: vec![],
+            kick: kick.clone(),
+        };
+
+        self.sessions.insert(peer, session);
+        kick
+    }
+
+    pub fn close(&mut self, peer: &SocketAddr) {
+        self.sessions.remove(peer);
+    }
+
+    pub fn get_all(&self) -> impl Iterator<Item = (&SocketAddr, &Session)> {
+        self.sessions.iter()
+    }
+
+    pub fn sessions_count(&self) -> usize {
+        self.sessions.len()
+    }
+
+    pub fn add_subscription(&mut self, peer: &SocketAddr, topic_uuid: Uuid) {
+        if let Some(session) = self.sessions.get_mut(peer) {
+            session.subscriptions.push(topic_uuid);
+        }
+    }
+
+    pub fn remove_subscriptions(&mut self, peer: &SocketAddr, topic_uuid: Uuid) {
+        if let Some(session) = self.sessions.get_mut(peer) {
+            session.subscriptions.retain(|uuid| *uuid != topic_uuid);
+        }
+    }
+
+    pub fn add_pattern_subscription(&mut self, peer: &SocketAddr, pattern: &str) {
+        if let Some(session) = self.sessions.get_mut(peer) {
+            session.pattern_subscriptions.push(pattern.to_string());
+        }
+    }
+
+    pub fn remove_pattern_subscriptions(&mut self, peer: &SocketAddr, pattern: &str) {
+        if let Some(session) = self.sessions.get_mut(peer) {
+            session.pattern_subscriptions.retain(|subscribed| subscribed != pattern);
+        }
+    }
+
+    /// Returns `false` if there is no such session.
+    pub fn kick(&self, peer: &SocketAddr) -> bool {
+        match self.sessions.get(peer) {
+            Some(session) => {
+                session.kick.cancel();
+                true
+            }
+            None => false,
+        }
+    }
+}
\ No newline at end of file
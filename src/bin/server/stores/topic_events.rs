@@ -0,0 +1,30 @@
+use uuid::Uuid;
+
+use crate::datatypes::Topic;
+use super::Broadcast;
+
+#[derive(Clone, Debug)]
+pub enum TopicEventKind {
+    Created,
+    Renamed { old_name: String },
+    RetentionChanged,
+    Deleted,
+    MetadataChanged,
+}
+
+/// Change of a topic, along with its state after the change, or before the deletion.
+#[derive(Clone, Debug)]
+pub struct TopicEvent {
+    pub uuid: Uuid,
+    pub topic: Topic,
+    pub kind: TopicEventKind,
+}
+
+impl TopicEvent {
+    pub fn new(uuid: Uuid, topic: &Topic, kind: TopicEventKind) -> Self {
+        Self { uuid, topic: topic.clone(), kind }
+    }
+}
+
+/// Lifecycle events of topics.
+pub type TopicEvents = Broadcast<TopicEvent>;
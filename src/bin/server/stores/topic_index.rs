@@ -0,0 +1,140 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::ops::Bound;
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::datatypes::{Topic, Username};
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum TopicSort {
+    #[default]
+    Name,
+    CreatedAt,
+    LastActivity,
+}
+
+/// Place of a topic in the sort order. Ties are broken by the UUID.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum SortKey {
+    Name(String),
+    /// Nanoseconds since the epoch
+    Time(i64),
+}
+
+impl SortKey {
+    fn time(timestamp: DateTime<Utc>) -> Self {
+        Self::Time(timestamp.timestamp_nanos_opt().unwrap_or(i64::MAX))
+    }
+}
+
+/// Public topics in every order `listTopics` sorts by, so pages are read in order instead of sorting all the topics.
+/// Updated along with the topics and their last activity.
+#[derive(Default)]
+pub struct TopicIndex {
+    /// Indexed by [`TopicSort`]
+    sorted: [BTreeSet<(SortKey, Uuid)>; 3],
+    keys: HashMap<Uuid, [SortKey; 3]>,
+}
+
+impl TopicIndex {
+    /// Adds the topic or moves it to its new place. Direct conversations are not listed, so they are left out.
+    pub fn insert(&mut self, uuid: Uuid, topic: &Topic, last_activity: DateTime<Utc>) {
+        self.remove(uuid);
+        if !topic.participants.is_empty() {
+            return;
+        }
+
+        let keys = [SortKey::Name(topic.name.clone()), SortKey::time(topic.timestamp), SortKey::time(last_activity)];
+        for (sorted, key) in self.sorted.iter_mut().zip(&keys) {
+            sorted.insert((key.clone(), uuid));
+        }
+        self.keys.insert(uuid, keys);
+    }
+
+    /// Moves the topic forward in the last activity order.
+    pub fn touch(&mut self, uuid: Uuid, timestamp: DateTime<Utc>) {
+        let Some(keys) = self.keys.get_mut(&uuid) else {
+            return;
+        };
+        let key = SortKey::time(timestamp);
+        let activity = &mut keys[TopicSort::LastActivity as usize];
+        if key <= *activity {
+            return;
+        }
+
+        let sorted = &mut self.sorted[TopicSort::LastActivity as usize];
+        sorted.remove(&(activity.clone(), uuid));
+        sorted.insert((key.clone(), uuid));
+        *activity = key;
+    }
+
+    pub fn remove(&mut self, uuid: Uuid) {
+        let Some(keys) = self.keys.remove(&uuid) else {
+            return;
+        };
+        for (sorted, key) in self.sorted.iter_mut().zip(keys) {
+            sorted.remove(&(key, uuid));
+        }
+    }
+
+    /// Topics in the order, starting right after `after`.
+    pub fn iter(&self, sort: TopicSort, descending: bool, after: Option<&(SortKey, Uuid)>) -> Box<dyn Iterator<Item = &(SortKey, Uuid)> + '_> {
+        let sorted = &self.sorted[sort as usize];
+        match (after, descending) {
+            (None, false) => Box::new(sorted.iter()),
+            (None, true) => Box::new(sorted.iter().rev()),
+            (Some(after), false) => Box::new(sorted.range((Bound::Excluded(after), Bound::Unbounded))),
+            (Some(after), true) => Box::new(sorted.range((Bound::Unbounded, Bound::Excluded(after))).rev()),
+        }
+    }
+}
+
+/// Direct conversations by their sorted participants, so sending a direct message does not scan every topic.
+#[derive(Default)]
+pub struct ConversationIndex {
+    conversations: BTreeMap<Vec<Username>, Uuid>,
+}
+
+impl ConversationIndex {
+    pub fn get(&self, participants: &[Username]) -> Option<Uuid> {
+        self.conversations.get(participants).copied()
+    }
+
+    /// Adds the conversation of the topic. Topics that are not direct conversations are left out.
+    pub fn insert(&mut self, uuid: Uuid, topic: &Topic) {
+        if !topic.participants.is_empty() {
+            self.conversations.insert(topic.participants.clone(), uuid);
+        }
+    }
+
+    pub fn remove(&mut self, uuid: Uuid, topic: &Topic) {
+        if self.get(&topic.participants) == Some(uuid) {
+            self.conversations.remove(&topic.participants);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn indexes_conversations_by_participants() {
+        let mut conversations = ConversationIndex::default();
+        let topic = Topic { participants: vec!["alice".to_string(), "bob".to_string()], ..Default::default() };
+        let (first, second) = (Uuid::new_v4(), Uuid::new_v4());
+
+        conversations.insert(first, &topic);
+        conversations.insert(second, &Topic { name: "news".to_string(), ..Default::default() });
+        assert_eq!(conversations.get(&topic.participants), Some(first));
+        assert_eq!(conversations.get(&[]), None);
+
+        // Removing another topic of the same participants keeps the conversation
+        conversations.remove(second, &topic);
+        assert_eq!(conversations.get(&topic.participants), Some(first));
+        conversations.remove(first, &topic);
+        assert_eq!(conversations.get(&topic.participants), None);
+    }
+}
@@ -0,0 +1,115 @@
+use std::collections::HashMap;
+
+use chrono::{DateTime, Utc};
+use uuid::Uuid;
+
+use crate::datatypes::Message;
+
+/// Seconds covered by the posting rate.
+const RATE_WINDOW: usize = 60;
+
+/// Posts per second of the last minute, in a ring indexed by the second.
+#[derive(Clone, Debug)]
+struct RecentPosts {
+    seconds: [i64; RATE_WINDOW],
+    counts: [u32; RATE_WINDOW],
+}
+
+impl Default for RecentPosts {
+    fn default() -> Self {
+        Self { seconds: [i64::MIN; RATE_WINDOW], counts: [0; RATE_WINDOW] }
+    }
+}
+
+impl RecentPosts {
+    fn add(&mut self, timestamp: DateTime<Utc>) {
+        let second = timestamp.timestamp();
+        let index = second.rem_euclid(RATE_WINDOW as i64) as usize;
+
+        // The slot already counts a newer second, this post is too old to matter
+        if self.seconds[index] > second {
+            return;
+        }
+        if self.seconds[index] != second {
+            self.seconds[index] = second;
+            self.counts[index] = 0;
+        }
+        self.counts[index] += 1;
+    }
+
+    fn last_minute(&self, now: DateTime<Utc>) -> u32 {
+        let now = now.timestamp();
+        self.seconds.iter()
+            .zip(self.counts)
+            .filter(|(&second, _)| second > now - RATE_WINDOW as i64 && second <= now)
+            .map(|(_, count)| count)
+            .sum()
+    }
+}
+
+/// Counters of a single topic.
+#[derive(Clone, Debug, Default)]
+pub struct TopicCounters {
+    pub messages: u64,
+    /// Content, payloads and headers
+    pub bytes: u64,
+    pub oldest_message: Option<DateTime<Utc>>,
+    pub newest_message: Option<DateTime<Utc>>,
+    /// Last rename, retention or metadata change
+    pub last_change: Option<DateTime<Utc>>,
+    recent_posts: RecentPosts,
+}
+
+impl TopicCounters {
+    /// Newest message or change, or `created_at` if there were none.
+    pub fn last_activity(&self, created_at: DateTime<Utc>) -> DateTime<Utc> {
+        [self.newest_message, self.last_change].into_iter()
+            .flatten()
+            .fold(created_at, DateTime::max)
+    }
+
+    pub fn posts_last_minute(&self, now: DateTime<Utc>) -> u32 {
+        self.recent_posts.last_minute(now)
+    }
+}
+
+/// Statistics of every topic, kept up to date as messages are posted, so they never scan the messages.
+/// Nothing removes messages yet, so there is no way to take them off the counters either.
+#[derive(Default)]
+pub struct TopicStats {
+    topics: HashMap<Uuid, TopicCounters>,
+}
+
+impl TopicStats {
+    pub fn record_message(&mut self, message: &Message) {
+        let counters = self.topics.entry(message.topic_uuid).or_default();
+        counters.messages += 1;
+        counters.bytes += message.size() as u64;
+        counters.oldest_message = Some(counters.oldest_message.map_or(message.timestamp, |oldest| oldest.min(message.timestamp)));
+        counters.newest_message = Some(counters.newest_message.map_or(message.timestamp, |newest| newest.max(message.timestamp)));
+        counters.recent_posts.add(message.timestamp);
+    }
+
+    /// Edits change the bytes, but not the number of messages or their timestamps.
+    pub fn record_edit(&mut self, topic_uuid: Uuid, old_size: usize, new_size: usize) {
+        let counters = self.topics.entry(topic_uuid).or_default();
+        counters.bytes = (counters.bytes + new_size as u64).saturating_sub(old_size as u64);
+    }
+
+    pub fn record_change(&mut self, topic_uuid: Uuid, timestamp: DateTime<Utc>) {
+        self.topics.entry(topic_uuid).or_default().last_change = Some(timestamp);
+    }
+
+    /// Counters of the topic, all zeroes if nothing happened to it yet.
+    pub fn get(&self, topic_uuid: Uuid) -> TopicCounters {
+        self.topics.get(&topic_uuid).cloned().unwrap_or_default()
+    }
+
+    pub fn last_activity(&self, topic_uuid: Uuid, created_at: DateTime<Utc>) -> DateTime<Utc> {
+        self.topics.get(&topic_uuid).map_or(created_at, |counters| counters.last_activity(created_at))
+    }
+
+    pub fn forget(&mut self, topic_uuid: Uuid) {
+        self.topics.remove(&topic_uuid);
+    }
+}
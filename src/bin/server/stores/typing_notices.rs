@@ -0,0 +1,13 @@
+use uuid::Uuid;
+
+use crate::datatypes::Username;
+use super::Broadcast;
+
+#[derive(Clone, Debug)]
+pub struct TypingNotice {
+    pub topic_uuid: Uuid,
+    pub username: Username,
+}
+
+/// Users typing in topics. Notices are ephemeral: they are never stored along with the messages.
+pub type TypingNotices = Broadcast<TypingNotice>;
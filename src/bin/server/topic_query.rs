@@ -0,0 +1,230 @@
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use broker::topic_capnp::{self, topic_query};
+use capnp::Error;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::datatypes::Topic;
+use crate::stores::{CrudStore, SortKey, TopicIndex, TopicSort};
+
+const DEFAULT_PAGE_SIZE: usize = 50;
+const MAX_PAGE_SIZE: usize = 500;
+
+/// Last topic of a page, handed out as an opaque `nextCursor`.
+#[derive(Serialize, Deserialize)]
+struct Cursor {
+    sort: TopicSort,
+    descending: bool,
+    key: SortKey,
+    uuid: Uuid,
+}
+
+impl Cursor {
+    fn encode(&self) -> String {
+        URL_SAFE_NO_PAD.encode(serde_json::to_vec(self).unwrap_or_default())
+    }
+
+    fn decode(cursor: &str) -> Option<Self> {
+        let bytes = URL_SAFE_NO_PAD.decode(cursor).ok()?;
+        serde_json::from_slice(&bytes).ok()
+    }
+
+    /// Position to continue after, or the reason to reject the cursor. Empty cursors start from the beginning.
+    fn read(cursor: &str, sort: TopicSort, descending: bool) -> Result<Option<(SortKey, Uuid)>, String> {
+        match cursor {
+            "" => Ok(None),
+            cursor => match Self::decode(cursor) {
+                None => Err("Malformed cursor".to_string()),
+                Some(cursor) if cursor.sort != sort || cursor.descending != descending => {
+                    Err("Cursor belongs to another sort order".to_string())
+                }
+                Some(cursor) => Ok(Some((cursor.key, cursor.uuid))),
+            },
+        }
+    }
+}
+
+/// A single page request of `listTopics`.
+#[derive(Clone, Debug)]
+pub struct TopicQuery {
+    name_prefix: String,
+    /// Lowercase
+    search: String,
+    tags: Vec<String>,
+    sort: TopicSort,
+    descending: bool,
+    page_size: usize,
+    after: Option<(SortKey, Uuid)>,
+}
+
+impl TopicQuery {
+    /// Reads the query of a request. The outer error is a broken request, the inner one is the reason to reject the cursor.
+    pub fn read_capnp(reader: topic_query::Reader<'_>) -> Result<Result<Self, String>, Error> {
+        let sort = match reader.get_sort()? {
+            topic_capnp::TopicSort::Name => TopicSort::Name,
+            topic_capnp::TopicSort::CreatedAt => TopicSort::CreatedAt,
+            topic_capnp::TopicSort::LastActivity => TopicSort::LastActivity,
+        };
+        let descending = reader.get_descending();
+
+        let page_size = match reader.get_page_size() {
+            0 => DEFAULT_PAGE_SIZE,
+            page_size => (page_size as usize).min(MAX_PAGE_SIZE),
+        };
+
+        let after = match Cursor::read(reader.get_cursor()?.to_str()?, sort, descending) {
+            Ok(after) => after,
+            Err(reason) => return Ok(Err(reason)),
+        };
+
+        Ok(Ok(Self {
+            name_prefix: reader.get_name_prefix()?.to_string()?,
+            search: reader.get_search()?.to_str()?.trim().to_lowercase(),
+            tags: reader.get_tags()?.iter()
+                .map(|tag| Ok(tag?.to_str()?.trim().to_string()))
+                .collect::<Result<_, Error>>()?,
+            sort,
+            descending,
+            page_size,
+            after,
+        }))
+    }
+
+    /// Walks the index from the cursor and returns the first matching topics, along with the cursor of the next page.
+    pub fn page(&self, index: &TopicIndex, topics: &CrudStore<Topic>) -> (Vec<(Uuid, Topic)>, Option<String>) {
+        let mut page = Vec::with_capacity(self.page_size);
+        let mut next_cursor = None;
+
+        for (key, uuid) in index.iter(self.sort, self.descending, self.after.as_ref()) {
+            let Some(topic) = topics.get(*uuid) else {
+                continue;
+            };
+            if !topic.name.starts_with(&self.name_prefix) || !matches_search(&topic, &self.search, &self.tags) {
+                continue;
+            }
+
+            // Another matching topic means there is a next page, which starts after the last one of this page
+            if page.len() == self.page_size {
+                next_cursor = page.last().map(|(key, uuid, _): &(SortKey, Uuid, Topic)| {
+                    Cursor { sort: self.sort, descending: self.descending, key: key.clone(), uuid: *uuid }.encode()
+                });
+                break;
+            }
+            page.push((key.clone(), *uuid, topic));
+        }
+
+        let page = page.into_iter()
+            .map(|(_, uuid, topic)| (uuid, topic))
+            .collect();
+        (page, next_cursor)
+    }
+}
+
+/// Whether the name or the description contain `search`, which has to be lowercase, and the topic has all the `tags`.
+pub fn matches_search(topic: &Topic, search: &str, tags: &[String]) -> bool {
+    let found = search.is_empty()
+        || topic.name.to_lowercase().contains(search)
+        || topic.description.to_lowercase().contains(search);
+    found && tags.iter().all(|tag| topic.tags.contains(tag))
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{Duration, Utc};
+
+    use super::*;
+
+    fn query(sort: TopicSort, descending: bool, page_size: usize, after: Option<(SortKey, Uuid)>) -> TopicQuery {
+        TopicQuery { name_prefix: String::new(), search: String::new(), tags: vec![], sort, descending, page_size, after }
+    }
+
+    fn store(names: &[&str]) -> (CrudStore<Topic>, TopicIndex) {
+        let mut topics = CrudStore::default();
+        let mut index = TopicIndex::default();
+        let start = Utc::now();
+        for (offset, name) in names.iter().enumerate() {
+            let topic = Topic { name: name.to_string(), timestamp: start + Duration::seconds(offset as i64), ..Default::default() };
+            let uuid = topics.create(topic.clone());
+            index.insert(uuid, &topic, topic.timestamp);
+        }
+        (topics, index)
+    }
+
+    fn names(page: &[(Uuid, Topic)]) -> Vec<&str> {
+        page.iter().map(|(_, topic)| topic.name.as_str()).collect()
+    }
+
+    #[test]
+    fn cursor_round_trips() {
+        let uuid = Uuid::new_v4();
+        let cursor = Cursor { sort: TopicSort::CreatedAt, descending: true, key: SortKey::Time(42), uuid }.encode();
+
+        assert_eq!(Cursor::read(&cursor, TopicSort::CreatedAt, true), Ok(Some((SortKey::Time(42), uuid))));
+    }
+
+    #[test]
+    fn empty_cursor_starts_from_the_beginning() {
+        assert_eq!(Cursor::read("", TopicSort::Name, false), Ok(None));
+    }
+
+    #[test]
+    fn rejects_malformed_cursors() {
+        assert!(Cursor::read("not a cursor", TopicSort::Name, false).is_err());
+        assert!(Cursor::read(&URL_SAFE_NO_PAD.encode("{}"), TopicSort::Name, false).is_err());
+    }
+
+    #[test]
+    fn rejects_cursors_of_another_query() {
+        let cursor = Cursor { sort: TopicSort::Name, descending: false, key: SortKey::Name("a".to_string()), uuid: Uuid::nil() }.encode();
+
+        assert!(Cursor::read(&cursor, TopicSort::CreatedAt, false).is_err());
+        assert!(Cursor::read(&cursor, TopicSort::Name, true).is_err());
+    }
+
+    #[test]
+    fn pages_continue_after_the_cursor() {
+        let (topics, index) = store(&["c", "a", "e", "b", "d"]);
+
+        let (page, cursor) = query(TopicSort::Name, false, 2, None).page(&index, &topics);
+        assert_eq!(names(&page), ["a", "b"]);
+
+        let after = Cursor::read(&cursor.unwrap(), TopicSort::Name, false).unwrap();
+        let (page, cursor) = query(TopicSort::Name, false, 2, after).page(&index, &topics);
+        assert_eq!(names(&page), ["c", "d"]);
+
+        let after = Cursor::read(&cursor.unwrap(), TopicSort::Name, false).unwrap();
+        let (page, cursor) = query(TopicSort::Name, false, 2, after).page(&index, &topics);
+        assert_eq!(names(&page), ["e"]);
+        assert_eq!(cursor, None);
+    }
+
+    #[test]
+    fn full_last_page_has_no_cursor() {
+        let (topics, index) = store(&["a", "b"]);
+
+        let (page, cursor) = query(TopicSort::CreatedAt, true, 2, None).page(&index, &topics);
+        assert_eq!(names(&page), ["b", "a"]);
+        assert_eq!(cursor, None);
+    }
+
+    #[test]
+    fn activity_moves_topics_forward() {
+        let (topics, mut index) = store(&["a", "b", "c"]);
+        let (uuid, _) = topics.get_all().into_iter().find(|(_, topic)| topic.name == "a").unwrap();
+        index.touch(uuid, Utc::now() + Duration::hours(1));
+
+        let (page, _) = query(TopicSort::LastActivity, true, 10, None).page(&index, &topics);
+        assert_eq!(names(&page), ["a", "c", "b"]);
+    }
+
+    #[test]
+    fn filters_by_prefix_and_search() {
+        let (topics, index) = store(&["builds.linux", "builds.mac", "alerts.disk"]);
+
+        let mut query = query(TopicSort::Name, false, 10, None);
+        query.name_prefix = "builds.".to_string();
+        query.search = "mac".to_string();
+        let (page, _) = query.page(&index, &topics);
+        assert_eq!(names(&page), ["builds.mac"]);
+    }
+}
@@ -0,0 +1,112 @@
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+
+use tokio::net::TcpStream;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+use tokio::task::LocalSet;
+use tokio_util::task::TaskTracker;
+use tracing::{error, warn};
+
+use crate::server::{ListenerConfig, Server};
+
+type Connection = (std::net::TcpStream, SocketAddr);
+
+/// A pool of threads, each running its own single-threaded runtime with a [`LocalSet`].
+///
+/// `capnp-rpc` objects are not `Send`, so a connection lives on one worker for its whole life.
+/// Connections are handed off to the worker with the least active connections.
+/// All workers share the same [`Server`] and therefore the same stores.
+pub struct WorkerPool {
+    workers: Vec<Worker>,
+}
+
+struct Worker {
+    sender: UnboundedSender<Connection>,
+    active_connections: Arc<AtomicUsize>,
+    thread: JoinHandle<()>,
+}
+
+impl WorkerPool {
+    /// Spawns `config.workers` worker threads. Connections are spawned into `connections`, so they can be awaited on shutdown.
+    pub fn new(server: Arc<Server>, config: ListenerConfig, connections: TaskTracker) -> std::io::Result<Self> {
+        assert!(config.workers > 0, "Worker pool needs at least one worker");
+
+        let workers = (0..config.workers)
+            .map(|index| Worker::spawn(index, server.clone(), config.clone(), connections.clone()))
+            .collect::<std::io::Result<Vec<_>>>()?;
+
+        Ok(Self { workers })
+    }
+
+    pub fn workers_count(&self) -> usize {
+        self.workers.len()
+    }
+
+    /// Hands the connection off to the least loaded worker.
+    pub fn dispatch(&self, stream: TcpStream, addr: SocketAddr) -> std::io::Result<()> {
+        let worker = self.workers.iter()
+            .min_by_key(|worker| worker.active_connections.load(Ordering::Relaxed))
+            .unwrap();
+
+        // Tokio streams are bound to the runtime that created them, so the stream travels as a std one.
+        let stream = stream.into_std()?;
+        worker.active_connections.fetch_add(1, Ordering::Relaxed);
+        worker.sender.send((stream, addr))
+            .map_err(|_| std::io::Error::new(std::io::ErrorKind::BrokenPipe, "Worker thread is not running"))
+    }
+
+    /// Stops the workers and waits for their threads. Connections still running on them are dropped.
+    pub fn join(self) {
+        for worker in self.workers {
+            drop(worker.sender);
+            if worker.thread.join().is_err() {
+                error!("Worker thread panicked");
+            }
+        }
+    }
+}
+
+impl Worker {
+    fn spawn(index: usize, server: Arc<Server>, config: ListenerConfig, connections: TaskTracker) -> std::io::Result<Self> {
+        let (sender, receiver) = unbounded_channel();
+        let active_connections = Arc::new(AtomicUsize::new(0));
+
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()?;
+
+        let counter = active_connections.clone();
+        let thread = std::thread::Builder::new()
+            .name(format!("worker-{index}"))
+            .spawn(move || {
+                let local_set = LocalSet::new();
+                local_set.block_on(&runtime, run_worker(server, config, receiver, counter, connections));
+            })?;
+
+        Ok(Self { sender, active_connections, thread })
+    }
+}
+
+async fn run_worker(server: Arc<Server>, config: ListenerConfig, mut receiver: UnboundedReceiver<Connection>, active_connections: Arc<AtomicUsize>, connections: TaskTracker) {
+    while let Some((stream, addr)) = receiver.recv().await {
+        let stream = match stream.set_nonblocking(true).and_then(|_| TcpStream::from_std(stream)) {
+            Ok(stream) => stream,
+            Err(e) => {
+                warn!(peer = %addr, error = %e, "Failed to take over connection");
+                active_connections.fetch_sub(1, Ordering::Relaxed);
+                continue;
+            }
+        };
+
+        let server = server.clone();
+        let config = config.clone();
+        let active_connections = active_connections.clone();
+
+        connections.spawn_local(async move {
+            server.process_connection(stream, addr, config).await;
+            active_connections.fetch_sub(1, Ordering::Relaxed);
+        });
+    }
+}
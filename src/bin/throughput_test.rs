@@ -0,0 +1,189 @@
+use std::net::SocketAddr;
+use std::process::{Command, Stdio};
+use std::str::FromStr;
+use std::sync::{Arc, Barrier};
+use std::time::{Duration, Instant};
+
+use broker::main_capnp::root_service;
+use broker::message_capnp::message_service;
+use broker::util::stream_to_rpc_network;
+use broker::util_capnp;
+use capnp_rpc::{rpc_twoparty_capnp, RpcSystem};
+use clap::Parser;
+use futures::stream::FuturesUnordered;
+use futures::StreamExt;
+use tokio::net::TcpStream;
+use tokio::task::LocalSet;
+use uuid::Uuid;
+
+type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+/// Measures how `postMessage` throughput scales with the amount of server worker threads.
+/// Launches the `server` binary next to this one for every worker count and floods it from parallel clients.
+#[derive(Parser, Debug, Clone)]
+pub struct CliArgs {
+    /// Worker counts to run the server with
+    #[arg(short, long, default_values_t = [1, 2, 4])]
+    pub workers: Vec<usize>,
+
+    /// Amount of parallel client connections
+    #[arg(short, long, default_value_t = 32)]
+    pub clients: usize,
+
+    /// Messages posted by every client
+    #[arg(short, long, default_value_t = 2000)]
+    pub messages: usize,
+
+    /// Requests every client keeps in flight
+    #[arg(short, long, default_value_t = 64)]
+    pub pipeline: usize,
+
+    #[arg(short, long, default_value_t = SocketAddr::from_str("127.0.0.1:8090").unwrap())]
+    pub address: SocketAddr,
+}
+
+fn main() -> Result<(), BoxError> {
+    let args = CliArgs::parse();
+    let server_path = std::env::current_exe()?.with_file_name("server");
+    let state_file = std::env::temp_dir().join(format!("throughput_test_{}.bin", std::process::id()));
+
+    println!("{} clients x {} messages, {} requests in flight per client", args.clients, args.messages, args.pipeline);
+    println!("workers | messages/s");
+
+    for &workers in &args.workers {
+        let mut server = Command::new(&server_path)
+            .arg("--address").arg(args.address.to_string())
+            .arg("--state-file").arg(&state_file)
+            .arg("--workers").arg(workers.to_string())
+            .arg("--rate-limit-messages").arg("0")
+            .arg("--rate-limit-bytes").arg("0")
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()?;
+
+        let result = run_clients(&args);
+
+        server.kill()?;
+        server.wait()?;
+        let _ = std::fs::remove_file(&state_file);
+
+        let rate = result?;
+        println!("{workers:>7} | {rate:.0}");
+    }
+
+    Ok(())
+}
+
+/// Runs every client on its own thread and returns the total amount of posted messages per second.
+fn run_clients(args: &CliArgs) -> Result<f64, BoxError> {
+    let topic_uuid = run_local(prepare_topic(args.address))?;
+
+    // Everyone waits until all clients are connected, so only posting is measured
+    let barrier = Arc::new(Barrier::new(args.clients + 1));
+
+    let threads = (0..args.clients)
+        .map(|i| {
+            let barrier = barrier.clone();
+            let args = args.clone();
+            std::thread::spawn(move || run_local(post_messages(i, args, topic_uuid, barrier)))
+        })
+        .collect::<Vec<_>>();
+
+    barrier.wait();
+    let start = Instant::now();
+
+    let mut total = 0;
+    for thread in threads {
+        total += thread.join().map_err(|_| "Client thread panicked")??;
+    }
+
+    Ok(total as f64 / start.elapsed().as_secs_f64())
+}
+
+fn run_local<T>(future: impl std::future::Future<Output = Result<T, BoxError>>) -> Result<T, BoxError> {
+    let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build()?;
+    LocalSet::new().block_on(&runtime, future)
+}
+
+/// Connects to the server and logs in.
+async fn connect(addr: SocketAddr, username: &str) -> Result<root_service::Client, BoxError> {
+    // Server may still be starting up
+    let mut attempts = 0;
+    let stream = loop {
+        match TcpStream::connect(addr).await {
+            Ok(stream) => break stream,
+            Err(e) if attempts >= 50 => return Err(e.into()),
+            Err(_) => {
+                attempts += 1;
+                tokio::time::sleep(Duration::from_millis(100)).await;
+            }
+        }
+    };
+    let _ = stream.set_nodelay(true);
+
+    let network = stream_to_rpc_network(stream);
+    let mut rpc_system = RpcSystem::new(Box::new(network), None);
+    let root: root_service::Client = rpc_system.bootstrap(rpc_twoparty_capnp::Side::Client);
+    tokio::task::spawn_local(rpc_system);
+
+    let auth = root.auth_request().send().promise.await?.get()?.get_service()?;
+    let mut login = auth.login_request();
+    login.get().set_username(username);
+    login.send().promise.await?;
+
+    Ok(root)
+}
+
+async fn prepare_topic(addr: SocketAddr) -> Result<Uuid, BoxError> {
+    let root = connect(addr, "throughput_test").await?;
+    let topic_service = root.topic_request().send().promise.await?.get()?.get_service()?;
+
+    let mut request = topic_service.create_topic_request();
+    request.get().set_name("throughput_test");
+    let response = request.send().promise.await?;
+
+    match response.get()?.get_topic()?.which()? {
+        util_capnp::result::Which::Ok(topic) => {
+            let uuid = topic?.get_uuid()?;
+            Ok(Uuid::from_u64_pair(uuid.get_upper(), uuid.get_lower()))
+        }
+        util_capnp::result::Which::Err(_) => Err("Failed to create the topic".into()),
+    }
+}
+
+async fn post_messages(client_id: usize, args: CliArgs, topic_uuid: Uuid, barrier: Arc<Barrier>) -> Result<usize, BoxError> {
+    let root = connect(args.address, &format!("client_{client_id}")).await?;
+    let message_service = root.message_request().send().promise.await?.get()?.get_service()?;
+    barrier.wait();
+
+    let mut in_flight = FuturesUnordered::new();
+    let mut posted = 0;
+
+    for i in 0..args.messages {
+        if in_flight.len() >= args.pipeline {
+            posted += check_posted(in_flight.next().await.unwrap())?;
+        }
+
+        let mut request = message_service.post_message_request();
+        let mut builder = request.get();
+        builder.set_content(format!("Message {i} from client {client_id}"));
+        let (upper, lower) = topic_uuid.as_u64_pair();
+        let mut capnp_uuid = builder.init_topic_id();
+        capnp_uuid.set_upper(upper);
+        capnp_uuid.set_lower(lower);
+
+        in_flight.push(request.send().promise);
+    }
+    while let Some(response) = in_flight.next().await {
+        posted += check_posted(response)?;
+    }
+
+    Ok(posted)
+}
+
+fn check_posted(response: capnp::Result<capnp::capability::Response<message_service::post_message_results::Owned>>) -> Result<usize, BoxError> {
+    match response?.get()?.get_message()?.which()? {
+        util_capnp::result::Which::Ok(_) => Ok(1),
+        util_capnp::result::Which::Err(_) => Err("Server rejected a message".into()),
+    }
+}
@@ -91,6 +91,7 @@ impl<T: 'static> ChunkRef<T> {
             match prev_node {
                 None => Err(EndOfCollection::default()),
                 Some(prev) => {
+                    self.chunk = prev;
                     self.index = prev.node_capacity() - 1;
                     self.global_index -= 1;
                     Ok(())
@@ -0,0 +1,80 @@
+use std::io;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use futures::io::AsyncRead;
+
+/// A thread-safe, shared timestamp of the last activity on a connection.
+///
+/// Cloning creates new references to the same timestamp.
+///
+/// # Examples
+///
+/// ```
+/// use std::time::Duration;
+/// use broker::util::Activity;
+///
+/// let activity = Activity::new();
+/// let handle = activity.clone();
+///
+/// handle.touch();
+/// assert!(activity.idle_for() < Duration::from_secs(1));
+/// ```
+#[derive(Clone)]
+pub struct Activity {
+    created_at: Instant,
+    last_touch_millis: Arc<AtomicU64>,
+}
+
+impl Default for Activity {
+    fn default() -> Self {
+        Self {
+            created_at: Instant::now(),
+            last_touch_millis: Arc::new(AtomicU64::new(0)),
+        }
+    }
+}
+
+impl Activity {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn touch(&self) {
+        let millis = self.created_at.elapsed().as_millis() as u64;
+        self.last_touch_millis.fetch_max(millis, Ordering::Relaxed);
+    }
+
+    pub fn idle_for(&self) -> Duration {
+        let last_touch = Duration::from_millis(self.last_touch_millis.load(Ordering::Relaxed));
+        self.created_at.elapsed().saturating_sub(last_touch)
+    }
+}
+
+/// An [`AsyncRead`] wrapper that touches an [`Activity`] every time data is read.
+pub struct ActivityReader<R> {
+    inner: R,
+    activity: Activity,
+}
+
+impl<R> ActivityReader<R> {
+    pub fn new(inner: R, activity: Activity) -> Self {
+        Self { inner, activity }
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for ActivityReader<R> {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+        let result = Pin::new(&mut self.inner).poll_read(cx, buf);
+
+        if let Poll::Ready(Ok(read)) = result {
+            if read > 0 {
+                self.activity.touch();
+            }
+        }
+        result
+    }
+}
\ No newline at end of file
@@ -2,8 +2,10 @@ mod rpc_network;
 mod handle;
 mod store_registry;
 mod reverse_iterator;
+mod activity;
 
-pub use rpc_network::{RpcNetwork, stream_to_rpc_network};
+pub use rpc_network::{RpcNetwork, stream_to_rpc_network, stream_to_tracked_rpc_network};
 pub use handle::Handle;
 pub use store_registry::StoreRegistry;
-pub use reverse_iterator::ReverseIterator;
\ No newline at end of file
+pub use reverse_iterator::ReverseIterator;
+pub use activity::{Activity, ActivityReader};
\ No newline at end of file
@@ -4,7 +4,9 @@ use capnp_rpc::{rpc_twoparty_capnp, twoparty};
 use tokio::net::TcpStream;
 use tokio_util::compat::{TokioAsyncReadCompatExt, TokioAsyncWriteCompatExt};
 
-pub type RpcNetwork = twoparty::VatNetwork<futures::io::BufReader<tokio_util::compat::Compat<tokio::net::tcp::OwnedReadHalf>>>;
+use super::{Activity, ActivityReader};
+
+pub type RpcNetwork = twoparty::VatNetwork<futures::io::BufReader<ActivityReader<tokio_util::compat::Compat<tokio::net::tcp::OwnedReadHalf>>>>;
 
 /// Converts a `tokio::net::TcpStream` into a `capnp_rpc::twoparty::VatNetwork`.
 /// use std::net::SocketAddr;
@@ -29,9 +31,15 @@ pub type RpcNetwork = twoparty::VatNetwork<futures::io::BufReader<tokio_util::co
 /// ```
 
 pub fn stream_to_rpc_network(stream: TcpStream) -> RpcNetwork {
+    stream_to_tracked_rpc_network(stream, Activity::new())
+}
+
+/// Same as [`stream_to_rpc_network`], but touches `activity` every time the peer sends anything.
+pub fn stream_to_tracked_rpc_network(stream: TcpStream, activity: Activity) -> RpcNetwork {
     let (reader, writer) = stream.into_split();
 
-    let reader = futures::io::BufReader::new(TokioAsyncReadCompatExt::compat(reader));
+    let reader = ActivityReader::new(TokioAsyncReadCompatExt::compat(reader), activity);
+    let reader = futures::io::BufReader::new(reader);
     let writer = futures::io::BufWriter::new(TokioAsyncWriteCompatExt::compat_write(writer));
     
     let network: RpcNetwork  = twoparty::VatNetwork::new(